@@ -0,0 +1,123 @@
+use regex::{Regex, RegexBuilder};
+
+pub const DEFAULT_ARTIST_SEPARATORS: [&str; 4] = [";", "/", "feat.", "&"];
+
+/// Splits artist strings such as `A feat. B` or `A; B` into the individual artists, using the
+/// separators configured by the user. Separators that start or end with a letter, like
+/// `feat.` or `vs`, only match at word boundaries, and all separators ignore case.
+#[derive(Clone, Debug)]
+pub struct ArtistSplitter {
+    regex: Option<Regex>,
+}
+
+impl ArtistSplitter {
+    pub fn new(separators: &[String]) -> ArtistSplitter {
+        let alternatives: Vec<String> = separators
+            .iter()
+            .map(|separator| separator.trim())
+            .filter(|separator| !separator.is_empty())
+            .map(|separator| {
+                let mut alternative = regex::escape(separator);
+                if separator.starts_with(char::is_alphanumeric) {
+                    alternative.insert_str(0, r"\b");
+                }
+                if separator.ends_with(char::is_alphanumeric) {
+                    alternative.push_str(r"\b");
+                }
+                alternative
+            })
+            .collect();
+
+        if alternatives.is_empty() {
+            return ArtistSplitter { regex: None };
+        }
+
+        let regex = RegexBuilder::new(&alternatives.join("|"))
+            .case_insensitive(true)
+            .build()
+            .expect("Artist separator regex should be valid");
+
+        ArtistSplitter { regex: Some(regex) }
+    }
+
+    /// Returns the distinct artists of the given tag values, in order of appearance.
+    pub fn split(&self, values: &[String]) -> Vec<String> {
+        let mut artists: Vec<String> = vec![];
+
+        for value in values.iter() {
+            let parts: Vec<&str> = match &self.regex {
+                Some(regex) => regex.split(value).collect(),
+                None => vec![value.as_str()],
+            };
+
+            for part in parts {
+                let artist = part.trim();
+                if !artist.is_empty() && !artists.iter().any(|existing| existing == artist) {
+                    artists.push(artist.to_owned());
+                }
+            }
+        }
+
+        artists
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn splitter(separators: &[&str]) -> ArtistSplitter {
+        let separators: Vec<String> = separators.iter().map(|s| s.to_string()).collect();
+        ArtistSplitter::new(&separators)
+    }
+
+    fn split(splitter: &ArtistSplitter, values: &[&str]) -> Vec<String> {
+        let values: Vec<String> = values.iter().map(|s| s.to_string()).collect();
+        splitter.split(&values)
+    }
+
+    #[test]
+    fn default_separators_split() {
+        let splitter = splitter(&DEFAULT_ARTIST_SEPARATORS);
+
+        assert_eq!(
+            split(&splitter, &["A; B/C feat. D & E"]),
+            vec!["A", "B", "C", "D", "E"]
+        );
+    }
+
+    #[test]
+    fn word_separators_ignore_case() {
+        let splitter = splitter(&DEFAULT_ARTIST_SEPARATORS);
+
+        assert_eq!(split(&splitter, &["Moby FEAT. Gwen"]), vec!["Moby", "Gwen"]);
+        assert_eq!(split(&splitter, &["Defeat.Band"]), vec!["Defeat.Band"]);
+    }
+
+    #[test]
+    fn word_separators_do_not_split_names() {
+        let splitter = splitter(&["and", "x", "vs"]);
+
+        assert_eq!(
+            split(&splitter, &["Andrew Bird and Xzibit vs Vsevolod x Max"]),
+            vec!["Andrew Bird", "Xzibit", "Vsevolod", "Max"]
+        );
+    }
+
+    #[test]
+    fn repeated_artists_are_kept_once() {
+        let splitter = splitter(&DEFAULT_ARTIST_SEPARATORS);
+
+        assert_eq!(
+            split(&splitter, &["A & B", "B; C", " A "]),
+            vec!["A", "B", "C"]
+        );
+    }
+
+    #[test]
+    fn no_separators_keep_the_value() {
+        let splitter = splitter(&["", "  "]);
+
+        assert_eq!(split(&splitter, &["A & B"]), vec!["A & B"]);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::db;
+use anyhow::Result;
+use data_encoding::HEXLOWER;
+use image::ImageFormat;
+use lofty::file::TaggedFileExt;
+use lofty::picture::PictureType;
+use lofty::read_from_path;
+use rayon::prelude::*;
+use ring::digest::{digest, SHA256};
+use rusqlite::Connection;
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tauri::{AppHandle, Manager};
+
+const THUMBNAIL_SIZE: u32 = 300;
+const COVER_FILE_STEMS: [&str; 4] = ["cover", "folder", "front", "album"];
+const COVER_FILE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Looks up the artwork of every album that hasn't been checked since its tracks were scanned,
+/// and stores a thumbnail of it in the app cache directory. Thumbnails are named after their
+/// content, so an album id reused by another album never serves a stale cached image.
+pub fn update_album_artworks(conn: &mut Connection, app_handle: &AppHandle) -> Result<()> {
+    let now = Instant::now();
+    let albums = db::get_albums_without_artwork(conn)?;
+    if albums.is_empty() {
+        return Ok(());
+    }
+
+    let artwork_dir = app_handle.path().app_cache_dir()?.join("artwork");
+    fs::create_dir_all(&artwork_dir)?;
+
+    let artworks: Vec<(i64, Option<String>)> = albums
+        .par_iter()
+        .map(|(album_id, track_path, _)| {
+            match save_album_thumbnail(*album_id, Path::new(track_path), &artwork_dir) {
+                Ok(thumbnail_path) => (
+                    *album_id,
+                    thumbnail_path.map(|path| path.display().to_string()),
+                ),
+                Err(error) => {
+                    println!(
+                        "Cannot extract the artwork of `{}`. Error: {}",
+                        track_path, error
+                    );
+                    (*album_id, None)
+                }
+            }
+        })
+        .collect();
+
+    db::update_album_artworks(&artworks, conn)?;
+
+    let replaced_thumbnails: Vec<String> = albums
+        .into_iter()
+        .zip(artworks.iter())
+        .filter_map(|((_, _, old_path), (_, new_path))| {
+            old_path.filter(|old| Some(old) != new_path.as_ref())
+        })
+        .collect();
+    remove_thumbnails(&replaced_thumbnails);
+
+    println!(
+        "==> Extracting {} album artworks take: {}ms",
+        artworks.len(),
+        now.elapsed().as_millis()
+    );
+
+    Ok(())
+}
+
+/// Deletes thumbnails that no album points at anymore. Missing files are fine, and other
+/// errors are only logged.
+pub fn remove_thumbnails(image_paths: &[String]) {
+    for image_path in image_paths.iter() {
+        match fs::remove_file(image_path) {
+            Ok(()) => (),
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            Err(error) => println!(
+                "Cannot remove the artwork thumbnail `{}`. Error: {}",
+                image_path, error
+            ),
+        }
+    }
+}
+
+/// Writes a thumbnail of the track's artwork to `album-{id}-{hash}.jpg` in `artwork_dir` and
+/// returns its path, or `None` if the track has no artwork.
+fn save_album_thumbnail(
+    album_id: i64,
+    track_path: &Path,
+    artwork_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let image = match read_embedded_cover(track_path) {
+        Some(data) => image::load_from_memory(&data)?,
+        None => match find_cover_file(track_path) {
+            Some(cover_path) => image::open(cover_path)?,
+            None => return Ok(None),
+        },
+    };
+
+    let mut thumbnail: Vec<u8> = vec![];
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .to_rgb8()
+        .write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Jpeg)?;
+
+    let hash = HEXLOWER.encode(digest(&SHA256, &thumbnail).as_ref());
+    let thumbnail_path = artwork_dir.join(format!("album-{}-{}.jpg", album_id, &hash[..16]));
+    fs::write(&thumbnail_path, &thumbnail)?;
+
+    Ok(Some(thumbnail_path))
+}
+
+/// Returns the embedded front cover, or the first embedded picture if none is marked as such.
+fn read_embedded_cover(track_path: &Path) -> Option<Vec<u8>> {
+    let tagged_file = read_from_path(track_path).ok()?;
+    let pictures: Vec<_> = tagged_file
+        .tags()
+        .iter()
+        .flat_map(|tag| tag.pictures())
+        .collect();
+
+    pictures
+        .iter()
+        .find(|picture| picture.pic_type() == PictureType::CoverFront)
+        .or_else(|| pictures.first())
+        .map(|picture| picture.data().to_vec())
+}
+
+/// Finds a `cover.jpg`, `folder.png` or similar image next to the track.
+fn find_cover_file(track_path: &Path) -> Option<PathBuf> {
+    let album_dir = track_path.parent()?;
+
+    fs::read_dir(album_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?.to_lowercase();
+            let extension = path.extension()?.to_str()?.to_lowercase();
+            let stem_rank = COVER_FILE_STEMS.iter().position(|s| *s == stem)?;
+            let extension_rank = COVER_FILE_EXTENSIONS.iter().position(|e| *e == extension)?;
+            Some(((stem_rank, extension_rank), path))
+        })
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, path)| path)
+}
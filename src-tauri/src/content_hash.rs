@@ -0,0 +1,515 @@
+use anyhow::Result;
+use data_encoding::HEXLOWER;
+use ring::digest::{Context, SHA256};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// How much of the start and of the end of the audio data is hashed.
+const SAMPLE_SIZE: u64 = 1024 * 1024;
+/// The longest Ogg page header: the fixed 27 bytes and a table of up to 255 segment sizes.
+const OGG_MAX_HEADER_LEN: u64 = 27 + 255;
+/// The longest Ogg page: its header and 255 segments of up to 255 bytes.
+const OGG_MAX_PAGE_LEN: u64 = OGG_MAX_HEADER_LEN + 255 * 255;
+
+/// Computes a hash of the audio data of a file that doesn't change when its tags are edited,
+/// so a file can be recognized after it was moved or renamed. Only the length of the audio
+/// data and its first and last megabyte are hashed, which keeps scanning fast while still
+/// telling different recordings apart.
+///
+/// Tag bytes are skipped for ID3v2, ID3v1 and APEv2 tags, FLAC metadata blocks, and the
+/// chunks/atoms around the audio of WAV, AIFF and MP4 files. Ogg files keep their comments
+/// in a header packet of the stream, so only the packets of their audio pages are hashed.
+pub fn compute(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    if matches!(extension.as_str(), "ogg" | "oga" | "opus") {
+        if let Some(hash) = ogg_audio_hash(&mut file, file_len)? {
+            return Ok(hash);
+        }
+    }
+
+    let (start, end) = match extension.as_str() {
+        "flac" => flac_audio_region(&mut file, file_len)?,
+        "m4a" | "m4b" => mp4_audio_region(&mut file, file_len)?,
+        "wav" => iff_audio_region(&mut file, file_len, b"RIFF", b"data", false)?,
+        "aiff" | "aif" => iff_audio_region(&mut file, file_len, b"FORM", b"SSND", true)?,
+        _ => stripped_audio_region(&mut file, file_len)?,
+    };
+
+    hash_region(&mut file, start, end.max(start))
+}
+
+fn hash_region(file: &mut File, start: u64, end: u64) -> Result<String> {
+    let region_len = end - start;
+    let mut context = Context::new(&SHA256);
+    context.update(&region_len.to_le_bytes());
+
+    if region_len <= SAMPLE_SIZE * 2 {
+        hash_range(file, &mut context, start, region_len)?;
+    } else {
+        hash_range(file, &mut context, start, SAMPLE_SIZE)?;
+        hash_range(file, &mut context, end - SAMPLE_SIZE, SAMPLE_SIZE)?;
+    }
+
+    Ok(HEXLOWER.encode(context.finish().as_ref()))
+}
+
+fn hash_range(file: &mut File, context: &mut Context, start: u64, len: u64) -> Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut buffer: Vec<u8> = vec![];
+    file.take(len).read_to_end(&mut buffer)?;
+    context.update(&buffer);
+    Ok(())
+}
+
+fn read_up_to(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer: Vec<u8> = vec![];
+    file.take(len).read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Returns the offset right after the ID3v2 tag at the start of the file, or 0 if there is none.
+fn id3v2_end(file: &mut File) -> Result<u64> {
+    let mut header = [0u8; 10];
+    if !read_at(file, 0, &mut header)? || &header[0..3] != b"ID3" {
+        return Ok(0);
+    }
+
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |size, byte| (size << 7) | (*byte & 0x7f) as u64);
+    let footer_size = if header[5] & 0x10 != 0 { 10 } else { 0 };
+
+    Ok(10 + size + footer_size)
+}
+
+/// The file without a leading ID3v2 tag and trailing ID3v1 and APEv2 tags.
+fn stripped_audio_region(file: &mut File, file_len: u64) -> Result<(u64, u64)> {
+    let start = id3v2_end(file)?;
+    let mut end = file_len;
+
+    let mut id3v1_header = [0u8; 3];
+    if end >= 128 && read_at(file, end - 128, &mut id3v1_header)? && &id3v1_header == b"TAG" {
+        end -= 128;
+    }
+
+    let mut ape_footer = [0u8; 32];
+    if end >= 32 && read_at(file, end - 32, &mut ape_footer)? && &ape_footer[0..8] == b"APETAGEX" {
+        let tag_size = u32::from_le_bytes(ape_footer[12..16].try_into()?) as u64;
+        let flags = u32::from_le_bytes(ape_footer[20..24].try_into()?);
+        let header_size = if flags & 0x8000_0000 != 0 { 32 } else { 0 };
+        end = end.saturating_sub(tag_size + header_size);
+    }
+
+    Ok((start, end))
+}
+
+/// The audio frames after the `fLaC` marker and every metadata block.
+fn flac_audio_region(file: &mut File, file_len: u64) -> Result<(u64, u64)> {
+    let mut offset = id3v2_end(file)?;
+    let mut marker = [0u8; 4];
+    if !read_at(file, offset, &mut marker)? || &marker != b"fLaC" {
+        return stripped_audio_region(file, file_len);
+    }
+    offset += 4;
+
+    loop {
+        let mut block_header = [0u8; 4];
+        if !read_at(file, offset, &mut block_header)? {
+            break;
+        }
+        let block_len = u32::from_be_bytes([0, block_header[1], block_header[2], block_header[3]]);
+        offset += 4 + block_len as u64;
+        if block_header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+
+    Ok((offset.min(file_len), file_len))
+}
+
+/// The payload of the top-level `mdat` atom.
+fn mp4_audio_region(file: &mut File, file_len: u64) -> Result<(u64, u64)> {
+    let mut offset = 0;
+
+    while offset + 8 <= file_len {
+        let mut atom_header = [0u8; 8];
+        if !read_at(file, offset, &mut atom_header)? {
+            break;
+        }
+        let mut header_len = 8;
+        let mut atom_len = u32::from_be_bytes(atom_header[0..4].try_into()?) as u64;
+        if atom_len == 1 {
+            let mut extended_len = [0u8; 8];
+            if !read_at(file, offset + 8, &mut extended_len)? {
+                break;
+            }
+            atom_len = u64::from_be_bytes(extended_len);
+            header_len = 16;
+        } else if atom_len == 0 {
+            atom_len = file_len - offset;
+        }
+        // A corrupt length could point past the end of any file
+        let Some(atom_end) = offset.checked_add(atom_len) else {
+            break;
+        };
+        if atom_len < header_len {
+            break;
+        }
+
+        if &atom_header[4..8] == b"mdat" {
+            return Ok((offset + header_len, atom_end.min(file_len)));
+        }
+        offset = atom_end;
+    }
+
+    Ok((0, file_len))
+}
+
+/// The payload of the audio chunk of a RIFF (little endian) or FORM (big endian) file.
+fn iff_audio_region(
+    file: &mut File,
+    file_len: u64,
+    container_id: &[u8; 4],
+    chunk_id: &[u8; 4],
+    big_endian: bool,
+) -> Result<(u64, u64)> {
+    let mut container_header = [0u8; 12];
+    if !read_at(file, 0, &mut container_header)? || &container_header[0..4] != container_id {
+        return stripped_audio_region(file, file_len);
+    }
+    let mut offset = 12;
+
+    while offset + 8 <= file_len {
+        let mut chunk_header = [0u8; 8];
+        if !read_at(file, offset, &mut chunk_header)? {
+            break;
+        }
+        let size_bytes: [u8; 4] = chunk_header[4..8].try_into()?;
+        let chunk_len = if big_endian {
+            u32::from_be_bytes(size_bytes)
+        } else {
+            u32::from_le_bytes(size_bytes)
+        } as u64;
+
+        let Some(chunk_end) = (offset + 8).checked_add(chunk_len) else {
+            break;
+        };
+
+        if &chunk_header[0..4] == chunk_id {
+            return Ok((offset + 8, chunk_end.min(file_len)));
+        }
+        let Some(next_offset) = chunk_end.checked_add(chunk_len % 2) else {
+            break;
+        };
+        offset = next_offset;
+    }
+
+    Ok((0, file_len))
+}
+
+struct OggPage {
+    granule_position: u64,
+    header_len: u64,
+    payload_len: u64,
+}
+
+/// Parses the Ogg page header at the start of the bytes, if there is one.
+fn parse_ogg_page_header(bytes: &[u8]) -> Option<OggPage> {
+    if bytes.len() < 27 || &bytes[0..4] != b"OggS" || bytes[4] != 0 {
+        return None;
+    }
+    let segments_count = bytes[26] as usize;
+    let segment_table = bytes.get(27..27 + segments_count)?;
+
+    Some(OggPage {
+        granule_position: u64::from_le_bytes(bytes[6..14].try_into().ok()?),
+        header_len: (27 + segments_count) as u64,
+        payload_len: segment_table.iter().map(|len| *len as u64).sum(),
+    })
+}
+
+/// Hashes the packets of the audio pages of an Ogg file, along with the granule position of
+/// its last page, which counts the samples of the whole stream. Header pages, including the
+/// comment header, have a granule position of 0 and are skipped. The page headers aren't
+/// hashed either: their sequence numbers and checksums change when the comments grow by a
+/// page. Returns `None` if the file isn't an Ogg stream.
+fn ogg_audio_hash(file: &mut File, file_len: u64) -> Result<Option<String>> {
+    let Some(last_granule_position) = ogg_last_granule_position(file, file_len)? else {
+        return Ok(None);
+    };
+    let mut context = Context::new(&SHA256);
+    context.update(&last_granule_position.to_le_bytes());
+
+    let mut offset = 0;
+    let mut hashed_len = 0;
+    while hashed_len < SAMPLE_SIZE * 2 {
+        let header = read_up_to(file, offset, OGG_MAX_HEADER_LEN)?;
+        let Some(page) = parse_ogg_page_header(&header) else {
+            break;
+        };
+        let payload_offset = offset + page.header_len;
+        if page.granule_position != 0 {
+            let len = page.payload_len.min(SAMPLE_SIZE * 2 - hashed_len);
+            hash_range(file, &mut context, payload_offset, len)?;
+            hashed_len += len;
+        }
+        offset = payload_offset + page.payload_len;
+    }
+
+    if offset == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(HEXLOWER.encode(context.finish().as_ref())))
+}
+
+/// Finds the page that ends the file by looking for a page header in its last 64 KiB whose
+/// page ends exactly at the end of the file.
+fn ogg_last_granule_position(file: &mut File, file_len: u64) -> Result<Option<u64>> {
+    let tail_start = file_len.saturating_sub(OGG_MAX_PAGE_LEN);
+    let tail = read_up_to(file, tail_start, OGG_MAX_PAGE_LEN)?;
+
+    let last_page = (0..tail.len())
+        .rev()
+        .filter_map(|offset| parse_ogg_page_header(&tail[offset..]).map(|page| (offset, page)))
+        .find(|(offset, page)| {
+            *offset as u64 + page.header_len + page.payload_len == tail.len() as u64
+        });
+
+    Ok(last_page.map(|(_, page)| page.granule_position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn hash_of(file_name: &str, bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "lrcget-content-hash-{}-{}",
+            std::process::id(),
+            file_name
+        ));
+        fs::write(&path, bytes).unwrap();
+        let hash = compute(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        hash
+    }
+
+    fn audio(seed: u8) -> Vec<u8> {
+        (0..5000u32)
+            .map(|index| (index.wrapping_mul(seed as u32 + 7) % 251) as u8)
+            .collect()
+    }
+
+    fn id3v2_tag(len: usize) -> Vec<u8> {
+        let size = [
+            (len >> 21) & 0x7f,
+            (len >> 14) & 0x7f,
+            (len >> 7) & 0x7f,
+            len & 0x7f,
+        ];
+        let mut tag = b"ID3\x04\x00\x00".to_vec();
+        tag.extend(size.iter().map(|byte| *byte as u8));
+        tag.extend(vec![b't'; len]);
+        tag
+    }
+
+    fn id3v1_tag(title: &str) -> Vec<u8> {
+        let mut tag = b"TAG".to_vec();
+        tag.extend(title.bytes());
+        tag.resize(128, 0);
+        tag
+    }
+
+    fn ape_tag(len: u32) -> Vec<u8> {
+        let mut tag = vec![b'a'; len as usize - 32];
+        tag.extend(b"APETAGEX");
+        tag.extend(2000u32.to_le_bytes());
+        tag.extend(len.to_le_bytes());
+        tag.extend(1u32.to_le_bytes());
+        tag.extend(0u32.to_le_bytes());
+        tag.extend([0u8; 8]);
+        tag
+    }
+
+    fn mp3(id3v2_len: usize, title: &str, ape_len: u32, audio: &[u8]) -> Vec<u8> {
+        [
+            id3v2_tag(id3v2_len),
+            audio.to_vec(),
+            ape_tag(ape_len),
+            id3v1_tag(title),
+        ]
+        .concat()
+    }
+
+    fn flac(comment_len: usize, audio: &[u8]) -> Vec<u8> {
+        let mut bytes = b"fLaC".to_vec();
+        bytes.extend([0x00, 0x00, 0x00, 0x22]);
+        bytes.extend([0u8; 0x22]);
+        bytes.extend([0x84, 0x00, (comment_len >> 8) as u8, comment_len as u8]);
+        bytes.extend(vec![b'c'; comment_len]);
+        bytes.extend(audio);
+        bytes
+    }
+
+    fn atom(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [
+            (payload.len() as u32 + 8).to_be_bytes().to_vec(),
+            name.to_vec(),
+            payload.to_vec(),
+        ]
+        .concat()
+    }
+
+    fn mp4(metadata_len: usize, audio: &[u8]) -> Vec<u8> {
+        [
+            atom(b"ftyp", b"M4A \x00\x00\x00\x00"),
+            atom(b"moov", &atom(b"udta", &vec![b'm'; metadata_len])),
+            atom(b"mdat", audio),
+        ]
+        .concat()
+    }
+
+    fn chunk(id: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = [id.to_vec(), (payload.len() as u32).to_le_bytes().to_vec()].concat();
+        bytes.extend(payload);
+        if payload.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn wav(info_len: usize, audio: &[u8]) -> Vec<u8> {
+        let body = [
+            b"WAVE".to_vec(),
+            chunk(b"LIST", &vec![b'i'; info_len]),
+            chunk(b"fmt ", &[0u8; 16]),
+            chunk(b"data", audio),
+        ]
+        .concat();
+        [
+            b"RIFF".to_vec(),
+            (body.len() as u32).to_le_bytes().to_vec(),
+            body,
+        ]
+        .concat()
+    }
+
+    fn ogg_page(sequence: u32, granule_position: u64, payload: &[u8]) -> Vec<u8> {
+        let mut segments = vec![255u8; payload.len() / 255];
+        segments.push((payload.len() % 255) as u8);
+        [
+            b"OggS\x00\x00".to_vec(),
+            granule_position.to_le_bytes().to_vec(),
+            1234u32.to_le_bytes().to_vec(),
+            sequence.to_le_bytes().to_vec(),
+            // The checksum changes whenever the sequence does
+            sequence.wrapping_mul(2654435761).to_le_bytes().to_vec(),
+            vec![segments.len() as u8],
+            segments,
+            payload.to_vec(),
+        ]
+        .concat()
+    }
+
+    fn ogg(comment_pages: usize, audio: &[u8]) -> Vec<u8> {
+        let mut bytes = ogg_page(0, 0, b"OpusHead\x01\x02");
+        let mut sequence = 1;
+        for _ in 0..comment_pages {
+            bytes.extend(ogg_page(sequence, 0, &[b'c'; 3000]));
+            sequence += 1;
+        }
+        for (index, packet) in audio.chunks(1000).enumerate() {
+            bytes.extend(ogg_page(sequence, (index as u64 + 1) * 960, packet));
+            sequence += 1;
+        }
+        bytes
+    }
+
+    #[test]
+    fn retagging_an_mp3_keeps_its_hash() {
+        let hash = hash_of("retag.mp3", &mp3(100, "Title", 64, &audio(1)));
+
+        assert_eq!(
+            hash,
+            hash_of("retag-2.mp3", &mp3(3000, "Other", 200, &audio(1)))
+        );
+        assert_ne!(
+            hash,
+            hash_of("retag-3.mp3", &mp3(100, "Title", 64, &audio(2)))
+        );
+    }
+
+    #[test]
+    fn retagging_a_flac_keeps_its_hash() {
+        let hash = hash_of("retag.flac", &flac(10, &audio(1)));
+
+        assert_eq!(hash, hash_of("retag-2.flac", &flac(900, &audio(1))));
+        assert_ne!(hash, hash_of("retag-3.flac", &flac(10, &audio(2))));
+    }
+
+    #[test]
+    fn retagging_an_mp4_keeps_its_hash() {
+        let hash = hash_of("retag.m4a", &mp4(10, &audio(1)));
+
+        assert_eq!(hash, hash_of("retag-2.m4a", &mp4(700, &audio(1))));
+        assert_ne!(hash, hash_of("retag-3.m4a", &mp4(10, &audio(2))));
+    }
+
+    #[test]
+    fn retagging_a_wav_keeps_its_hash() {
+        let hash = hash_of("retag.wav", &wav(11, &audio(1)));
+
+        assert_eq!(hash, hash_of("retag-2.wav", &wav(400, &audio(1))));
+        assert_ne!(hash, hash_of("retag-3.wav", &wav(11, &audio(2))));
+    }
+
+    #[test]
+    fn retagging_an_ogg_keeps_its_hash() {
+        let hash = hash_of("retag.opus", &ogg(1, &audio(1)));
+
+        assert_eq!(hash, hash_of("retag-2.opus", &ogg(3, &audio(1))));
+        assert_ne!(hash, hash_of("retag-3.opus", &ogg(1, &audio(2))));
+    }
+
+    #[test]
+    fn corrupt_mp4_atom_length_does_not_overflow() {
+        let mut bytes = atom(b"ftyp", b"M4A \x00\x00\x00\x00");
+        bytes.extend(1u32.to_be_bytes());
+        bytes.extend(b"free");
+        bytes.extend((u64::MAX - 4).to_be_bytes());
+        bytes.extend(atom(b"mdat", &audio(1)));
+
+        hash_of("corrupt.m4a", &bytes);
+        // The extended length itself is cut off
+        hash_of("truncated.m4a", &bytes[..bytes.len() - audio(1).len() - 20]);
+    }
+
+    #[test]
+    fn corrupt_wav_chunk_length_does_not_overflow() {
+        let mut bytes = b"RIFF\xff\xff\xff\xffWAVE".to_vec();
+        bytes.extend(b"LIST\xff\xff\xff\xff");
+        bytes.extend(audio(1));
+
+        hash_of("corrupt.wav", &bytes);
+    }
+}
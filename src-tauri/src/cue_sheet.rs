@@ -0,0 +1,306 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// CUE sheet timestamps count frames, 75 to a second.
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Error, Debug)]
+pub enum CueSheetError {
+    #[error("Cannot parse the timestamp `{0}` of the CUE sheet")]
+    InvalidTimestamp(String),
+    #[error("The CUE sheet `{0}` has no track for this file")]
+    NoTracks(String),
+}
+
+#[derive(Debug, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub isrc: Option<String>,
+    /// Offset of `INDEX 01` in the file, in seconds.
+    pub start: f64,
+    /// Offset where the next track of the file starts, or `None` for the last track, which
+    /// plays to the end of the file.
+    pub end: Option<f64>,
+}
+
+/// Returns the CUE sheet that describes the audio file: `Album.cue` or `Album.flac.cue` next
+/// to `Album.flac`.
+pub fn find_for(audio_path: &Path) -> Option<PathBuf> {
+    let file_name = audio_path.file_name()?.to_str()?;
+
+    [
+        audio_path.with_extension("cue"),
+        audio_path.with_file_name(format!("{}.cue", file_name)),
+    ]
+    .into_iter()
+    .find(|cue_path| cue_path.is_file())
+}
+
+/// Tells whether the path looks like a CUE sheet.
+pub fn is_cue_sheet(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+}
+
+impl CueSheet {
+    pub fn read(cue_path: &Path) -> Result<CueSheet> {
+        let content = decode(fs::read(cue_path)?);
+        CueSheet::parse(content.trim_start_matches('\u{feff}'))
+    }
+
+    pub fn parse(content: &str) -> Result<CueSheet> {
+        let mut sheet = CueSheet::default();
+
+        for line in content.lines() {
+            let arguments = split_arguments(line);
+            let Some((command, arguments)) = arguments.split_first() else {
+                continue;
+            };
+            let argument = arguments.first().cloned();
+            let current_track = sheet
+                .files
+                .last_mut()
+                .and_then(|file| file.tracks.last_mut());
+
+            match (command.to_uppercase().as_str(), current_track) {
+                ("FILE", _) => sheet.files.push(CueFile {
+                    name: argument.unwrap_or_default(),
+                    tracks: vec![],
+                }),
+                ("TRACK", _) => {
+                    let Some(file) = sheet.files.last_mut() else {
+                        continue;
+                    };
+                    let Some(number) = argument.and_then(|number| number.parse().ok()) else {
+                        continue;
+                    };
+                    file.tracks.push(CueTrack {
+                        number,
+                        title: None,
+                        performer: None,
+                        isrc: None,
+                        start: 0.0,
+                        end: None,
+                    });
+                }
+                ("TITLE", Some(track)) => track.title = argument,
+                ("TITLE", None) => sheet.title = argument,
+                ("PERFORMER", Some(track)) => track.performer = argument,
+                ("PERFORMER", None) => sheet.performer = argument,
+                ("ISRC", Some(track)) => track.isrc = argument,
+                ("INDEX", Some(track)) if argument.as_deref() == Some("01") => {
+                    if let Some(timestamp) = arguments.get(1) {
+                        track.start = parse_timestamp(timestamp)?;
+                    }
+                }
+                ("REM", _) => match (argument.as_deref(), arguments.get(1)) {
+                    (Some("GENRE"), Some(genre)) => sheet.genre = Some(genre.to_owned()),
+                    (Some("DATE"), Some(date)) => {
+                        sheet.year = date.get(0..4).and_then(|year| year.parse().ok())
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+
+        for file in sheet.files.iter_mut() {
+            let starts: Vec<f64> = file.tracks.iter().map(|track| track.start).collect();
+            for (track, next_start) in file.tracks.iter_mut().zip(starts.into_iter().skip(1)) {
+                track.end = Some(next_start);
+            }
+        }
+
+        Ok(sheet)
+    }
+
+    /// Returns the tracks that live in the audio file. A sheet with a single `FILE` entry
+    /// describes the file even if the names differ, since rips are often converted after the
+    /// sheet was written.
+    pub fn tracks_for(&self, audio_path: &Path) -> Result<&Vec<CueTrack>> {
+        let file_name = audio_path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .unwrap_or_default();
+        let file = match self.files.as_slice() {
+            [file] => Some(file),
+            files => files.iter().find(|file| {
+                Path::new(&file.name)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.eq_ignore_ascii_case(file_name))
+            }),
+        };
+
+        match file {
+            Some(file) if !file.tracks.is_empty() => Ok(&file.tracks),
+            _ => Err(CueSheetError::NoTracks(audio_path.display().to_string()).into()),
+        }
+    }
+}
+
+/// Most sheets are written by Windows rippers, so anything that isn't UTF-8 is read as
+/// Latin-1.
+fn decode(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(error) => error
+            .into_bytes()
+            .iter()
+            .map(|byte| *byte as char)
+            .collect(),
+    }
+}
+
+/// Splits a CUE sheet line into its words, keeping double-quoted arguments together.
+fn split_arguments(line: &str) -> Vec<String> {
+    let mut arguments: Vec<String> = vec![];
+    let mut current: Option<String> = None;
+    let mut in_quotes = false;
+
+    for character in line.trim().chars() {
+        match character {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.get_or_insert_with(String::new);
+            }
+            character if character.is_whitespace() && !in_quotes => {
+                if let Some(argument) = current.take() {
+                    arguments.push(argument);
+                }
+            }
+            character => current.get_or_insert_with(String::new).push(character),
+        }
+    }
+    if let Some(argument) = current {
+        arguments.push(argument);
+    }
+
+    arguments
+}
+
+/// Parses a `mm:ss:ff` timestamp into seconds.
+fn parse_timestamp(timestamp: &str) -> Result<f64> {
+    let parts: Vec<&str> = timestamp.split(':').collect();
+    let numbers: Vec<u32> = parts.iter().filter_map(|part| part.parse().ok()).collect();
+
+    match numbers.as_slice() {
+        [minutes, seconds, frames] if parts.len() == 3 => {
+            Ok(*minutes as f64 * 60.0 + *seconds as f64 + *frames as f64 / FRAMES_PER_SECOND)
+        }
+        _ => Err(CueSheetError::InvalidTimestamp(timestamp.to_owned()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn tracks_end_where_the_next_one_of_their_file_starts() {
+        let sheet = CueSheet::parse(indoc! {r#"
+            PERFORMER "Artist"
+            TITLE "Album"
+            FILE "Album.flac" WAVE
+              TRACK 01 AUDIO
+                TITLE "First"
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                TITLE "Second"
+                INDEX 01 03:20:15
+        "#})
+        .unwrap();
+        let tracks = &sheet.files[0].tracks;
+
+        assert_eq!(sheet.title.as_deref(), Some("Album"));
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].end, Some(200.2));
+        assert_eq!(tracks[1].start, 200.2);
+        assert_eq!(tracks[1].end, None);
+    }
+
+    #[test]
+    fn pregap_index_does_not_move_the_start() {
+        let sheet = CueSheet::parse(indoc! {r#"
+            FILE "Album.flac" WAVE
+              TRACK 01 AUDIO
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                INDEX 00 02:58:00
+                INDEX 01 03:00:00
+        "#})
+        .unwrap();
+        let tracks = &sheet.files[0].tracks;
+
+        assert_eq!(tracks[0].end, Some(180.0));
+        assert_eq!(tracks[1].start, 180.0);
+    }
+
+    #[test]
+    fn multi_file_sheet_keeps_the_tracks_of_each_file() {
+        let sheet = CueSheet::parse(indoc! {r#"
+            FILE "CD1.flac" WAVE
+              TRACK 01 AUDIO
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                INDEX 01 04:00:00
+            FILE "CD2.flac" WAVE
+              TRACK 03 AUDIO
+                TITLE "Third"
+                INDEX 01 00:00:00
+        "#})
+        .unwrap();
+
+        let first_tracks = sheet.tracks_for(Path::new("/music/cd1.FLAC")).unwrap();
+        assert_eq!(first_tracks.len(), 2);
+        assert_eq!(first_tracks[1].end, None);
+
+        let second_tracks = sheet.tracks_for(Path::new("/music/CD2.flac")).unwrap();
+        assert_eq!(second_tracks.len(), 1);
+        assert_eq!(second_tracks[0].number, 3);
+        assert_eq!(second_tracks[0].title.as_deref(), Some("Third"));
+
+        assert!(sheet.tracks_for(Path::new("/music/CD3.flac")).is_err());
+    }
+
+    #[test]
+    fn latin1_sheet_is_decoded() {
+        let bytes = b"TITLE \"Caf\xe9\"\r\nFILE \"a.flac\" WAVE\r\n".to_vec();
+        let sheet = CueSheet::parse(&decode(bytes)).unwrap();
+
+        assert_eq!(sheet.title.as_deref(), Some("Caf\u{e9}"));
+        assert_eq!(sheet.files[0].name, "a.flac");
+    }
+
+    #[test]
+    fn invalid_timestamp_fails() {
+        let result = CueSheet::parse(indoc! {r#"
+            FILE "Album.flac" WAVE
+              TRACK 01 AUDIO
+                INDEX 01 00:xx:00
+        "#});
+
+        assert!(result.is_err());
+    }
+}
@@ -1,45 +1,121 @@
+use crate::artist_splitter::DEFAULT_ARTIST_SEPARATORS;
+use crate::db_backup;
+use crate::db_pool::DbPool;
 use crate::fs_track;
+use crate::library_query::LibraryQuery;
+use crate::lyrics_search;
 use crate::persistent_entities::{
-    PersistentAlbum, PersistentArtist, PersistentConfig, PersistentTrack,
+    PersistentAlbum, PersistentAlbumCoverage, PersistentArtist, PersistentArtistCoverage,
+    PersistentConfig, PersistentDirectory, PersistentDuplicateTrack, PersistentFormatCoverage,
+    PersistentLibraryStatistics, PersistentLyricsCoverage, PersistentLyricsRevision,
+    PersistentScanFailure, PersistentScanFailureGroup, PersistentTrack,
 };
 use crate::utils::prepare_input;
 use anyhow::Result;
-use indoc::indoc;
+use indoc::{formatdoc, indoc};
 use regex::Regex;
-use rusqlite::{named_params, params, Connection};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value;
+use rusqlite::{named_params, params, params_from_iter, Connection, Row, Rows, Transaction};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tauri::{AppHandle, Manager};
+use thiserror::Error;
 
-const CURRENT_DB_VERSION: u32 = 6;
+pub const CURRENT_DB_VERSION: u32 = 26;
 
-/// Initializes the database connection, creating the .sqlite file if needed, and upgrading the database
-/// if it's out of date.
-pub fn initialize_database(app_handle: &AppHandle) -> Result<Connection, rusqlite::Error> {
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("The database failed its integrity check after migrating to version {0}: {1}")]
+    CheckFailed(u32, String),
+    #[error("The database has no `{1}.{2}` column after migrating to version {0}")]
+    MissingColumn(u32, String, String),
+}
+
+/// The directory of the database file and its backups.
+pub fn app_dir(app_handle: &AppHandle) -> PathBuf {
     let app_dir = app_handle
         .path()
         .app_data_dir()
         .expect("The app data directory should exist.");
     fs::create_dir_all(&app_dir).expect("The app data directory should be created.");
+    app_dir
+}
+
+/// Initializes the database connection, creating the .sqlite file if needed, and upgrading the database
+/// if it's out of date.
+///
+/// An existing database is backed up once before it is upgraded. One backup is enough because
+/// every step runs in its own transaction: a failing step is rolled back, and the backup holds
+/// the database as the app last used it, before any step. If the upgrade fails, the app starts
+/// from that backup, and the error is returned along with the connection so it can be shown.
+/// The app then runs on the old schema until an upgrade succeeds.
+pub fn initialize_database(app_handle: &AppHandle) -> Result<(DbPool, Option<anyhow::Error>)> {
+    let app_dir = app_dir(app_handle);
     let sqlite_path = app_dir.join("db.sqlite3");
 
     println!("Database file path: {}", sqlite_path.display());
 
-    let mut db = Connection::open(sqlite_path)?;
+    if let Err(error) = db_backup::restore_pending(&app_dir, &sqlite_path) {
+        println!("Restoring the database backup errored. Message: {}", error);
+    }
+
+    let mut db = open(&sqlite_path)?;
 
     let mut user_pragma = db.prepare("PRAGMA user_version")?;
     let existing_user_version: u32 = user_pragma.query_row([], |row| Ok(row.get(0)?))?;
     drop(user_pragma);
 
-    upgrade_database_if_needed(&mut db, existing_user_version)?;
+    // A new database has nothing worth backing up
+    if existing_user_version == 0 || existing_user_version >= CURRENT_DB_VERSION {
+        upgrade_database_if_needed(&mut db, existing_user_version)?;
+        return Ok((DbPool::new(db, &sqlite_path)?, None));
+    }
+
+    let backup_path = db_backup::create(&db, &app_dir, existing_user_version)?;
+    if let Err(error) = upgrade_database_if_needed(&mut db, existing_user_version) {
+        println!(
+            "Upgrading the database errored, starting from the backup. Message: {}",
+            error
+        );
+        drop(db);
+        db_backup::restore(&backup_path, &sqlite_path)?;
+        let db = open(&sqlite_path)?;
+        let error = error.context(format!(
+            "Upgrading the database from version {} failed, so the app started from the backup {}",
+            existing_user_version,
+            backup_path.display()
+        ));
+        return Ok((DbPool::new(db, &sqlite_path)?, Some(error)));
+    }
+
+    Ok((DbPool::new(db, &sqlite_path)?, None))
+}
+
+/// Opens the database file with the SQL functions its triggers call. Only the writer runs
+/// triggers, so the read-only connections go without them.
+fn open(sqlite_path: &Path) -> Result<Connection> {
+    let db = Connection::open(sqlite_path)?;
+
+    db.create_scalar_function(
+        "lrc_text",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |context| {
+            let lyrics: Option<String> = context.get(0)?;
+            Ok(lyrics.map(|lyrics| lyrics_search::synced_lyrics_text(&lyrics)))
+        },
+    )?;
 
     Ok(db)
 }
 
-/// Upgrades the database to the current version.
-pub fn upgrade_database_if_needed(
-    db: &mut Connection,
-    existing_version: u32,
-) -> Result<(), rusqlite::Error> {
+/// Upgrades the database to the current version. Every step runs in its own transaction,
+/// which is only committed if the database still passes SQLite's integrity check.
+pub fn upgrade_database_if_needed(db: &mut Connection, existing_version: u32) -> Result<()> {
     println!("Existing database version: {}", existing_version);
 
     if existing_version < CURRENT_DB_VERSION {
@@ -98,7 +174,32 @@ pub fn upgrade_database_if_needed(
             INSERT INTO config_data (skip_not_needed_tracks, try_embed_lyrics) VALUES (1, 0);
             "})?;
 
-            tx.commit()?;
+            commit_migration(
+                tx,
+                &[
+                    ("directories", &["id", "path"]),
+                    ("library_data", &["id", "init"]),
+                    (
+                        "config_data",
+                        &["id", "skip_not_needed_tracks", "try_embed_lyrics"],
+                    ),
+                    ("artists", &["id", "name"]),
+                    ("albums", &["id", "name", "artist_id", "image_path"]),
+                    (
+                        "tracks",
+                        &[
+                            "id",
+                            "file_path",
+                            "file_name",
+                            "title",
+                            "album_id",
+                            "artist_id",
+                            "duration",
+                            "lrc_lyrics",
+                        ],
+                    ),
+                ],
+            )?;
         }
 
         if existing_version <= 1 {
@@ -115,7 +216,7 @@ pub fn upgrade_database_if_needed(
             CREATE INDEX idx_albums_name ON albums(name);
             CREATE INDEX idx_artists_name ON artists(name);
             "})?;
-            tx.commit()?;
+            commit_migration(tx, &[("tracks", &["txt_lyrics"])])?;
         }
 
         if existing_version <= 2 {
@@ -127,7 +228,7 @@ pub fn upgrade_database_if_needed(
             tx.execute_batch(indoc! {"
             ALTER TABLE tracks ADD instrumental BOOLEAN;
             "})?;
-            tx.commit()?;
+            commit_migration(tx, &[("tracks", &["instrumental"])])?;
         }
 
         if existing_version <= 3 {
@@ -145,7 +246,14 @@ pub fn upgrade_database_if_needed(
             CREATE INDEX idx_artists_name_lower ON artists(name_lower);
             "})?;
 
-            tx.commit()?;
+            commit_migration(
+                tx,
+                &[
+                    ("tracks", &["title_lower"]),
+                    ("albums", &["name_lower"]),
+                    ("artists", &["name_lower"]),
+                ],
+            )?;
         }
 
         if existing_version <= 4 {
@@ -162,14 +270,16 @@ pub fn upgrade_database_if_needed(
             ALTER TABLE config_data ADD lrclib_instance TEXT DEFAULT 'https://lrclib.net';
             CREATE INDEX idx_albums_album_artist_name_lower ON albums(album_artist_name_lower);
             CREATE INDEX idx_tracks_track_number ON tracks(track_number);
-
-            DELETE FROM tracks WHERE 1;
-            DELETE FROM albums WHERE 1;
-            DELETE FROM artists WHERE 1;
-            UPDATE library_data SET init = 0 WHERE 1;
             "})?;
 
-            tx.commit()?;
+            commit_migration(
+                tx,
+                &[
+                    ("tracks", &["track_number"]),
+                    ("albums", &["album_artist_name", "album_artist_name_lower"]),
+                    ("config_data", &["theme_mode", "lrclib_instance"]),
+                ],
+            )?;
         }
 
         if existing_version <= 5 {
@@ -185,273 +295,1363 @@ pub fn upgrade_database_if_needed(
             ALTER TABLE config_data DROP COLUMN skip_not_needed_tracks;
             "})?;
 
-            tx.commit()?;
+            commit_migration(
+                tx,
+                &[(
+                    "config_data",
+                    &[
+                        "skip_tracks_with_synced_lyrics",
+                        "skip_tracks_with_plain_lyrics",
+                    ],
+                )],
+            )?;
         }
-    }
 
-    Ok(())
-}
+        if existing_version <= 6 {
+            println!("Migrate database version 7...");
+            let tx = db.transaction()?;
 
-pub fn get_directories(db: &Connection) -> Result<Vec<String>> {
-    let mut statement = db.prepare("SELECT * FROM directories")?;
-    let mut rows = statement.query([])?;
-    let mut directories: Vec<String> = Vec::new();
-    while let Some(row) = rows.next()? {
-        let path: String = row.get("path")?;
+            tx.pragma_update(None, "user_version", 7)?;
 
-        directories.push(path);
-    }
+            tx.execute_batch(indoc! {"
+            ALTER TABLE tracks ADD file_mtime INTEGER;
+            ALTER TABLE tracks ADD file_size INTEGER;
+            CREATE INDEX idx_tracks_file_path ON tracks(file_path);
+            "})?;
 
-    Ok(directories)
-}
+            commit_migration(tx, &[("tracks", &["file_mtime", "file_size"])])?;
+        }
 
-pub fn set_directories(directories: Vec<String>, db: &Connection) -> Result<()> {
-    db.execute("DELETE FROM directories WHERE 1", ())?;
-    let mut statement = db.prepare("INSERT INTO directories (path) VALUES (@path)")?;
-    for directory in directories.iter() {
-        statement.execute(named_params! { "@path": directory })?;
-    }
+        if existing_version <= 7 {
+            println!("Migrate database version 8...");
+            let tx = db.transaction()?;
 
-    Ok(())
-}
+            tx.pragma_update(None, "user_version", 8)?;
 
-pub fn get_init(db: &Connection) -> Result<bool> {
-    let mut statement = db.prepare("SELECT init FROM library_data LIMIT 1")?;
-    let init: bool = statement.query_row([], |r| r.get(0))?;
-    Ok(init)
-}
+            tx.execute_batch(indoc! {"
+            ALTER TABLE config_data ADD watch_library BOOLEAN DEFAULT 0;
+            "})?;
 
-pub fn set_init(init: bool, db: &Connection) -> Result<()> {
-    let mut statement = db.prepare("UPDATE library_data SET init = ? WHERE 1")?;
-    statement.execute([init])?;
-    Ok(())
-}
+            commit_migration(tx, &[("config_data", &["watch_library"])])?;
+        }
 
-pub fn get_config(db: &Connection) -> Result<PersistentConfig> {
-    let mut statement = db.prepare(indoc! {"
-      SELECT
-        skip_tracks_with_synced_lyrics,
-        skip_tracks_with_plain_lyrics,
-        try_embed_lyrics,
-        theme_mode,
-        lrclib_instance
-      FROM config_data
-      LIMIT 1
-    "})?;
-    let row = statement.query_row([], |r| {
-        Ok(PersistentConfig {
-            skip_tracks_with_synced_lyrics: r.get("skip_tracks_with_synced_lyrics")?,
-            skip_tracks_with_plain_lyrics: r.get("skip_tracks_with_plain_lyrics")?,
-            try_embed_lyrics: r.get("try_embed_lyrics")?,
-            theme_mode: r.get("theme_mode")?,
-            lrclib_instance: r.get("lrclib_instance")?,
-        })
-    })?;
-    Ok(row)
-}
+        if existing_version <= 8 {
+            println!("Migrate database version 9...");
+            let tx = db.transaction()?;
 
-pub fn set_config(
-    skip_tracks_with_synced_lyrics: bool,
-    skip_tracks_with_plain_lyrics: bool,
-    try_embed_lyrics: bool,
-    theme_mode: &str,
-    lrclib_instance: &str,
-    db: &Connection,
-) -> Result<()> {
-    let mut statement = db.prepare(indoc! {"
-      UPDATE config_data
-      SET
-        skip_tracks_with_synced_lyrics = ?,
-        skip_tracks_with_plain_lyrics = ?,
-        try_embed_lyrics = ?,
-        theme_mode = ?,
-        lrclib_instance = ?
-      WHERE 1
-    "})?;
-    statement.execute((
-        skip_tracks_with_synced_lyrics,
-        skip_tracks_with_plain_lyrics,
-        try_embed_lyrics,
-        theme_mode,
-        lrclib_instance,
-    ))?;
-    Ok(())
-}
+            tx.pragma_update(None, "user_version", 9)?;
 
-pub fn find_artist(name: &str, db: &Connection) -> Result<i64> {
-    let mut statement = db.prepare("SELECT id FROM artists WHERE name = ?")?;
-    let id: i64 = statement.query_row([name], |r| r.get(0))?;
-    Ok(id)
-}
+            tx.execute_batch(indoc! {"
+            CREATE TABLE path_templates (
+                id INTEGER PRIMARY KEY,
+                template TEXT
+            );
 
-pub fn add_artist(name: &str, db: &Connection) -> Result<i64> {
-    let mut statement = db.prepare("INSERT INTO artists (name, name_lower) VALUES (?, ?)")?;
-    let row_id = statement.insert((name, prepare_input(name)))?;
-    Ok(row_id)
-}
+            ALTER TABLE tracks ADD inferred_fields TEXT;
+            "})?;
 
-pub fn find_album(name: &str, album_artist_name: &str, db: &Connection) -> Result<i64> {
-    let mut statement =
-        db.prepare("SELECT id FROM albums WHERE name = ? AND album_artist_name = ?")?;
-    let id: i64 = statement.query_row((name, album_artist_name), |r| r.get(0))?;
-    Ok(id)
-}
+            commit_migration(
+                tx,
+                &[
+                    ("path_templates", &["id", "template"]),
+                    ("tracks", &["inferred_fields"]),
+                ],
+            )?;
+        }
 
-pub fn add_album(name: &str, album_artist_name: &str, db: &Connection) -> Result<i64> {
-    let mut statement = db.prepare("INSERT INTO albums (name, name_lower, album_artist_name, album_artist_name_lower) VALUES (?, ?, ?, ?)")?;
-    let row_id = statement.insert((
-        name,
-        prepare_input(name),
-        album_artist_name,
-        prepare_input(album_artist_name),
-    ))?;
-    Ok(row_id)
-}
+        if existing_version <= 9 {
+            println!("Migrate database version 10...");
+            let tx = db.transaction()?;
 
-pub fn get_track_by_id(id: i64, db: &Connection) -> Result<PersistentTrack> {
-    let query = indoc! {"
-    SELECT
-      tracks.id,
-      file_path,
-      file_name,
-      title,
-      artists.name AS artist_name,
-      tracks.artist_id,
-      albums.name AS album_name,
-      albums.album_artist_name,
-      album_id,
-      duration,
-      track_number,
-      albums.image_path,
-      txt_lyrics,
-      lrc_lyrics,
-      instrumental
-    FROM tracks
-    JOIN albums ON tracks.album_id = albums.id
-    JOIN artists ON tracks.artist_id = artists.id
-    WHERE tracks.id = ?
-    LIMIT 1
-  "};
+            tx.pragma_update(None, "user_version", 10)?;
 
-    let mut statement = db.prepare(query)?;
-    let row = statement.query_row([id], |row| {
-        let is_instrumental: Option<bool> = row.get("instrumental")?;
+            tx.execute_batch(indoc! {"
+            CREATE TABLE scan_failures (
+                id INTEGER PRIMARY KEY,
+                file_path TEXT UNIQUE,
+                error_kind TEXT,
+                message TEXT
+            );
 
-        Ok(PersistentTrack {
-            id: row.get("id")?,
-            file_path: row.get("file_path")?,
-            file_name: row.get("file_name")?,
-            title: row.get("title")?,
-            artist_name: row.get("artist_name")?,
-            artist_id: row.get("artist_id")?,
-            album_name: row.get("album_name")?,
-            album_artist_name: row.get("album_artist_name")?,
-            album_id: row.get("album_id")?,
-            duration: row.get("duration")?,
-            track_number: row.get("track_number")?,
-            txt_lyrics: row.get("txt_lyrics")?,
-            lrc_lyrics: row.get("lrc_lyrics")?,
-            image_path: row.get("image_path")?,
-            instrumental: is_instrumental.unwrap_or(false),
-        })
-    })?;
-    Ok(row)
-}
+            CREATE INDEX idx_scan_failures_error_kind ON scan_failures(error_kind);
+            "})?;
 
-pub fn update_track_synced_lyrics(
-    id: i64,
-    synced_lyrics: &str,
-    plain_lyrics: &str,
-    db: &Connection,
-) -> Result<PersistentTrack> {
-    let mut statement = db.prepare(
-        "UPDATE tracks SET lrc_lyrics = ?, txt_lyrics = ?, instrumental = false WHERE id = ?",
-    )?;
-    statement.execute((synced_lyrics, plain_lyrics, id))?;
+            commit_migration(
+                tx,
+                &[(
+                    "scan_failures",
+                    &["id", "file_path", "error_kind", "message"],
+                )],
+            )?;
+        }
 
-    Ok(get_track_by_id(id, db)?)
-}
+        if existing_version <= 10 {
+            println!("Migrate database version 11...");
+            let tx = db.transaction()?;
 
-pub fn update_track_plain_lyrics(
-    id: i64,
-    plain_lyrics: &str,
-    db: &Connection,
-) -> Result<PersistentTrack> {
-    let mut statement = db.prepare(
-        "UPDATE tracks SET txt_lyrics = ?, lrc_lyrics = null, instrumental = false WHERE id = ?",
-    )?;
-    statement.execute((plain_lyrics, id))?;
+            tx.pragma_update(None, "user_version", 11)?;
 
-    Ok(get_track_by_id(id, db)?)
-}
+            tx.execute_batch(indoc! {"
+            ALTER TABLE directories ADD extensions TEXT;
+            ALTER TABLE directories ADD exclude_patterns TEXT;
+            ALTER TABLE directories ADD min_duration FLOAT;
+            ALTER TABLE directories ADD follow_symlinks BOOLEAN DEFAULT 0;
+            "})?;
 
-pub fn update_track_null_lyrics(id: i64, db: &Connection) -> Result<PersistentTrack> {
-    let mut statement = db.prepare(
-        "UPDATE tracks SET txt_lyrics = null, lrc_lyrics = null, instrumental = false WHERE id = ?",
-    )?;
-    statement.execute([id])?;
+            commit_migration(
+                tx,
+                &[(
+                    "directories",
+                    &[
+                        "extensions",
+                        "exclude_patterns",
+                        "min_duration",
+                        "follow_symlinks",
+                    ],
+                )],
+            )?;
+        }
 
-    Ok(get_track_by_id(id, db)?)
-}
+        if existing_version <= 11 {
+            println!("Migrate database version 12...");
+            let tx = db.transaction()?;
 
-pub fn update_track_instrumental(id: i64, db: &Connection) -> Result<PersistentTrack> {
-    let mut statement = db.prepare(
-        "UPDATE tracks SET txt_lyrics = null, lrc_lyrics = ?, instrumental = true WHERE id = ?",
-    )?;
-    statement.execute(params!["[au: instrumental]", id])?;
+            tx.pragma_update(None, "user_version", 12)?;
 
-    Ok(get_track_by_id(id, db)?)
-}
+            tx.execute_batch(indoc! {"
+            ALTER TABLE albums ADD artwork_checked BOOLEAN DEFAULT 0;
+            "})?;
 
-pub fn add_tracks(tracks: &Vec<fs_track::FsTrack>, db: &mut Connection) -> Result<()> {
-    let tx = db.transaction()?;
+            commit_migration(tx, &[("albums", &["artwork_checked"])])?;
+        }
 
-    for track in tracks.iter() {
-        add_track(track, &tx)?;
-    }
+        if existing_version <= 12 {
+            println!("Migrate database version 13...");
+            let tx = db.transaction()?;
 
-    tx.commit()?;
+            tx.pragma_update(None, "user_version", 13)?;
 
-    Ok(())
-}
+            // Clearing the mtime makes the next refresh re-read the disc numbers of every track
+            tx.execute_batch(indoc! {"
+            ALTER TABLE tracks ADD track_total INTEGER;
+            ALTER TABLE tracks ADD disc_number INTEGER;
+            ALTER TABLE tracks ADD disc_total INTEGER;
+            UPDATE tracks SET file_mtime = NULL;
+            "})?;
 
-pub fn add_track(track: &fs_track::FsTrack, db: &Connection) -> Result<()> {
-    let artist_result = find_artist(&track.artist(), db);
-    let artist_id = match artist_result {
-        Ok(artist_id) => artist_id,
-        Err(_) => add_artist(&track.artist(), db)?,
-    };
+            commit_migration(
+                tx,
+                &[("tracks", &["track_total", "disc_number", "disc_total"])],
+            )?;
+        }
 
-    let album_result = find_album(&track.album(), &track.album_artist(), db);
-    let album_id = match album_result {
-        Ok(album_id) => album_id,
-        Err(_) => add_album(&track.album(), &track.album_artist(), db)?,
-    };
+        if existing_version <= 13 {
+            println!("Migrate database version 14...");
+            let tx = db.transaction()?;
 
-    // Create a regex to match "[au: instrumental]" or "[au:instrumental]"
-    let re = Regex::new(r"\[au:\s*instrumental\]").expect("Invalid regex");
-    let is_instrumental = track
-        .lrc_lyrics()
-        .as_ref()
-        .map_or(false, |lyrics| re.is_match(lyrics));
+            tx.pragma_update(None, "user_version", 14)?;
 
-    let query = indoc! {"
-    INSERT INTO tracks (
-        file_path,
-        file_name,
-        title,
-        title_lower,
-        album_id,
-        artist_id,
-        duration,
-        track_number,
-        txt_lyrics,
+            // Existing tracks keep their single artist until the next refresh splits it
+            tx.execute_batch(indoc! {"
+            CREATE TABLE track_artists (
+                track_id INTEGER NOT NULL,
+                artist_id INTEGER NOT NULL,
+                position INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY(track_id, artist_id),
+                FOREIGN KEY(track_id) REFERENCES tracks(id),
+                FOREIGN KEY(artist_id) REFERENCES artists(id)
+            );
+
+            CREATE INDEX idx_track_artists_artist_id ON track_artists(artist_id);
+
+            INSERT INTO track_artists (track_id, artist_id) SELECT id, artist_id FROM tracks;
+            UPDATE tracks SET file_mtime = NULL;
+
+            CREATE TABLE artist_separators (
+                id INTEGER PRIMARY KEY,
+                separator TEXT NOT NULL
+            );
+            "})?;
+
+            {
+                let mut statement =
+                    tx.prepare("INSERT INTO artist_separators (separator) VALUES (?)")?;
+                for separator in DEFAULT_ARTIST_SEPARATORS.iter() {
+                    statement.execute([separator])?;
+                }
+            }
+
+            commit_migration(
+                tx,
+                &[
+                    ("track_artists", &["track_id", "artist_id", "position"]),
+                    ("artist_separators", &["id", "separator"]),
+                ],
+            )?;
+        }
+
+        if existing_version <= 14 {
+            println!("Migrate database version 15...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 15)?;
+
+            tx.execute_batch(indoc! {"
+            ALTER TABLE library_data ADD scan_in_progress BOOLEAN DEFAULT 0;
+
+            CREATE TABLE scan_checkpoints (
+                directory_path TEXT PRIMARY KEY,
+                files_scanned INTEGER NOT NULL DEFAULT 0,
+                completed BOOLEAN NOT NULL DEFAULT 0
+            );
+            "})?;
+
+            commit_migration(
+                tx,
+                &[
+                    ("library_data", &["scan_in_progress"]),
+                    (
+                        "scan_checkpoints",
+                        &["directory_path", "files_scanned", "completed"],
+                    ),
+                ],
+            )?;
+        }
+
+        if existing_version <= 15 {
+            println!("Migrate database version 16...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 16)?;
+
+            // Clearing the mtime makes the next refresh pick up lyrics embedded in the tags
+            tx.execute_batch(indoc! {"
+            ALTER TABLE tracks ADD lyrics_source TEXT;
+            UPDATE tracks SET lyrics_source = 'sidecar'
+            WHERE txt_lyrics IS NOT NULL OR lrc_lyrics IS NOT NULL;
+            UPDATE tracks SET file_mtime = NULL;
+            "})?;
+
+            commit_migration(tx, &[("tracks", &["lyrics_source"])])?;
+        }
+
+        if existing_version <= 16 {
+            println!("Migrate database version 17...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 17)?;
+
+            tx.execute_batch(indoc! {"
+            ALTER TABLE tracks ADD year INTEGER;
+            ALTER TABLE tracks ADD genre TEXT;
+            ALTER TABLE tracks ADD isrc TEXT;
+            ALTER TABLE tracks ADD musicbrainz_recording_id TEXT;
+            ALTER TABLE tracks ADD musicbrainz_release_id TEXT;
+            ALTER TABLE tracks ADD codec TEXT;
+            ALTER TABLE tracks ADD bitrate INTEGER;
+            ALTER TABLE tracks ADD sample_rate INTEGER;
+            ALTER TABLE tracks ADD channels INTEGER;
+            CREATE INDEX idx_tracks_year ON tracks(year);
+            CREATE INDEX idx_tracks_genre ON tracks(genre);
+            CREATE INDEX idx_tracks_codec ON tracks(codec);
+            UPDATE tracks SET file_mtime = NULL;
+            "})?;
+
+            commit_migration(
+                tx,
+                &[(
+                    "tracks",
+                    &[
+                        "year",
+                        "genre",
+                        "isrc",
+                        "musicbrainz_recording_id",
+                        "musicbrainz_release_id",
+                        "codec",
+                        "bitrate",
+                        "sample_rate",
+                        "channels",
+                    ],
+                )],
+            )?;
+        }
+
+        if existing_version <= 17 {
+            println!("Migrate database version 18...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 18)?;
+
+            tx.execute_batch(indoc! {"
+            ALTER TABLE tracks ADD content_hash TEXT;
+            CREATE INDEX idx_tracks_content_hash ON tracks(content_hash);
+            UPDATE tracks SET file_mtime = NULL;
+            "})?;
+
+            commit_migration(tx, &[("tracks", &["content_hash"])])?;
+        }
+
+        if existing_version <= 18 {
+            println!("Migrate database version 19...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 19)?;
+
+            tx.execute_batch(indoc! {"
+            ALTER TABLE tracks ADD cue_track_number INTEGER;
+            ALTER TABLE tracks ADD cue_start REAL;
+            ALTER TABLE tracks ADD cue_end REAL;
+            UPDATE tracks SET file_mtime = NULL;
+            "})?;
+
+            commit_migration(
+                tx,
+                &[("tracks", &["cue_track_number", "cue_start", "cue_end"])],
+            )?;
+        }
+
+        if existing_version <= 19 {
+            println!("Migrate database version 20...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 20)?;
+
+            // The index holds the normalized `*_lower` values, so search terms go through
+            // `prepare_input` just like the stored names
+            tx.execute_batch(indoc! {"
+            CREATE VIRTUAL TABLE tracks_fts USING fts5(
+                title,
+                artist,
+                album,
+                album_artist,
+                tokenize = 'unicode61 remove_diacritics 2'
+            );
+
+            CREATE TRIGGER tracks_fts_insert AFTER INSERT ON tracks BEGIN
+                INSERT INTO tracks_fts (rowid, title, artist, album, album_artist)
+                SELECT new.id, new.title_lower, artists.name_lower, albums.name_lower,
+                    albums.album_artist_name_lower
+                FROM artists, albums
+                WHERE artists.id = new.artist_id AND albums.id = new.album_id;
+            END;
+
+            CREATE TRIGGER tracks_fts_update AFTER UPDATE OF title_lower, artist_id, album_id
+            ON tracks BEGIN
+                DELETE FROM tracks_fts WHERE rowid = old.id;
+                INSERT INTO tracks_fts (rowid, title, artist, album, album_artist)
+                SELECT new.id, new.title_lower, artists.name_lower, albums.name_lower,
+                    albums.album_artist_name_lower
+                FROM artists, albums
+                WHERE artists.id = new.artist_id AND albums.id = new.album_id;
+            END;
+
+            CREATE TRIGGER tracks_fts_delete AFTER DELETE ON tracks BEGIN
+                DELETE FROM tracks_fts WHERE rowid = old.id;
+            END;
+
+            INSERT INTO tracks_fts (rowid, title, artist, album, album_artist)
+            SELECT tracks.id, tracks.title_lower, artists.name_lower, albums.name_lower,
+                albums.album_artist_name_lower
+            FROM tracks
+            JOIN artists ON tracks.artist_id = artists.id
+            JOIN albums ON tracks.album_id = albums.id;
+            "})?;
+
+            commit_migration(
+                tx,
+                &[("tracks_fts", &["title", "artist", "album", "album_artist"])],
+            )?;
+        }
+
+        if existing_version <= 20 {
+            println!("Migrate database version 21...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 21)?;
+
+            tx.execute_batch(indoc! {"
+            CREATE VIRTUAL TABLE lyrics_fts USING fts5(
+                txt_lyrics,
+                lrc_lyrics,
+                content = 'tracks',
+                content_rowid = 'id',
+                tokenize = 'unicode61 remove_diacritics 2'
+            );
+
+            CREATE TRIGGER lyrics_fts_insert AFTER INSERT ON tracks BEGIN
+                INSERT INTO lyrics_fts (rowid, txt_lyrics, lrc_lyrics)
+                VALUES (new.id, new.txt_lyrics, new.lrc_lyrics);
+            END;
+
+            CREATE TRIGGER lyrics_fts_update AFTER UPDATE OF txt_lyrics, lrc_lyrics ON tracks BEGIN
+                INSERT INTO lyrics_fts (lyrics_fts, rowid, txt_lyrics, lrc_lyrics)
+                VALUES ('delete', old.id, old.txt_lyrics, old.lrc_lyrics);
+                INSERT INTO lyrics_fts (rowid, txt_lyrics, lrc_lyrics)
+                VALUES (new.id, new.txt_lyrics, new.lrc_lyrics);
+            END;
+
+            CREATE TRIGGER lyrics_fts_delete AFTER DELETE ON tracks BEGIN
+                INSERT INTO lyrics_fts (lyrics_fts, rowid, txt_lyrics, lrc_lyrics)
+                VALUES ('delete', old.id, old.txt_lyrics, old.lrc_lyrics);
+            END;
+
+            INSERT INTO lyrics_fts (lyrics_fts) VALUES ('rebuild');
+            "})?;
+
+            commit_migration(tx, &[("lyrics_fts", &["txt_lyrics", "lrc_lyrics"])])?;
+        }
+
+        if existing_version <= 21 {
+            println!("Migrate database version 22...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 22)?;
+
+            // Tracks that were already in the library are dated by their file's modification
+            // time, the closest to when they were added that is known
+            tx.execute_batch(indoc! {"
+            ALTER TABLE tracks ADD added_at INTEGER;
+            UPDATE tracks SET added_at = COALESCE(file_mtime, CAST(strftime('%s', 'now') AS INTEGER));
+            CREATE INDEX idx_tracks_added_at ON tracks(added_at);
+            "})?;
+
+            commit_migration(tx, &[("tracks", &["added_at"])])?;
+        }
+
+        if existing_version <= 22 {
+            println!("Migrate database version 23...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 23)?;
+
+            tx.execute_batch(indoc! {"
+            CREATE TABLE lyrics_revisions (
+                id INTEGER PRIMARY KEY,
+                track_id INTEGER NOT NULL,
+                txt_lyrics TEXT,
+                lrc_lyrics TEXT,
+                instrumental BOOLEAN NOT NULL DEFAULT false,
+                source TEXT NOT NULL,
+                lrclib_id INTEGER,
+                batch_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY(track_id) REFERENCES tracks(id)
+            );
+            CREATE INDEX idx_lyrics_revisions_track_id ON lyrics_revisions(track_id);
+            CREATE INDEX idx_lyrics_revisions_batch_id ON lyrics_revisions(batch_id);
+            "})?;
+
+            commit_migration(
+                tx,
+                &[(
+                    "lyrics_revisions",
+                    &[
+                        "id",
+                        "track_id",
+                        "txt_lyrics",
+                        "lrc_lyrics",
+                        "instrumental",
+                        "source",
+                        "lrclib_id",
+                        "batch_id",
+                        "created_at",
+                    ],
+                )],
+            )?;
+        }
+
+        if existing_version <= 23 {
+            println!("Migrate database version 24...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 24)?;
+
+            tx.execute_batch(indoc! {"
+            CREATE TABLE download_attempts (
+                id INTEGER PRIMARY KEY,
+                track_id INTEGER NOT NULL,
+                outcome TEXT NOT NULL,
+                lrclib_instance TEXT NOT NULL,
+                attempted_at INTEGER NOT NULL,
+                FOREIGN KEY(track_id) REFERENCES tracks(id)
+            );
+            CREATE INDEX idx_download_attempts_track_id ON download_attempts(track_id, lrclib_instance);
+            ALTER TABLE config_data ADD retry_not_found_days INTEGER DEFAULT 7;
+            "})?;
+
+            commit_migration(
+                tx,
+                &[
+                    (
+                        "download_attempts",
+                        &[
+                            "id",
+                            "track_id",
+                            "outcome",
+                            "lrclib_instance",
+                            "attempted_at",
+                        ],
+                    ),
+                    ("config_data", &["retry_not_found_days"]),
+                ],
+            )?;
+        }
+
+        if existing_version <= 24 {
+            println!("Migrate database version 25...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 25)?;
+
+            // Revisions outlive their track: a removed track leaves them detached, with what
+            // identifies its file, so they come back when the file is scanned again
+            tx.execute_batch(indoc! {"
+            CREATE TABLE lyrics_revisions_new (
+                id INTEGER PRIMARY KEY,
+                track_id INTEGER,
+                txt_lyrics TEXT,
+                lrc_lyrics TEXT,
+                instrumental BOOLEAN NOT NULL DEFAULT false,
+                source TEXT NOT NULL,
+                lrclib_id INTEGER,
+                batch_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                file_path TEXT,
+                cue_track_number INTEGER,
+                content_hash TEXT,
+                FOREIGN KEY(track_id) REFERENCES tracks(id)
+            );
+            INSERT INTO lyrics_revisions_new (
+                id, track_id, txt_lyrics, lrc_lyrics, instrumental, source, lrclib_id, batch_id,
+                created_at
+            )
+            SELECT
+                id, track_id, txt_lyrics, lrc_lyrics, instrumental, source, lrclib_id, batch_id,
+                created_at
+            FROM lyrics_revisions;
+            DROP TABLE lyrics_revisions;
+            ALTER TABLE lyrics_revisions_new RENAME TO lyrics_revisions;
+
+            CREATE INDEX idx_lyrics_revisions_track_id ON lyrics_revisions(track_id);
+            CREATE INDEX idx_lyrics_revisions_batch_id ON lyrics_revisions(batch_id);
+            CREATE INDEX idx_lyrics_revisions_file_path ON lyrics_revisions(file_path);
+            CREATE INDEX idx_lyrics_revisions_content_hash ON lyrics_revisions(content_hash);
+
+            CREATE TRIGGER lyrics_revisions_reattach AFTER INSERT ON tracks BEGIN
+                UPDATE lyrics_revisions
+                SET track_id = new.id, file_path = NULL, cue_track_number = NULL,
+                    content_hash = NULL
+                WHERE track_id IS NULL
+                  AND (
+                    (file_path = new.file_path AND cue_track_number IS new.cue_track_number)
+                    OR content_hash = new.content_hash
+                  );
+            END;
+            "})?;
+
+            commit_migration(
+                tx,
+                &[(
+                    "lyrics_revisions",
+                    &["track_id", "file_path", "cue_track_number", "content_hash"],
+                )],
+            )?;
+        }
+
+        if existing_version <= 25 {
+            println!("Migrate database version 26...");
+            let tx = db.transaction()?;
+
+            tx.pragma_update(None, "user_version", 26)?;
+
+            // Synced lyrics are indexed without their timestamps, which would otherwise be
+            // indexed as words. The index no longer mirrors the tracks' columns, so it keeps
+            // no content of its own
+            tx.execute_batch(indoc! {"
+            DROP TRIGGER lyrics_fts_insert;
+            DROP TRIGGER lyrics_fts_update;
+            DROP TRIGGER lyrics_fts_delete;
+            DROP TABLE lyrics_fts;
+
+            CREATE VIRTUAL TABLE lyrics_fts USING fts5(
+                txt_lyrics,
+                lrc_lyrics,
+                content = '',
+                contentless_delete = 1,
+                tokenize = 'unicode61 remove_diacritics 2'
+            );
+
+            CREATE TRIGGER lyrics_fts_insert AFTER INSERT ON tracks BEGIN
+                INSERT INTO lyrics_fts (rowid, txt_lyrics, lrc_lyrics)
+                VALUES (new.id, new.txt_lyrics, lrc_text(new.lrc_lyrics));
+            END;
+
+            CREATE TRIGGER lyrics_fts_update AFTER UPDATE OF txt_lyrics, lrc_lyrics ON tracks BEGIN
+                DELETE FROM lyrics_fts WHERE rowid = old.id;
+                INSERT INTO lyrics_fts (rowid, txt_lyrics, lrc_lyrics)
+                VALUES (new.id, new.txt_lyrics, lrc_text(new.lrc_lyrics));
+            END;
+
+            CREATE TRIGGER lyrics_fts_delete AFTER DELETE ON tracks BEGIN
+                DELETE FROM lyrics_fts WHERE rowid = old.id;
+            END;
+
+            INSERT INTO lyrics_fts (rowid, txt_lyrics, lrc_lyrics)
+            SELECT id, txt_lyrics, lrc_text(lrc_lyrics) FROM tracks;
+            "})?;
+
+            commit_migration(tx, &[("lyrics_fts", &["txt_lyrics", "lrc_lyrics"])])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Tables of the schema, each with the columns it must have.
+type ExpectedSchema<'a> = &'a [(&'a str, &'a [&'a str])];
+
+/// Commits a migration step once the database it left behind passes `PRAGMA quick_check` and
+/// has the tables and columns the step was meant to create.
+fn commit_migration(tx: Transaction, expected_schema: ExpectedSchema) -> Result<()> {
+    let version: u32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let check: String = tx.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+    if check != "ok" {
+        return Err(MigrationError::CheckFailed(version, check).into());
+    }
+
+    for (table, columns) in expected_schema.iter() {
+        let mut statement = tx.prepare("SELECT name FROM pragma_table_info(?)")?;
+        let existing_columns = statement
+            .query_map([table], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        for column in columns.iter() {
+            if !existing_columns.contains(*column) {
+                return Err(MigrationError::MissingColumn(
+                    version,
+                    table.to_string(),
+                    column.to_string(),
+                )
+                .into());
+            }
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+pub fn get_directories(db: &Connection) -> Result<Vec<String>> {
+    let mut statement = db.prepare("SELECT * FROM directories")?;
+    let mut rows = statement.query([])?;
+    let mut directories: Vec<String> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get("path")?;
+
+        directories.push(path);
+    }
+
+    Ok(directories)
+}
+
+/// Replaces the library directories. Directories that are kept retain their scan settings.
+pub fn set_directories(directories: Vec<String>, db: &Connection) -> Result<()> {
+    let existing_directories = get_directories(db)?;
+
+    let mut statement = db.prepare("DELETE FROM directories WHERE path = @path")?;
+    for directory in existing_directories.iter() {
+        if !directories.contains(directory) {
+            statement.execute(named_params! { "@path": directory })?;
+        }
+    }
+
+    let mut statement = db.prepare("INSERT INTO directories (path) VALUES (@path)")?;
+    for directory in directories.iter() {
+        if !existing_directories.contains(directory) {
+            statement.execute(named_params! { "@path": directory })?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn get_directory_settings(db: &Connection) -> Result<Vec<PersistentDirectory>> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT id, path, extensions, exclude_patterns, min_duration, follow_symlinks
+      FROM directories
+      ORDER BY id ASC
+  "})?;
+    let mut rows = statement.query([])?;
+    let mut directories: Vec<PersistentDirectory> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let extensions: Option<String> = row.get("extensions")?;
+        let exclude_patterns: Option<String> = row.get("exclude_patterns")?;
+        let follow_symlinks: Option<bool> = row.get("follow_symlinks")?;
+
+        directories.push(PersistentDirectory {
+            id: row.get("id")?,
+            path: row.get("path")?,
+            extensions: split_list(extensions, ','),
+            exclude_patterns: split_list(exclude_patterns, '\n'),
+            min_duration: row.get("min_duration")?,
+            follow_symlinks: follow_symlinks.unwrap_or(false),
+        });
+    }
+
+    Ok(directories)
+}
+
+pub fn set_directory_settings(
+    path: &str,
+    extensions: &[String],
+    exclude_patterns: &[String],
+    min_duration: Option<f64>,
+    follow_symlinks: bool,
+    db: &Connection,
+) -> Result<()> {
+    let mut statement = db.prepare(indoc! {"
+      UPDATE directories
+      SET
+        extensions = ?,
+        exclude_patterns = ?,
+        min_duration = ?,
+        follow_symlinks = ?
+      WHERE path = ?
+    "})?;
+    statement.execute((
+        join_list(extensions, ","),
+        join_list(exclude_patterns, "\n"),
+        min_duration,
+        follow_symlinks,
+        path,
+    ))?;
+    Ok(())
+}
+
+fn split_list(value: Option<String>, separator: char) -> Vec<String> {
+    value
+        .map(|value| {
+            value
+                .split(separator)
+                .filter(|item| !item.is_empty())
+                .map(|item| item.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn join_list(items: &[String], separator: &str) -> Option<String> {
+    if items.is_empty() {
+        None
+    } else {
+        Some(items.join(separator))
+    }
+}
+
+pub fn get_path_templates(db: &Connection) -> Result<Vec<String>> {
+    let mut statement = db.prepare("SELECT template FROM path_templates ORDER BY id ASC")?;
+    let mut rows = statement.query([])?;
+    let mut templates: Vec<String> = Vec::new();
+    while let Some(row) = rows.next()? {
+        templates.push(row.get("template")?);
+    }
+
+    Ok(templates)
+}
+
+pub fn set_path_templates(templates: Vec<String>, db: &Connection) -> Result<()> {
+    db.execute("DELETE FROM path_templates WHERE 1", ())?;
+    let mut statement = db.prepare("INSERT INTO path_templates (template) VALUES (@template)")?;
+    for template in templates.iter() {
+        statement.execute(named_params! { "@template": template })?;
+    }
+
+    Ok(())
+}
+
+pub fn get_artist_separators(db: &Connection) -> Result<Vec<String>> {
+    let mut statement = db.prepare("SELECT separator FROM artist_separators ORDER BY id ASC")?;
+    let mut rows = statement.query([])?;
+    let mut separators: Vec<String> = Vec::new();
+    while let Some(row) = rows.next()? {
+        separators.push(row.get("separator")?);
+    }
+
+    Ok(separators)
+}
+
+pub fn set_artist_separators(separators: Vec<String>, db: &Connection) -> Result<()> {
+    db.execute("DELETE FROM artist_separators WHERE 1", ())?;
+    let mut statement =
+        db.prepare("INSERT INTO artist_separators (separator) VALUES (@separator)")?;
+    for separator in separators.iter() {
+        statement.execute(named_params! { "@separator": separator })?;
+    }
+
+    // Makes the next refresh re-read every file so artists are split with the new separators
+    db.execute("UPDATE tracks SET file_mtime = NULL WHERE 1", ())?;
+
+    Ok(())
+}
+
+pub fn get_init(db: &Connection) -> Result<bool> {
+    let mut statement = db.prepare("SELECT init FROM library_data LIMIT 1")?;
+    let init: bool = statement.query_row([], |r| r.get(0))?;
+    Ok(init)
+}
+
+pub fn set_init(init: bool, db: &Connection) -> Result<()> {
+    let mut statement = db.prepare("UPDATE library_data SET init = ? WHERE 1")?;
+    statement.execute([init])?;
+    Ok(())
+}
+
+/// Whether an initialization was started and hasn't finished yet, so it should be resumed.
+pub fn get_scan_in_progress(db: &Connection) -> Result<bool> {
+    let mut statement = db.prepare("SELECT scan_in_progress FROM library_data LIMIT 1")?;
+    let scan_in_progress: Option<bool> = statement.query_row([], |r| r.get(0))?;
+    Ok(scan_in_progress.unwrap_or(false))
+}
+
+pub fn set_scan_in_progress(scan_in_progress: bool, db: &Connection) -> Result<()> {
+    let mut statement = db.prepare("UPDATE library_data SET scan_in_progress = ? WHERE 1")?;
+    statement.execute([scan_in_progress])?;
+    Ok(())
+}
+
+pub fn save_scan_checkpoint(
+    directory_path: &str,
+    files_scanned: usize,
+    completed: bool,
+    db: &Connection,
+) -> Result<()> {
+    let mut statement = db.prepare(indoc! {"
+      INSERT OR REPLACE INTO scan_checkpoints (directory_path, files_scanned, completed)
+      VALUES (?, ?, ?)
+    "})?;
+    statement.execute((directory_path, files_scanned, completed))?;
+    Ok(())
+}
+
+/// Returns the directories that an interrupted scan completed, with how many files it found
+/// in each of them.
+pub fn get_completed_scan_directories(db: &Connection) -> Result<HashMap<String, usize>> {
+    let mut statement = db.prepare(
+        "SELECT directory_path, files_scanned FROM scan_checkpoints WHERE completed = 1",
+    )?;
+    let mut rows = statement.query([])?;
+    let mut directories: HashMap<String, usize> = HashMap::new();
+
+    while let Some(row) = rows.next()? {
+        directories.insert(row.get("directory_path")?, row.get("files_scanned")?);
+    }
+
+    Ok(directories)
+}
+
+pub fn clean_scan_checkpoints(db: &Connection) -> Result<()> {
+    db.execute("DELETE FROM scan_checkpoints WHERE 1", ())?;
+    Ok(())
+}
+
+/// Paths of every file that a scan has already handled, whether it became a track or failed.
+pub fn get_scanned_file_paths(db: &Connection) -> Result<HashSet<String>> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT file_path FROM tracks
+      UNION
+      SELECT file_path FROM scan_failures
+    "})?;
+    let mut rows = statement.query([])?;
+    let mut file_paths: HashSet<String> = HashSet::new();
+
+    while let Some(row) = rows.next()? {
+        file_paths.insert(row.get("file_path")?);
+    }
+
+    Ok(file_paths)
+}
+
+pub fn get_config(db: &Connection) -> Result<PersistentConfig> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT
+        skip_tracks_with_synced_lyrics,
+        skip_tracks_with_plain_lyrics,
+        try_embed_lyrics,
+        theme_mode,
+        lrclib_instance,
+        watch_library,
+        retry_not_found_days
+      FROM config_data
+      LIMIT 1
+    "})?;
+    let row = statement.query_row([], |r| {
+        Ok(PersistentConfig {
+            skip_tracks_with_synced_lyrics: r.get("skip_tracks_with_synced_lyrics")?,
+            skip_tracks_with_plain_lyrics: r.get("skip_tracks_with_plain_lyrics")?,
+            try_embed_lyrics: r.get("try_embed_lyrics")?,
+            theme_mode: r.get("theme_mode")?,
+            lrclib_instance: r.get("lrclib_instance")?,
+            watch_library: r.get("watch_library")?,
+            retry_not_found_days: r.get("retry_not_found_days")?,
+        })
+    })?;
+    Ok(row)
+}
+
+pub fn set_config(
+    skip_tracks_with_synced_lyrics: bool,
+    skip_tracks_with_plain_lyrics: bool,
+    try_embed_lyrics: bool,
+    theme_mode: &str,
+    lrclib_instance: &str,
+    retry_not_found_days: u32,
+    db: &Connection,
+) -> Result<()> {
+    let mut statement = db.prepare(indoc! {"
+      UPDATE config_data
+      SET
+        skip_tracks_with_synced_lyrics = ?,
+        skip_tracks_with_plain_lyrics = ?,
+        try_embed_lyrics = ?,
+        theme_mode = ?,
+        lrclib_instance = ?,
+        retry_not_found_days = ?
+      WHERE 1
+    "})?;
+    statement.execute((
+        skip_tracks_with_synced_lyrics,
+        skip_tracks_with_plain_lyrics,
+        try_embed_lyrics,
+        theme_mode,
+        lrclib_instance,
+        retry_not_found_days,
+    ))?;
+    Ok(())
+}
+
+pub fn set_watch_library(watch_library: bool, db: &Connection) -> Result<()> {
+    let mut statement = db.prepare("UPDATE config_data SET watch_library = ? WHERE 1")?;
+    statement.execute([watch_library])?;
+    Ok(())
+}
+
+pub fn find_artist(name: &str, db: &Connection) -> Result<i64> {
+    let mut statement = db.prepare("SELECT id FROM artists WHERE name = ?")?;
+    let id: i64 = statement.query_row([name], |r| r.get(0))?;
+    Ok(id)
+}
+
+pub fn add_artist(name: &str, db: &Connection) -> Result<i64> {
+    let mut statement = db.prepare("INSERT INTO artists (name, name_lower) VALUES (?, ?)")?;
+    let row_id = statement.insert((name, prepare_input(name)))?;
+    Ok(row_id)
+}
+
+pub fn find_album(name: &str, album_artist_name: &str, db: &Connection) -> Result<i64> {
+    let mut statement =
+        db.prepare("SELECT id FROM albums WHERE name = ? AND album_artist_name = ?")?;
+    let id: i64 = statement.query_row((name, album_artist_name), |r| r.get(0))?;
+    Ok(id)
+}
+
+pub fn add_album(name: &str, album_artist_name: &str, db: &Connection) -> Result<i64> {
+    let mut statement = db.prepare("INSERT INTO albums (name, name_lower, album_artist_name, album_artist_name_lower) VALUES (?, ?, ?, ?)")?;
+    let row_id = statement.insert((
+        name,
+        prepare_input(name),
+        album_artist_name,
+        prepare_input(album_artist_name),
+    ))?;
+    Ok(row_id)
+}
+
+const TRACK_QUERY: &str = indoc! {"
+    SELECT
+      tracks.id,
+      file_path,
+      file_name,
+      title,
+      artists.name AS artist_name,
+      tracks.artist_id,
+      albums.name AS album_name,
+      albums.album_artist_name,
+      album_id,
+      duration,
+      cue_track_number,
+      cue_start,
+      cue_end,
+      track_number,
+      track_total,
+      disc_number,
+      disc_total,
+      year,
+      genre,
+      isrc,
+      musicbrainz_recording_id,
+      musicbrainz_release_id,
+      codec,
+      bitrate,
+      sample_rate,
+      channels,
+      file_size,
+      albums.image_path,
+      txt_lyrics,
+      lrc_lyrics,
+      lyrics_source,
+      instrumental,
+      inferred_fields
+    FROM tracks
+    JOIN albums ON tracks.album_id = albums.id
+    JOIN artists ON tracks.artist_id = artists.id
+"};
+
+fn track_from_row(row: &Row) -> rusqlite::Result<PersistentTrack> {
+    let is_instrumental: Option<bool> = row.get("instrumental")?;
+    let inferred_fields: Option<String> = row.get("inferred_fields")?;
+
+    Ok(PersistentTrack {
+        id: row.get("id")?,
+        file_path: row.get("file_path")?,
+        file_name: row.get("file_name")?,
+        title: row.get("title")?,
+        artist_name: row.get("artist_name")?,
+        artist_id: row.get("artist_id")?,
+        album_name: row.get("album_name")?,
+        album_artist_name: row.get("album_artist_name")?,
+        album_id: row.get("album_id")?,
+        duration: row.get("duration")?,
+        cue_track_number: row.get("cue_track_number")?,
+        cue_start: row.get("cue_start")?,
+        cue_end: row.get("cue_end")?,
+        track_number: row.get("track_number")?,
+        track_total: row.get("track_total")?,
+        disc_number: row.get("disc_number")?,
+        disc_total: row.get("disc_total")?,
+        year: row.get("year")?,
+        genre: row.get("genre")?,
+        isrc: row.get("isrc")?,
+        musicbrainz_recording_id: row.get("musicbrainz_recording_id")?,
+        musicbrainz_release_id: row.get("musicbrainz_release_id")?,
+        codec: row.get("codec")?,
+        bitrate: row.get("bitrate")?,
+        sample_rate: row.get("sample_rate")?,
+        channels: row.get("channels")?,
+        file_size: row.get("file_size")?,
+        txt_lyrics: row.get("txt_lyrics")?,
+        lrc_lyrics: row.get("lrc_lyrics")?,
+        lyrics_source: row.get("lyrics_source")?,
+        image_path: row.get("image_path")?,
+        instrumental: is_instrumental.unwrap_or(false),
+        inferred_fields: split_list(inferred_fields, ','),
+    })
+}
+
+fn collect_tracks(mut rows: Rows) -> Result<Vec<PersistentTrack>> {
+    let mut tracks: Vec<PersistentTrack> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        tracks.push(track_from_row(row)?);
+    }
+
+    Ok(tracks)
+}
+
+pub fn get_track_by_id(id: i64, db: &Connection) -> Result<PersistentTrack> {
+    let query = format!("{}WHERE tracks.id = ?\nLIMIT 1", TRACK_QUERY);
+
+    let mut statement = db.prepare(&query)?;
+    let row = statement.query_row([id], track_from_row)?;
+    Ok(row)
+}
+
+/// Where new lyrics of a track come from.
+#[derive(Clone, Copy, Debug)]
+pub enum LyricsChangeSource {
+    /// Edited in the app.
+    Manual,
+    /// Downloaded from LRCLIB, with the id of the LRCLIB record when it is known.
+    Lrclib(Option<i64>),
+    /// Read from changed sidecars or tags by a scan.
+    Import,
+    /// Restored from an earlier revision.
+    Restore,
+}
+
+impl LyricsChangeSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LyricsChangeSource::Manual => "manual",
+            LyricsChangeSource::Lrclib(_) => "lrclib",
+            LyricsChangeSource::Import => "import",
+            LyricsChangeSource::Restore => "restore",
+        }
+    }
+
+    fn lrclib_id(&self) -> Option<i64> {
+        match self {
+            LyricsChangeSource::Lrclib(lrclib_id) => *lrclib_id,
+            _ => None,
+        }
+    }
+}
+
+/// A change of lyrics, which keeps the lyrics it replaces as a revision of the track.
+#[derive(Clone, Debug)]
+pub struct LyricsChange {
+    pub source: LyricsChangeSource,
+    /// Groups the revisions of one operation, such as a download run or a scan.
+    pub batch_id: String,
+}
+
+impl LyricsChange {
+    /// Without a batch id, the change makes up a batch of its own.
+    pub fn new(source: LyricsChangeSource, batch_id: Option<String>) -> Result<LyricsChange> {
+        let batch_id = match batch_id {
+            Some(batch_id) => batch_id,
+            None => new_lyrics_batch_id()?,
+        };
+
+        Ok(LyricsChange { source, batch_id })
+    }
+}
+
+/// Batch ids only need to tell the operations of one library apart, which never start in
+/// the same nanosecond.
+pub fn new_lyrics_batch_id() -> Result<String> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    Ok(format!("{:x}", nanos))
+}
+
+/// Keeps the current lyrics of the track as a revision before they are replaced. Nothing is
+/// recorded when the lyrics stay the same. Plain lyrics only count when there are no synced
+/// lyrics, as they are then derived from the synced ones and aren't kept in a sidecar.
+fn add_lyrics_revision(
+    track_id: i64,
+    txt_lyrics: Option<&str>,
+    lrc_lyrics: Option<&str>,
+    change: &LyricsChange,
+    db: &Connection,
+) -> Result<()> {
+    let mut statement = db.prepare(indoc! {"
+      INSERT INTO lyrics_revisions (
+        track_id,
+        txt_lyrics,
+        lrc_lyrics,
+        instrumental,
+        source,
+        lrclib_id,
+        batch_id,
+        created_at
+      )
+      SELECT
+        id,
+        txt_lyrics,
+        lrc_lyrics,
+        instrumental,
+        :source,
+        :lrclib_id,
+        :batch_id,
+        CAST(strftime('%s', 'now') AS INTEGER)
+      FROM tracks
+      WHERE id = :track_id
+        AND (
+          lrc_lyrics IS NOT :lrc_lyrics
+          OR (lrc_lyrics IS NULL AND txt_lyrics IS NOT :txt_lyrics)
+        )
+    "})?;
+    statement.execute(named_params! {
+        ":source": change.source.name(),
+        ":lrclib_id": change.source.lrclib_id(),
+        ":batch_id": change.batch_id,
+        ":track_id": track_id,
+        ":txt_lyrics": txt_lyrics,
+        ":lrc_lyrics": lrc_lyrics,
+    })?;
+
+    Ok(())
+}
+
+/// Lists the revisions of the track, newest first.
+pub fn get_lyrics_revisions(
+    track_id: i64,
+    db: &Connection,
+) -> Result<Vec<PersistentLyricsRevision>> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT * FROM lyrics_revisions
+      WHERE track_id = ?
+      ORDER BY id DESC
+    "})?;
+    let mut rows = statement.query([track_id])?;
+    let mut revisions: Vec<PersistentLyricsRevision> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        revisions.push(lyrics_revision_from_row(row)?);
+    }
+
+    Ok(revisions)
+}
+
+pub fn get_lyrics_revision_by_id(id: i64, db: &Connection) -> Result<PersistentLyricsRevision> {
+    let mut statement = db.prepare("SELECT * FROM lyrics_revisions WHERE id = ?")?;
+    let row = statement.query_row([id], lyrics_revision_from_row)?;
+
+    Ok(row)
+}
+
+fn lyrics_revision_from_row(row: &Row) -> rusqlite::Result<PersistentLyricsRevision> {
+    Ok(PersistentLyricsRevision {
+        id: row.get("id")?,
+        track_id: row.get("track_id")?,
+        txt_lyrics: row.get("txt_lyrics")?,
+        lrc_lyrics: row.get("lrc_lyrics")?,
+        instrumental: row.get("instrumental")?,
+        source: row.get("source")?,
+        lrclib_id: row.get("lrclib_id")?,
+        batch_id: row.get("batch_id")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+/// Logs a lookup of the track's lyrics on an LRCLIB instance.
+pub fn add_download_attempt(
+    track_id: i64,
+    outcome: &str,
+    lrclib_instance: &str,
+    db: &Connection,
+) -> Result<()> {
+    let mut statement = db.prepare(indoc! {"
+      INSERT INTO download_attempts (track_id, outcome, lrclib_instance, attempted_at)
+      VALUES (?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER))
+    "})?;
+    statement.execute((track_id, outcome, lrclib_instance))?;
+
+    Ok(())
+}
+
+pub fn update_track_synced_lyrics(
+    id: i64,
+    synced_lyrics: &str,
+    plain_lyrics: &str,
+    change: &LyricsChange,
+    db: &Connection,
+) -> Result<PersistentTrack> {
+    add_lyrics_revision(id, Some(plain_lyrics), Some(synced_lyrics), change, db)?;
+    let mut statement = db.prepare(indoc! {"
+      UPDATE tracks
+      SET lrc_lyrics = ?, txt_lyrics = ?, lyrics_source = 'sidecar', instrumental = false
+      WHERE id = ?
+    "})?;
+    statement.execute((synced_lyrics, plain_lyrics, id))?;
+
+    Ok(get_track_by_id(id, db)?)
+}
+
+pub fn update_track_plain_lyrics(
+    id: i64,
+    plain_lyrics: &str,
+    change: &LyricsChange,
+    db: &Connection,
+) -> Result<PersistentTrack> {
+    add_lyrics_revision(id, Some(plain_lyrics), None, change, db)?;
+    let mut statement = db.prepare(indoc! {"
+      UPDATE tracks
+      SET txt_lyrics = ?, lrc_lyrics = null, lyrics_source = 'sidecar', instrumental = false
+      WHERE id = ?
+    "})?;
+    statement.execute((plain_lyrics, id))?;
+
+    Ok(get_track_by_id(id, db)?)
+}
+
+pub fn update_track_null_lyrics(
+    id: i64,
+    change: &LyricsChange,
+    db: &Connection,
+) -> Result<PersistentTrack> {
+    add_lyrics_revision(id, None, None, change, db)?;
+    let mut statement = db.prepare(indoc! {"
+      UPDATE tracks
+      SET txt_lyrics = null, lrc_lyrics = null, lyrics_source = null, instrumental = false
+      WHERE id = ?
+    "})?;
+    statement.execute([id])?;
+
+    Ok(get_track_by_id(id, db)?)
+}
+
+pub fn update_track_instrumental(
+    id: i64,
+    change: &LyricsChange,
+    db: &Connection,
+) -> Result<PersistentTrack> {
+    add_lyrics_revision(id, None, Some("[au: instrumental]"), change, db)?;
+    let mut statement = db.prepare(indoc! {"
+      UPDATE tracks
+      SET txt_lyrics = null, lrc_lyrics = ?, lyrics_source = 'sidecar', instrumental = true
+      WHERE id = ?
+    "})?;
+    statement.execute(params!["[au: instrumental]", id])?;
+
+    Ok(get_track_by_id(id, db)?)
+}
+
+pub fn add_tracks(tracks: &Vec<fs_track::FsTrack>, db: &mut Connection) -> Result<()> {
+    let tx = db.transaction()?;
+
+    for track in tracks.iter() {
+        add_track(track, &tx)?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+pub fn add_track(track: &fs_track::FsTrack, db: &Connection) -> Result<i64> {
+    let (artist_id, album_id) = find_or_add_artist_and_album(track, db)?;
+    let is_instrumental = is_instrumental_lyrics(track);
+
+    let query = indoc! {"
+    INSERT INTO tracks (
+        file_path,
+        file_name,
+        title,
+        title_lower,
+        album_id,
+        artist_id,
+        duration,
+        track_number,
+        track_total,
+        disc_number,
+        disc_total,
+        year,
+        genre,
+        isrc,
+        musicbrainz_recording_id,
+        musicbrainz_release_id,
+        codec,
+        bitrate,
+        sample_rate,
+        channels,
+        txt_lyrics,
         lrc_lyrics,
-        instrumental
-    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        lyrics_source,
+        instrumental,
+        file_mtime,
+        file_size,
+        content_hash,
+        cue_track_number,
+        cue_start,
+        cue_end,
+        inferred_fields,
+        added_at
+    ) VALUES (
+        ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+        ?, CAST(strftime('%s', 'now') AS INTEGER)
+    )
   "};
     let mut statement = db.prepare(query)?;
-    statement.execute((
+    let row_id = statement.insert(params![
         track.file_path(),
         track.file_name(),
         track.title(),
@@ -460,72 +1660,674 @@ pub fn add_track(track: &fs_track::FsTrack, db: &Connection) -> Result<()> {
         artist_id,
         track.duration(),
         track.track_number(),
+        track.track_total(),
+        track.disc_number(),
+        track.disc_total(),
+        track.year(),
+        track.genre(),
+        track.isrc(),
+        track.musicbrainz_recording_id(),
+        track.musicbrainz_release_id(),
+        track.codec(),
+        track.bitrate(),
+        track.sample_rate(),
+        track.channels(),
         track.txt_lyrics(),
         track.lrc_lyrics(),
+        track.lyrics_source().map(|source| source.name()),
         is_instrumental,
-    ))?;
+        track.file_mtime(),
+        track.file_size(),
+        track.content_hash(),
+        track.cue_track_number(),
+        track.cue_start(),
+        track.cue_end(),
+        join_list(&track.inferred_fields(), ","),
+    ])?;
+    set_track_artists(row_id, track, db)?;
 
-    Ok(())
+    Ok(row_id)
 }
 
-pub fn get_tracks(db: &Connection) -> Result<Vec<PersistentTrack>> {
+/// Re-reads the scanned metadata into an existing track row, keeping its id.
+pub fn update_track(
+    id: i64,
+    track: &fs_track::FsTrack,
+    batch_id: &str,
+    db: &Connection,
+) -> Result<()> {
+    let change = LyricsChange {
+        source: LyricsChangeSource::Import,
+        batch_id: batch_id.to_owned(),
+    };
+    add_lyrics_revision(
+        id,
+        track.txt_lyrics().as_deref(),
+        track.lrc_lyrics().as_deref(),
+        &change,
+        db,
+    )?;
+    let (artist_id, album_id) = find_or_add_artist_and_album(track, db)?;
+    let is_instrumental = is_instrumental_lyrics(track);
+
     let query = indoc! {"
-      SELECT
-          tracks.id, file_path, file_name, title,
-          artists.name AS artist_name, tracks.artist_id,
-          albums.name AS album_name, albums.album_artist_name, album_id, duration, track_number,
-          albums.image_path, txt_lyrics, lrc_lyrics, instrumental
-      FROM tracks
-      JOIN albums ON tracks.album_id = albums.id
-      JOIN artists ON tracks.artist_id = artists.id
-      ORDER BY title_lower ASC
+    UPDATE tracks SET
+        file_path = ?,
+        file_name = ?,
+        title = ?,
+        title_lower = ?,
+        album_id = ?,
+        artist_id = ?,
+        duration = ?,
+        track_number = ?,
+        track_total = ?,
+        disc_number = ?,
+        disc_total = ?,
+        year = ?,
+        genre = ?,
+        isrc = ?,
+        musicbrainz_recording_id = ?,
+        musicbrainz_release_id = ?,
+        codec = ?,
+        bitrate = ?,
+        sample_rate = ?,
+        channels = ?,
+        txt_lyrics = ?,
+        lrc_lyrics = ?,
+        lyrics_source = ?,
+        instrumental = ?,
+        file_mtime = ?,
+        file_size = ?,
+        content_hash = ?,
+        cue_track_number = ?,
+        cue_start = ?,
+        cue_end = ?,
+        inferred_fields = ?
+    WHERE id = ?
   "};
     let mut statement = db.prepare(query)?;
+    statement.execute(params![
+        track.file_path(),
+        track.file_name(),
+        track.title(),
+        prepare_input(&track.title()),
+        album_id,
+        artist_id,
+        track.duration(),
+        track.track_number(),
+        track.track_total(),
+        track.disc_number(),
+        track.disc_total(),
+        track.year(),
+        track.genre(),
+        track.isrc(),
+        track.musicbrainz_recording_id(),
+        track.musicbrainz_release_id(),
+        track.codec(),
+        track.bitrate(),
+        track.sample_rate(),
+        track.channels(),
+        track.txt_lyrics(),
+        track.lrc_lyrics(),
+        track.lyrics_source().map(|source| source.name()),
+        is_instrumental,
+        track.file_mtime(),
+        track.file_size(),
+        track.content_hash(),
+        track.cue_track_number(),
+        track.cue_start(),
+        track.cue_end(),
+        join_list(&track.inferred_fields(), ","),
+        id,
+    ])?;
+    set_track_artists(id, track, db)?;
+
+    // The artwork may have changed along with the other tags
+    db.execute(
+        "UPDATE albums SET artwork_checked = 0 WHERE id = ?",
+        [album_id],
+    )?;
+
+    Ok(())
+}
+
+fn find_or_add_artist_and_album(track: &fs_track::FsTrack, db: &Connection) -> Result<(i64, i64)> {
+    let artist_result = find_artist(&track.artist(), db);
+    let artist_id = match artist_result {
+        Ok(artist_id) => artist_id,
+        Err(_) => add_artist(&track.artist(), db)?,
+    };
+
+    let album_result = find_album(&track.album(), &track.album_artist(), db);
+    let album_id = match album_result {
+        Ok(album_id) => album_id,
+        Err(_) => add_album(&track.album(), &track.album_artist(), db)?,
+    };
+
+    Ok((artist_id, album_id))
+}
+
+/// Links the track to each of its individual artists. The artist string of the tag itself
+/// stays on `tracks.artist_id`, since that's what LRCLIB knows the track by.
+fn set_track_artists(track_id: i64, track: &fs_track::FsTrack, db: &Connection) -> Result<()> {
+    db.execute("DELETE FROM track_artists WHERE track_id = ?", [track_id])?;
+    let mut statement = db.prepare(
+        "INSERT OR IGNORE INTO track_artists (track_id, artist_id, position) VALUES (?, ?, ?)",
+    )?;
+
+    for (position, name) in track.artists().iter().enumerate() {
+        let artist_id = match find_artist(name, db) {
+            Ok(artist_id) => artist_id,
+            Err(_) => add_artist(name, db)?,
+        };
+        statement.execute((track_id, artist_id, position))?;
+    }
+
+    Ok(())
+}
+
+fn is_instrumental_lyrics(track: &fs_track::FsTrack) -> bool {
+    // Create a regex to match "[au: instrumental]" or "[au:instrumental]"
+    let re = Regex::new(r"\[au:\s*instrumental\]").expect("Invalid regex");
+    track
+        .lrc_lyrics()
+        .as_ref()
+        .map_or(false, |lyrics| re.is_match(lyrics))
+}
+
+/// Applies one batch of a library refresh: tracks paired with an existing id are updated in
+/// place, the others are inserted. Returns the ids of the saved tracks.
+pub fn upsert_tracks(
+    tracks: &[(Option<i64>, fs_track::FsTrack)],
+    batch_id: &str,
+    db: &mut Connection,
+) -> Result<Vec<i64>> {
+    let tx = db.transaction()?;
+    let mut ids: Vec<i64> = vec![];
+
+    for (existing_id, track) in tracks.iter() {
+        ids.push(save_scanned_track(*existing_id, track, batch_id, &tx)?);
+    }
+
+    tx.commit()?;
+
+    Ok(ids)
+}
+
+/// Updates the track row with the given id, or inserts a new row if there is none.
+/// Changed lyrics of an updated track are recorded under the batch id. Returns the id of the
+/// saved track.
+pub fn save_scanned_track(
+    existing_id: Option<i64>,
+    track: &fs_track::FsTrack,
+    batch_id: &str,
+    db: &Connection,
+) -> Result<i64> {
+    delete_scan_failure(&track.file_path(), db)?;
+
+    match existing_id {
+        Some(id) => {
+            update_track(id, track, batch_id, db)?;
+            Ok(id)
+        }
+        None => add_track(track, db),
+    }
+}
+
+/// Returns the id and path of every track whose file path starts with the given prefix.
+pub fn get_tracks_by_file_path_prefix(prefix: &str, db: &Connection) -> Result<Vec<(i64, String)>> {
+    let mut statement =
+        db.prepare("SELECT id, file_path FROM tracks WHERE instr(file_path, ?) = 1")?;
+    let mut rows = statement.query([prefix])?;
+    let mut tracks: Vec<(i64, String)> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        tracks.push((row.get("id")?, row.get("file_path")?));
+    }
+
+    Ok(tracks)
+}
+
+/// Returns the id and path of every track whose audio has the given content hash.
+pub fn get_tracks_by_content_hash(
+    content_hash: &str,
+    db: &Connection,
+) -> Result<Vec<(i64, String)>> {
+    let mut statement = db.prepare(
+        "SELECT id, file_path FROM tracks WHERE content_hash = ? AND cue_track_number IS NULL",
+    )?;
+    let mut rows = statement.query([content_hash])?;
+    let mut tracks: Vec<(i64, String)> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        tracks.push((row.get("id")?, row.get("file_path")?));
+    }
+
+    Ok(tracks)
+}
+
+pub struct TrackFileState {
+    pub id: i64,
+    pub cue_track_number: Option<u32>,
+    pub file_mtime: Option<i64>,
+    pub file_size: Option<i64>,
+    pub content_hash: Option<String>,
+}
+
+/// Returns the scan state of every track, grouped by file path. A file holds several tracks
+/// when it's split by a CUE sheet.
+pub fn get_track_file_states(db: &Connection) -> Result<HashMap<String, Vec<TrackFileState>>> {
+    let mut statement = db.prepare(
+        "SELECT id, file_path, cue_track_number, file_mtime, file_size, content_hash FROM tracks",
+    )?;
     let mut rows = statement.query([])?;
-    let mut tracks: Vec<PersistentTrack> = Vec::new();
+    let mut track_file_states: HashMap<String, Vec<TrackFileState>> = HashMap::new();
 
     while let Some(row) = rows.next()? {
-        let is_instrumental: Option<bool> = row.get("instrumental")?;
+        track_file_states
+            .entry(row.get("file_path")?)
+            .or_default()
+            .push(TrackFileState {
+                id: row.get("id")?,
+                cue_track_number: row.get("cue_track_number")?,
+                file_mtime: row.get("file_mtime")?,
+                file_size: row.get("file_size")?,
+                content_hash: row.get("content_hash")?,
+            });
+    }
+
+    Ok(track_file_states)
+}
+
+/// Returns the id and CUE sheet track number of every track read from the file.
+pub fn get_tracks_by_file_path(
+    file_path: &str,
+    db: &Connection,
+) -> Result<Vec<(i64, Option<u32>)>> {
+    let mut statement =
+        db.prepare("SELECT id, cue_track_number FROM tracks WHERE file_path = ?")?;
+    let mut rows = statement.query([file_path])?;
+    let mut tracks: Vec<(i64, Option<u32>)> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        tracks.push((row.get("id")?, row.get("cue_track_number")?));
+    }
+
+    Ok(tracks)
+}
+
+/// Detaches lyrics revisions from their track, keeping the path, CUE track number and content
+/// hash of its file. The `lyrics_revisions_reattach` trigger gives them to the next track that
+/// is added for the same file, and `REATTACH_LYRICS_REVISIONS` to one already in the library.
+const DETACH_LYRICS_REVISIONS: &str = indoc! {"
+    UPDATE lyrics_revisions
+    SET track_id = NULL,
+      file_path = (SELECT file_path FROM tracks WHERE tracks.id = lyrics_revisions.track_id),
+      cue_track_number = (
+        SELECT cue_track_number FROM tracks WHERE tracks.id = lyrics_revisions.track_id
+      ),
+      content_hash = (SELECT content_hash FROM tracks WHERE tracks.id = lyrics_revisions.track_id)
+"};
+
+/// Gives detached lyrics revisions to the track of their file, if it is in the library.
+const REATTACH_LYRICS_REVISIONS: &str = indoc! {"
+    UPDATE lyrics_revisions
+    SET track_id = tracks.id, file_path = NULL, cue_track_number = NULL, content_hash = NULL
+    FROM tracks
+    WHERE lyrics_revisions.track_id IS NULL
+      AND (
+        (
+          tracks.file_path = lyrics_revisions.file_path
+          AND tracks.cue_track_number IS lyrics_revisions.cue_track_number
+        )
+        OR tracks.content_hash = lyrics_revisions.content_hash
+      )
+"};
+
+/// Deletes the tracks. Their lyrics revisions are kept, detached, in case the files come back.
+/// A refresh may already have added the track that replaces a deleted one, e.g. when a CUE
+/// sheet changed, so the revisions go to that track right away.
+pub fn delete_tracks(ids: &[i64], db: &mut Connection) -> Result<()> {
+    let tx = db.transaction()?;
+
+    {
+        let mut statement = tx.prepare("DELETE FROM tracks WHERE id = ?")?;
+        let mut artists_statement = tx.prepare("DELETE FROM track_artists WHERE track_id = ?")?;
+        let mut revisions_statement =
+            tx.prepare(&format!("{} WHERE track_id = ?", DETACH_LYRICS_REVISIONS))?;
+        let mut attempts_statement =
+            tx.prepare("DELETE FROM download_attempts WHERE track_id = ?")?;
+        for id in ids.iter() {
+            artists_statement.execute([id])?;
+            revisions_statement.execute([id])?;
+            attempts_statement.execute([id])?;
+            statement.execute([id])?;
+        }
+    }
+    tx.execute(REATTACH_LYRICS_REVISIONS, ())?;
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Removes albums and artists that no longer have any track pointing at them. Returns the
+/// artwork thumbnails of the removed albums, which are left for the caller to delete.
+pub fn clean_orphan_albums_and_artists(db: &Connection) -> Result<Vec<String>> {
+    let image_paths =
+        get_album_image_paths("WHERE id NOT IN (SELECT DISTINCT album_id FROM tracks)", db)?;
+    db.execute(
+        "DELETE FROM albums WHERE id NOT IN (SELECT DISTINCT album_id FROM tracks)",
+        (),
+    )?;
+    db.execute(
+        indoc! {"
+        DELETE FROM artists
+        WHERE id NOT IN (SELECT DISTINCT artist_id FROM tracks)
+          AND id NOT IN (SELECT DISTINCT artist_id FROM track_artists)
+        "},
+        (),
+    )?;
+    Ok(image_paths)
+}
+
+fn get_album_image_paths(condition: &str, db: &Connection) -> Result<Vec<String>> {
+    let query = format!(
+        "SELECT image_path FROM albums {} AND image_path IS NOT NULL",
+        condition
+    );
+    let mut statement = db.prepare(&query)?;
+    let image_paths = statement
+        .query_map([], |row| row.get("image_path"))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(image_paths)
+}
+
+pub fn get_tracks(list_options: &ListOptions, db: &Connection) -> Result<Vec<PersistentTrack>> {
+    let query = format!(
+        "{}WHERE 1 = 1{}",
+        TRACK_QUERY,
+        list_options.to_sql(ListKind::Tracks, "tracks.title_lower ASC")
+    );
+    let mut statement = db.prepare(&query)?;
+    let rows = statement.query([])?;
+
+    collect_tracks(rows)
+}
+
+/// Metadata filters of the track list commands. Unset fields don't filter anything; text
+/// fields match case-insensitively.
+#[derive(Deserialize, Default, Debug)]
+pub struct TrackFilters {
+    pub year_from: Option<u32>,
+    pub year_to: Option<u32>,
+    pub genre: Option<String>,
+    pub codec: Option<String>,
+    pub min_bitrate: Option<u32>,
+    pub min_sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    pub isrc: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+    /// Skips the tracks that LRCLIB recently had no lyrics for, as the bulk downloads do.
+    pub skip_not_found: Option<bool>,
+}
+
+/// Ids of the tracks to leave out of bulk downloads for now. After `n` lookups in a row on
+/// the configured LRCLIB instance found nothing, the track is skipped for the configured
+/// number of days times `2^(n - 1)`, at most 32 times as long. A window of 0 days skips
+/// nothing.
+const RECENTLY_NOT_FOUND_TRACK_IDS: &str = indoc! {"
+  SELECT misses.track_id
+  FROM download_attempts AS misses
+  WHERE misses.lrclib_instance = (SELECT lrclib_instance FROM config_data LIMIT 1)
+    AND misses.outcome = 'not_found'
+    AND misses.id > COALESCE((
+      SELECT MAX(found.id) FROM download_attempts AS found
+      WHERE found.track_id = misses.track_id
+        AND found.lrclib_instance = misses.lrclib_instance
+        AND found.outcome = 'found'
+    ), 0)
+  GROUP BY misses.track_id
+  HAVING MAX(misses.attempted_at)
+    + (SELECT retry_not_found_days FROM config_data LIMIT 1) * 86400 * (1 << MIN(COUNT(*) - 1, 5))
+    > CAST(strftime('%s', 'now') AS INTEGER)"};
+
+impl TrackFilters {
+    /// Builds the ` AND ...` conditions of the filters along with their parameters.
+    fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut conditions = String::new();
+        let mut values: Vec<Value> = vec![];
+        let mut add = |condition: &str, value: Value| {
+            conditions.push_str(condition);
+            values.push(value);
+        };
+
+        if let Some(year_from) = self.year_from {
+            add(" AND tracks.year >= ?", Value::from(year_from));
+        }
+        if let Some(year_to) = self.year_to {
+            add(" AND tracks.year <= ?", Value::from(year_to));
+        }
+        if let Some(genre) = &self.genre {
+            add(
+                " AND tracks.genre = ? COLLATE NOCASE",
+                Value::from(genre.to_owned()),
+            );
+        }
+        if let Some(codec) = &self.codec {
+            add(
+                " AND tracks.codec = ? COLLATE NOCASE",
+                Value::from(codec.to_owned()),
+            );
+        }
+        if let Some(min_bitrate) = self.min_bitrate {
+            add(" AND tracks.bitrate >= ?", Value::from(min_bitrate));
+        }
+        if let Some(min_sample_rate) = self.min_sample_rate {
+            add(" AND tracks.sample_rate >= ?", Value::from(min_sample_rate));
+        }
+        if let Some(channels) = self.channels {
+            add(" AND tracks.channels = ?", Value::from(channels));
+        }
+        if let Some(isrc) = &self.isrc {
+            add(
+                " AND tracks.isrc = ? COLLATE NOCASE",
+                Value::from(isrc.to_owned()),
+            );
+        }
+        if let Some(recording_id) = &self.musicbrainz_recording_id {
+            add(
+                " AND tracks.musicbrainz_recording_id = ? COLLATE NOCASE",
+                Value::from(recording_id.to_owned()),
+            );
+        }
+        if let Some(release_id) = &self.musicbrainz_release_id {
+            add(
+                " AND tracks.musicbrainz_release_id = ? COLLATE NOCASE",
+                Value::from(release_id.to_owned()),
+            );
+        }
+        if self.skip_not_found == Some(true) {
+            conditions.push_str(&format!(
+                " AND tracks.id NOT IN ({})",
+                RECENTLY_NOT_FOUND_TRACK_IDS
+            ));
+        }
+
+        (conditions, values)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    Title,
+    Artist,
+    Album,
+    Duration,
+    DateAdded,
+    LyricsStatus,
+    FilePath,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Sorting and paging of the list commands. Without `sort_by`, every list keeps its usual
+/// order, which otherwise breaks the ties of the sort key.
+#[derive(Deserialize, Default, Debug)]
+pub struct ListOptions {
+    pub sort_by: Option<SortKey>,
+    pub sort_direction: Option<SortDirection>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// The kind of rows a list command returns, which decides what the sort keys sort on.
+#[derive(Clone, Copy)]
+enum ListKind {
+    Tracks,
+    Albums,
+    Artists,
+}
 
-        let track = PersistentTrack {
-            id: row.get("id")?,
-            file_path: row.get("file_path")?,
-            file_name: row.get("file_name")?,
-            title: row.get("title")?,
-            artist_name: row.get("artist_name")?,
-            artist_id: row.get("artist_id")?,
-            album_name: row.get("album_name")?,
-            album_artist_name: row.get("album_artist_name")?,
-            album_id: row.get("album_id")?,
-            duration: row.get("duration")?,
-            track_number: row.get("track_number")?,
-            txt_lyrics: row.get("txt_lyrics")?,
-            lrc_lyrics: row.get("lrc_lyrics")?,
-            image_path: row.get("image_path")?,
-            instrumental: is_instrumental.unwrap_or(false),
+/// Ranks the lyrics of a track: missing, plain, synced, then instrumental.
+const LYRICS_STATUS_RANK: &str = indoc! {"
+    CASE
+      WHEN tracks.instrumental THEN 3
+      WHEN tracks.lrc_lyrics IS NOT NULL THEN 2
+      WHEN tracks.txt_lyrics IS NOT NULL THEN 1
+      ELSE 0
+    END"};
+
+impl SortKey {
+    /// The expression to order the rows by. Albums and artists are sorted by the total
+    /// duration, earliest addition, average lyrics status and first file path of their
+    /// tracks; artists have no album, so they sort by name for it.
+    fn to_sql(self, kind: ListKind) -> String {
+        let aggregate = |function: &str, expression: &str| match kind {
+            ListKind::Tracks => expression.to_owned(),
+            ListKind::Albums => format!(
+                "(SELECT {}({}) FROM tracks WHERE tracks.album_id = albums.id)",
+                function, expression
+            ),
+            ListKind::Artists => formatdoc! {"
+                (SELECT {}({}) FROM tracks
+                  JOIN track_artists ON track_artists.track_id = tracks.id
+                  WHERE track_artists.artist_id = artists.id)",
+                function, expression
+            },
         };
 
-        tracks.push(track);
+        match (self, kind) {
+            (SortKey::Title | SortKey::Artist | SortKey::Album, ListKind::Artists) => {
+                "artists.name_lower".to_owned()
+            }
+            (SortKey::Title, ListKind::Tracks) => "tracks.title_lower".to_owned(),
+            (SortKey::Title | SortKey::Album, ListKind::Albums) => "albums.name_lower".to_owned(),
+            (SortKey::Artist, ListKind::Tracks) => {
+                "(SELECT name_lower FROM artists WHERE artists.id = tracks.artist_id)".to_owned()
+            }
+            (SortKey::Artist, ListKind::Albums) => "albums.album_artist_name_lower".to_owned(),
+            (SortKey::Album, ListKind::Tracks) => {
+                "(SELECT name_lower FROM albums WHERE albums.id = tracks.album_id)".to_owned()
+            }
+            (SortKey::Duration, _) => aggregate("SUM", "tracks.duration"),
+            (SortKey::DateAdded, _) => aggregate("MIN", "tracks.added_at"),
+            (SortKey::LyricsStatus, _) => aggregate("AVG", LYRICS_STATUS_RANK),
+            (SortKey::FilePath, _) => aggregate("MIN", "tracks.file_path"),
+        }
     }
+}
 
-    Ok(tracks)
+impl ListOptions {
+    /// Builds the ` ORDER BY ...` clause, followed by `LIMIT` and `OFFSET` if the list is
+    /// paged. `default_order` is the usual order of the list.
+    fn to_sql(&self, kind: ListKind, default_order: &str) -> String {
+        let mut clause = match self.sort_by {
+            Some(sort_by) => {
+                let direction = match self.sort_direction.unwrap_or_default() {
+                    SortDirection::Asc => "ASC",
+                    SortDirection::Desc => "DESC",
+                };
+                format!(
+                    " ORDER BY {} {}, {}",
+                    sort_by.to_sql(kind),
+                    direction,
+                    default_order
+                )
+            }
+            None => format!(" ORDER BY {}", default_order),
+        };
+
+        if self.limit.is_some() || self.offset.is_some() {
+            // A negative limit means no limit to SQLite
+            clause.push_str(&format!(
+                " LIMIT {} OFFSET {}",
+                self.limit.map_or(-1, i64::from),
+                self.offset.unwrap_or(0)
+            ));
+        }
+
+        clause
+    }
 }
 
-pub fn get_track_ids(without_plain_lyrics: bool, without_synced_lyrics: bool, db: &Connection) -> Result<Vec<i64>> {
-    let base_query = "SELECT id FROM tracks";
+/// Returns the tracks matching the query and filters. When the query has words to search for,
+/// they are looked up in the titles, artists, albums and album artists through the
+/// `tracks_fts` index, every word matching the start of a word, and the best matches come
+/// first, with title matches weighing the most.
+pub fn get_track_ids(
+    query: &LibraryQuery,
+    without_plain_lyrics: bool,
+    without_synced_lyrics: bool,
+    filters: &TrackFilters,
+    list_options: &ListOptions,
+    db: &Connection,
+) -> Result<Vec<i64>> {
+    let match_query = query.match_query();
+    let base_query = match match_query {
+        Some(_) => indoc! {"
+          SELECT tracks.id
+          FROM tracks_fts
+          JOIN tracks ON tracks.id = tracks_fts.rowid
+          WHERE tracks_fts MATCH ?"},
+        None => "SELECT tracks.id FROM tracks WHERE 1 = 1",
+    };
+    let order_by = match match_query {
+        Some(_) => "bm25(tracks_fts, 10.0, 5.0, 3.0, 1.0) ASC, tracks.title_lower ASC",
+        None => "tracks.title_lower ASC",
+    };
 
     let lyrics_conditions: &str = match (without_plain_lyrics, without_synced_lyrics) {
-        (true, true) => " WHERE txt_lyrics IS NULL AND lrc_lyrics IS NULL AND instrumental = false",
-        (true, false) => " WHERE txt_lyrics IS NULL AND instrumental = false",
-        (false, true) => " WHERE lrc_lyrics IS NULL AND instrumental = false",
+        (true, true) => " AND txt_lyrics IS NULL AND lrc_lyrics IS NULL AND instrumental = false",
+        (true, false) => " AND txt_lyrics IS NULL AND instrumental = false",
+        (false, true) => " AND lrc_lyrics IS NULL AND instrumental = false",
         (false, false) => "",
     };
-
-    let full_query = format!("{}{} ORDER BY title_lower ASC",
-        base_query, lyrics_conditions);
+    let (query_conditions, query_values) = query.to_sql();
+    let (filter_conditions, filter_values) = filters.to_sql();
+
+    let full_query = format!(
+        "{}{}{}{}{}",
+        base_query,
+        lyrics_conditions,
+        query_conditions,
+        filter_conditions,
+        list_options.to_sql(ListKind::Tracks, order_by)
+    );
 
     let mut statement = db.prepare(&full_query)?;
-    let mut rows = statement.query([])?;
+    let mut values: Vec<Value> = match_query.into_iter().map(Value::from).collect();
+    values.extend(query_values);
+    values.extend(filter_values);
+    let mut rows = statement.query(params_from_iter(values))?;
     let mut track_ids: Vec<i64> = Vec::new();
 
     while let Some(row) = rows.next()? {
@@ -535,43 +2337,89 @@ pub fn get_track_ids(without_plain_lyrics: bool, without_synced_lyrics: bool, db
     Ok(track_ids)
 }
 
-pub fn get_search_track_ids(query_str: &String, db: &Connection) -> Result<Vec<i64>> {
-    let query = indoc! {"
-      SELECT tracks.id
-      FROM tracks
+/// Builds the ` AND ...` conditions on `tracks` for the whole query, for listings that keep
+/// their own order instead of ranking the search matches.
+fn library_query_conditions(query: &LibraryQuery) -> (String, Vec<Value>) {
+    let (mut conditions, mut values) = query.to_sql();
+
+    if let Some(match_query) = query.match_query() {
+        conditions.insert_str(
+            0,
+            " AND tracks.id IN (SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?)",
+        );
+        values.insert(0, Value::from(match_query));
+    }
+
+    (conditions, values)
+}
+
+pub struct LyricsSearchCandidate {
+    pub id: i64,
+    pub title: String,
+    pub artist_name: String,
+    pub album_name: String,
+    pub txt_lyrics: Option<String>,
+    pub lrc_lyrics: Option<String>,
+}
+
+/// Goes through the tracks whose lyrics match the FTS5 query, best matches first, and returns
+/// the first `limit` matches that `find_match` confirms. The index only knows which words the
+/// lyrics contain, so the phrase has to be confirmed on the lyrics text. Rows are read one at a
+/// time, so no lyrics are loaded past the last match.
+pub fn find_lyrics_search_matches<T>(
+    match_query: &str,
+    limit: usize,
+    find_match: impl Fn(&LyricsSearchCandidate) -> Option<T>,
+    db: &Connection,
+) -> Result<Vec<T>> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT
+        tracks.id,
+        tracks.title,
+        artists.name AS artist_name,
+        albums.name AS album_name,
+        tracks.txt_lyrics,
+        tracks.lrc_lyrics
+      FROM lyrics_fts
+      JOIN tracks ON tracks.id = lyrics_fts.rowid
       JOIN artists ON tracks.artist_id = artists.id
       JOIN albums ON tracks.album_id = albums.id
-      WHERE artists.name_lower LIKE ?
-      OR albums.name_lower LIKE ?
-      OR tracks.title_lower LIKE ?
-      ORDER BY title_lower ASC
-  "};
-
-    let mut statement = db.prepare(query)?;
-    let formatted_query_str = format!("%{}%", prepare_input(query_str));
-    let mut rows = statement.query(params![
-        formatted_query_str,
-        formatted_query_str,
-        formatted_query_str
-    ])?;
-    let mut track_ids: Vec<i64> = Vec::new();
+      WHERE lyrics_fts MATCH ?
+      ORDER BY lyrics_fts.rank ASC
+  "})?;
+    let mut rows = statement.query([match_query])?;
+    let mut matches: Vec<T> = Vec::new();
 
-    while let Some(row) = rows.next()? {
-        track_ids.push(row.get("id")?);
+    while matches.len() < limit {
+        let Some(row) = rows.next()? else {
+            break;
+        };
+        let candidate = LyricsSearchCandidate {
+            id: row.get("id")?,
+            title: row.get("title")?,
+            artist_name: row.get("artist_name")?,
+            album_name: row.get("album_name")?,
+            txt_lyrics: row.get("txt_lyrics")?,
+            lrc_lyrics: row.get("lrc_lyrics")?,
+        };
+        if let Some(lyrics_match) = find_match(&candidate) {
+            matches.push(lyrics_match);
+        }
     }
 
-    Ok(track_ids)
+    Ok(matches)
 }
 
-pub fn get_albums(db: &Connection) -> Result<Vec<PersistentAlbum>> {
-    let mut statement = db.prepare(indoc! {"
+pub fn get_albums(list_options: &ListOptions, db: &Connection) -> Result<Vec<PersistentAlbum>> {
+    let query = formatdoc! {"
       SELECT albums.id, albums.name, albums.album_artist_name AS album_artist_name, albums.album_artist_name,
-          COUNT(tracks.id) AS tracks_count
+          albums.image_path, COUNT(tracks.id) AS tracks_count
       FROM albums
       JOIN tracks ON tracks.album_id = albums.id
-      GROUP BY albums.id, albums.name, albums.album_artist_name
-      ORDER BY albums.name_lower ASC
-  "})?;
+      GROUP BY albums.id, albums.name, albums.album_artist_name{}",
+        list_options.to_sql(ListKind::Albums, "albums.name_lower ASC")
+    };
+    let mut statement = db.prepare(&query)?;
     let mut rows = statement.query([])?;
     let mut albums: Vec<PersistentAlbum> = Vec::new();
 
@@ -597,6 +2445,7 @@ pub fn get_album_by_id(id: i64, db: &Connection) -> Result<PersistentAlbum> {
       albums.id,
       albums.name,
       albums.album_artist_name,
+      albums.image_path,
       COUNT(tracks.id) AS tracks_count
     FROM albums
     JOIN tracks ON tracks.album_id = albums.id
@@ -611,7 +2460,7 @@ pub fn get_album_by_id(id: i64, db: &Connection) -> Result<PersistentAlbum> {
         Ok(PersistentAlbum {
             id: row.get("id")?,
             name: row.get("name")?,
-            image_path: None,
+            image_path: row.get("image_path")?,
             artist_name: row.get("album_artist_name")?,
             album_artist_name: row.get("album_artist_name")?,
             tracks_count: row.get("tracks_count")?,
@@ -620,9 +2469,68 @@ pub fn get_album_by_id(id: i64, db: &Connection) -> Result<PersistentAlbum> {
     Ok(row)
 }
 
-pub fn get_album_ids(db: &Connection) -> Result<Vec<i64>> {
-    let mut statement = db.prepare("SELECT id FROM albums ORDER BY name_lower ASC")?;
+/// Returns, for every album whose artwork hasn't been looked up yet, its id, the path of one
+/// of its tracks and its current artwork thumbnail.
+pub fn get_albums_without_artwork(db: &Connection) -> Result<Vec<(i64, String, Option<String>)>> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT albums.id, MIN(tracks.file_path) AS file_path, albums.image_path
+      FROM albums
+      JOIN tracks ON tracks.album_id = albums.id
+      WHERE albums.artwork_checked IS NOT 1
+      GROUP BY albums.id
+  "})?;
     let mut rows = statement.query([])?;
+    let mut albums: Vec<(i64, String, Option<String>)> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        albums.push((
+            row.get("id")?,
+            row.get("file_path")?,
+            row.get("image_path")?,
+        ));
+    }
+
+    Ok(albums)
+}
+
+pub fn update_album_artworks(
+    artworks: &[(i64, Option<String>)],
+    db: &mut Connection,
+) -> Result<()> {
+    let tx = db.transaction()?;
+
+    {
+        let mut statement =
+            tx.prepare("UPDATE albums SET image_path = ?, artwork_checked = 1 WHERE id = ?")?;
+        for (album_id, image_path) in artworks.iter() {
+            statement.execute((image_path, album_id))?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Returns the albums with at least one track matching the query, or all of them if the query
+/// is empty.
+pub fn get_album_ids(
+    query: &LibraryQuery,
+    list_options: &ListOptions,
+    db: &Connection,
+) -> Result<Vec<i64>> {
+    let (query_conditions, query_values) = library_query_conditions(query);
+    let order_clause = list_options.to_sql(ListKind::Albums, "albums.name_lower ASC");
+    let full_query = if query.is_empty() {
+        format!("SELECT id FROM albums{}", order_clause)
+    } else {
+        format!(
+            "SELECT id FROM albums WHERE id IN (SELECT tracks.album_id FROM tracks WHERE 1 = 1{}){}",
+            query_conditions, order_clause
+        )
+    };
+    let mut statement = db.prepare(&full_query)?;
+    let mut rows = statement.query(params_from_iter(query_values))?;
     let mut album_ids: Vec<i64> = Vec::new();
 
     while let Some(row) = rows.next()? {
@@ -632,14 +2540,15 @@ pub fn get_album_ids(db: &Connection) -> Result<Vec<i64>> {
     Ok(album_ids)
 }
 
-pub fn get_artists(db: &Connection) -> Result<Vec<PersistentArtist>> {
-    let mut statement = db.prepare(indoc! {"
-    SELECT artists.id, artists.name AS name, COUNT(tracks.id) AS tracks_count
+pub fn get_artists(list_options: &ListOptions, db: &Connection) -> Result<Vec<PersistentArtist>> {
+    let query = formatdoc! {"
+    SELECT artists.id, artists.name AS name, COUNT(track_artists.track_id) AS tracks_count
     FROM artists
-    JOIN tracks ON tracks.artist_id = artists.id
-    GROUP BY artists.id, artists.name
-    ORDER BY artists.name_lower ASC
-  "})?;
+    JOIN track_artists ON track_artists.artist_id = artists.id
+    GROUP BY artists.id, artists.name{}",
+        list_options.to_sql(ListKind::Artists, "artists.name_lower ASC")
+    };
+    let mut statement = db.prepare(&query)?;
     let mut rows = statement.query([])?;
     let mut artists: Vec<PersistentArtist> = Vec::new();
 
@@ -661,9 +2570,9 @@ pub fn get_artist_by_id(id: i64, db: &Connection) -> Result<PersistentArtist> {
     let mut statement = db.prepare(indoc! {"
     SELECT artists.id,
       artists.name AS name,
-      COUNT(tracks.id) AS tracks_count
+      COUNT(track_artists.track_id) AS tracks_count
     FROM artists
-    JOIN tracks ON tracks.artist_id = artists.id
+    JOIN track_artists ON track_artists.artist_id = artists.id
     WHERE artists.id = ?
     GROUP BY artists.id, artists.name
     LIMIT 1
@@ -679,9 +2588,27 @@ pub fn get_artist_by_id(id: i64, db: &Connection) -> Result<PersistentArtist> {
     Ok(row)
 }
 
-pub fn get_artist_ids(db: &Connection) -> Result<Vec<i64>> {
-    let mut statement = db.prepare("SELECT id FROM artists ORDER BY name_lower ASC")?;
-    let mut rows = statement.query([])?;
+/// Returns the artists with at least one track matching the query, or all artists with tracks
+/// if the query is empty.
+pub fn get_artist_ids(
+    query: &LibraryQuery,
+    list_options: &ListOptions,
+    db: &Connection,
+) -> Result<Vec<i64>> {
+    let (query_conditions, query_values) = library_query_conditions(query);
+    let full_query = formatdoc! {"
+        SELECT id FROM artists
+        WHERE id IN (
+          SELECT track_artists.artist_id
+          FROM track_artists
+          JOIN tracks ON tracks.id = track_artists.track_id
+          WHERE 1 = 1{}
+        ){}",
+        query_conditions,
+        list_options.to_sql(ListKind::Artists, "artists.name_lower ASC")
+    };
+    let mut statement = db.prepare(&full_query)?;
+    let mut rows = statement.query(params_from_iter(query_values))?;
     let mut artist_ids: Vec<i64> = Vec::new();
 
     while let Some(row) = rows.next()? {
@@ -691,61 +2618,34 @@ pub fn get_artist_ids(db: &Connection) -> Result<Vec<i64>> {
     Ok(artist_ids)
 }
 
-pub fn get_album_tracks(album_id: i64, db: &Connection) -> Result<Vec<PersistentTrack>> {
-    let mut statement = db.prepare(indoc! {"
-    SELECT
-      tracks.id,
-      file_path,
-      file_name,
-      title,
-      artists.name AS artist_name,
-      tracks.artist_id,
-      albums.name AS album_name,
-      albums.album_artist_name,
-      album_id,
-      duration,
-      track_number,
-      albums.image_path,
-      txt_lyrics,
-      lrc_lyrics,
-      instrumental
-    FROM tracks
-    JOIN albums ON tracks.album_id = albums.id
-    JOIN artists ON tracks.artist_id = artists.id
-    WHERE tracks.album_id = ?
-    ORDER BY track_number ASC
-  "})?;
-    let mut rows = statement.query([album_id])?;
-    let mut tracks: Vec<PersistentTrack> = Vec::new();
-
-    while let Some(row) = rows.next()? {
-        let is_instrumental: Option<bool> = row.get("instrumental")?;
-
-        let track = PersistentTrack {
-            id: row.get("id")?,
-            file_path: row.get("file_path")?,
-            file_name: row.get("file_name")?,
-            title: row.get("title")?,
-            artist_name: row.get("artist_name")?,
-            album_artist_name: row.get("album_artist_name")?,
-            album_name: row.get("album_name")?,
-            album_id: row.get("album_id")?,
-            artist_id: row.get("artist_id")?,
-            duration: row.get("duration")?,
-            track_number: row.get("track_number")?,
-            txt_lyrics: row.get("txt_lyrics")?,
-            lrc_lyrics: row.get("lrc_lyrics")?,
-            image_path: row.get("image_path")?,
-            instrumental: is_instrumental.unwrap_or(false),
-        };
-
-        tracks.push(track);
-    }
-
-    Ok(tracks)
+pub fn get_album_tracks(
+    album_id: i64,
+    list_options: &ListOptions,
+    db: &Connection,
+) -> Result<Vec<PersistentTrack>> {
+    let query = format!(
+        "{}WHERE tracks.album_id = ?{}",
+        TRACK_QUERY,
+        list_options.to_sql(
+            ListKind::Tracks,
+            "tracks.disc_number ASC, tracks.track_number ASC"
+        )
+    );
+    let mut statement = db.prepare(&query)?;
+    let rows = statement.query([album_id])?;
+
+    collect_tracks(rows)
 }
 
-pub fn get_album_track_ids(album_id: i64, without_plain_lyrics: bool, without_synced_lyrics: bool, db: &Connection) -> Result<Vec<i64>> {
+pub fn get_album_track_ids(
+    album_id: i64,
+    query: &LibraryQuery,
+    without_plain_lyrics: bool,
+    without_synced_lyrics: bool,
+    filters: &TrackFilters,
+    list_options: &ListOptions,
+    db: &Connection,
+) -> Result<Vec<i64>> {
     let base_query = indoc! {"
       SELECT tracks.id
       FROM tracks
@@ -759,11 +2659,26 @@ pub fn get_album_track_ids(album_id: i64, without_plain_lyrics: bool, without_sy
         (false, false) => "",
     };
 
-    let full_query = format!("{}{} ORDER BY tracks.track_number ASC",
-        base_query, lyrics_conditions);
+    let (query_conditions, query_values) = library_query_conditions(query);
+    let (filter_conditions, filter_values) = filters.to_sql();
+
+    let full_query = format!(
+        "{}{}{}{}{}",
+        base_query,
+        lyrics_conditions,
+        query_conditions,
+        filter_conditions,
+        list_options.to_sql(
+            ListKind::Tracks,
+            "tracks.disc_number ASC, tracks.track_number ASC"
+        )
+    );
 
     let mut statement = db.prepare(&full_query)?;
-    let mut rows = statement.query([album_id])?;
+    let mut values = vec![Value::from(album_id)];
+    values.extend(query_values);
+    values.extend(filter_values);
+    let mut rows = statement.query(params_from_iter(values))?;
     let mut tracks: Vec<i64> = Vec::new();
 
     while let Some(row) = rows.next()? {
@@ -773,54 +2688,40 @@ pub fn get_album_track_ids(album_id: i64, without_plain_lyrics: bool, without_sy
     Ok(tracks)
 }
 
-pub fn get_artist_tracks(artist_id: i64, db: &Connection) -> Result<Vec<PersistentTrack>> {
-    let mut statement = db.prepare(indoc! {"
-      SELECT tracks.id, file_path, file_name, title, artists.name AS artist_name,
-        tracks.artist_id, albums.name AS album_name, albums.album_artist_name, album_id, duration, track_number,
-        albums.image_path, txt_lyrics, lrc_lyrics, instrumental
-      FROM tracks
-      JOIN albums ON tracks.album_id = albums.id
-      JOIN artists ON tracks.artist_id = artists.id
-      WHERE tracks.artist_id = ?
-      ORDER BY album_name_lower ASC, track_number ASC
-  "})?;
-    let mut rows = statement.query([artist_id])?;
-    let mut tracks: Vec<PersistentTrack> = Vec::new();
-
-    while let Some(row) = rows.next()? {
-        let is_instrumental: Option<bool> = row.get("instrumental")?;
-
-        let track = PersistentTrack {
-            id: row.get("id")?,
-            file_path: row.get("file_path")?,
-            file_name: row.get("file_name")?,
-            title: row.get("title")?,
-            artist_name: row.get("artist_name")?,
-            artist_id: row.get("artist_id")?,
-            album_name: row.get("album_name")?,
-            album_artist_name: row.get("album_artist_name")?,
-            album_id: row.get("album_id")?,
-            duration: row.get("duration")?,
-            track_number: row.get("track_number")?,
-            txt_lyrics: row.get("txt_lyrics")?,
-            lrc_lyrics: row.get("lrc_lyrics")?,
-            image_path: row.get("image_path")?,
-            instrumental: is_instrumental.unwrap_or(false),
-        };
-
-        tracks.push(track);
-    }
-
-    Ok(tracks)
+pub fn get_artist_tracks(
+    artist_id: i64,
+    list_options: &ListOptions,
+    db: &Connection,
+) -> Result<Vec<PersistentTrack>> {
+    let query = format!(
+        "{}WHERE tracks.id IN (SELECT track_id FROM track_artists WHERE artist_id = ?){}",
+        TRACK_QUERY,
+        list_options.to_sql(
+            ListKind::Tracks,
+            "albums.name_lower ASC, tracks.disc_number ASC, tracks.track_number ASC"
+        )
+    );
+    let mut statement = db.prepare(&query)?;
+    let rows = statement.query([artist_id])?;
+
+    collect_tracks(rows)
 }
 
-pub fn get_artist_track_ids(artist_id: i64, without_plain_lyrics: bool, without_synced_lyrics: bool, db: &Connection) -> Result<Vec<i64>> {
+pub fn get_artist_track_ids(
+    artist_id: i64,
+    query: &LibraryQuery,
+    without_plain_lyrics: bool,
+    without_synced_lyrics: bool,
+    filters: &TrackFilters,
+    list_options: &ListOptions,
+    db: &Connection,
+) -> Result<Vec<i64>> {
     let base_query = indoc! {"
       SELECT tracks.id
       FROM tracks
       JOIN albums ON tracks.album_id = albums.id
-      JOIN artists ON tracks.artist_id = artists.id
-      WHERE tracks.artist_id = ?"};
+      JOIN track_artists ON track_artists.track_id = tracks.id
+      WHERE track_artists.artist_id = ?"};
 
     let lyrics_conditions = match (without_plain_lyrics, without_synced_lyrics) {
         (true, true) => " AND txt_lyrics IS NULL AND lrc_lyrics IS NULL AND tracks.instrumental = false",
@@ -829,11 +2730,26 @@ pub fn get_artist_track_ids(artist_id: i64, without_plain_lyrics: bool, without_
         (false, false) => "",
     };
 
-    let full_query = format!("{}{} ORDER BY albums.name_lower ASC, tracks.track_number ASC",
-        base_query, lyrics_conditions);
+    let (query_conditions, query_values) = library_query_conditions(query);
+    let (filter_conditions, filter_values) = filters.to_sql();
+
+    let full_query = format!(
+        "{}{}{}{}{}",
+        base_query,
+        lyrics_conditions,
+        query_conditions,
+        filter_conditions,
+        list_options.to_sql(
+            ListKind::Tracks,
+            "albums.name_lower ASC, tracks.disc_number ASC, tracks.track_number ASC"
+        )
+    );
 
     let mut statement = db.prepare(&full_query)?;
-    let mut rows = statement.query([artist_id])?;
+    let mut values = vec![Value::from(artist_id)];
+    values.extend(query_values);
+    values.extend(filter_values);
+    let mut rows = statement.query(params_from_iter(values))?;
     let mut tracks: Vec<i64> = Vec::new();
 
     while let Some(row) = rows.next()? {
@@ -843,9 +2759,271 @@ pub fn get_artist_track_ids(artist_id: i64, without_plain_lyrics: bool, without_
     Ok(tracks)
 }
 
-pub fn clean_library(db: &Connection) -> Result<()> {
+pub fn add_scan_failures(failures: &[fs_track::ScanFailure], db: &mut Connection) -> Result<()> {
+    let tx = db.transaction()?;
+
+    for failure in failures.iter() {
+        add_scan_failure(failure, &tx)?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Records a scan failure, replacing the previous failure of the same file.
+pub fn add_scan_failure(failure: &fs_track::ScanFailure, db: &Connection) -> Result<()> {
+    let mut statement = db.prepare(
+        "INSERT OR REPLACE INTO scan_failures (file_path, error_kind, message) VALUES (?, ?, ?)",
+    )?;
+    statement.execute((&failure.file_path, &failure.error_kind, &failure.message))?;
+    Ok(())
+}
+
+pub fn delete_scan_failure(file_path: &str, db: &Connection) -> Result<()> {
+    db.execute("DELETE FROM scan_failures WHERE file_path = ?", [file_path])?;
+    Ok(())
+}
+
+pub fn clean_scan_failures(db: &Connection) -> Result<()> {
+    db.execute("DELETE FROM scan_failures WHERE 1", ())?;
+    Ok(())
+}
+
+pub fn get_scan_failure_by_id(id: i64, db: &Connection) -> Result<PersistentScanFailure> {
+    let mut statement =
+        db.prepare("SELECT id, file_path, error_kind, message FROM scan_failures WHERE id = ?")?;
+    let row = statement.query_row([id], |row| {
+        Ok(PersistentScanFailure {
+            id: row.get("id")?,
+            file_path: row.get("file_path")?,
+            error_kind: row.get("error_kind")?,
+            message: row.get("message")?,
+        })
+    })?;
+    Ok(row)
+}
+
+/// Returns the recorded scan failures grouped by their error kind.
+pub fn get_scan_failure_groups(db: &Connection) -> Result<Vec<PersistentScanFailureGroup>> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT id, file_path, error_kind, message
+      FROM scan_failures
+      ORDER BY error_kind ASC, file_path ASC
+  "})?;
+    let mut rows = statement.query([])?;
+    let mut groups: Vec<PersistentScanFailureGroup> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let failure = PersistentScanFailure {
+            id: row.get("id")?,
+            file_path: row.get("file_path")?,
+            error_kind: row.get("error_kind")?,
+            message: row.get("message")?,
+        };
+
+        match groups.last_mut() {
+            Some(group) if group.error_kind == failure.error_kind => {
+                group.failures_count += 1;
+                group.failures.push(failure);
+            }
+            _ => groups.push(PersistentScanFailureGroup {
+                error_kind: failure.error_kind.to_owned(),
+                failures_count: 1,
+                failures: vec![failure],
+            }),
+        }
+    }
+
+    Ok(groups)
+}
+
+pub struct DuplicateCandidate {
+    pub title_lower: String,
+    pub artist_name_lower: String,
+    pub content_hash: Option<String>,
+    pub track: PersistentDuplicateTrack,
+}
+
+/// Returns every track with its normalized title and artist, ordered so that likely
+/// duplicates are next to each other.
+pub fn get_duplicate_candidates(db: &Connection) -> Result<Vec<DuplicateCandidate>> {
+    let mut statement = db.prepare(indoc! {"
+      SELECT
+        tracks.id,
+        tracks.file_path,
+        tracks.title,
+        tracks.title_lower,
+        tracks.duration,
+        tracks.content_hash,
+        tracks.instrumental,
+        tracks.txt_lyrics IS NOT NULL AS has_plain_lyrics,
+        tracks.lrc_lyrics IS NOT NULL AS has_synced_lyrics,
+        artists.name AS artist_name,
+        artists.name_lower AS artist_name_lower
+      FROM tracks
+      JOIN artists ON tracks.artist_id = artists.id
+      ORDER BY tracks.title_lower ASC, artists.name_lower ASC, tracks.duration ASC
+  "})?;
+    let mut rows = statement.query([])?;
+    let mut candidates: Vec<DuplicateCandidate> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let is_instrumental: Option<bool> = row.get("instrumental")?;
+        let lyrics_status = if is_instrumental.unwrap_or(false) {
+            "instrumental"
+        } else if row.get("has_synced_lyrics")? {
+            "synced"
+        } else if row.get("has_plain_lyrics")? {
+            "plain"
+        } else {
+            "none"
+        };
+
+        candidates.push(DuplicateCandidate {
+            title_lower: row.get("title_lower")?,
+            artist_name_lower: row.get("artist_name_lower")?,
+            content_hash: row.get("content_hash")?,
+            track: PersistentDuplicateTrack {
+                id: row.get("id")?,
+                file_path: row.get("file_path")?,
+                title: row.get("title")?,
+                artist_name: row.get("artist_name")?,
+                duration: row.get("duration")?,
+                lyrics_status: lyrics_status.to_owned(),
+            },
+        });
+    }
+
+    Ok(candidates)
+}
+
+/// Empties the library. Lyrics revisions are kept, detached from their tracks, so a new scan
+/// gives them back. Returns the artwork thumbnails of the removed albums, which are left for
+/// the caller to delete.
+pub fn clean_library(db: &Connection) -> Result<Vec<String>> {
+    let image_paths = get_album_image_paths("WHERE 1", db)?;
+    db.execute("DELETE FROM scan_checkpoints WHERE 1", ())?;
+    db.execute("DELETE FROM scan_failures WHERE 1", ())?;
+    db.execute("DELETE FROM track_artists WHERE 1", ())?;
+    db.execute(
+        &format!("{} WHERE track_id IS NOT NULL", DETACH_LYRICS_REVISIONS),
+        (),
+    )?;
+    db.execute("DELETE FROM download_attempts WHERE 1", ())?;
     db.execute("DELETE FROM tracks WHERE 1", ())?;
     db.execute("DELETE FROM albums WHERE 1", ())?;
     db.execute("DELETE FROM artists WHERE 1", ())?;
-    Ok(())
+    Ok(image_paths)
+}
+
+/// Aggregates of `tracks` counting them by lyrics status, in the order of
+/// `PersistentLyricsCoverage`.
+const COVERAGE_COLUMNS: &str = indoc! {"
+    COUNT(tracks.id) AS tracks_count,
+    IFNULL(SUM(IFNULL(tracks.instrumental, 0) = 0 AND tracks.lrc_lyrics IS NOT NULL), 0)
+      AS synced_count,
+    IFNULL(SUM(
+      IFNULL(tracks.instrumental, 0) = 0
+      AND tracks.lrc_lyrics IS NULL
+      AND tracks.txt_lyrics IS NOT NULL
+    ), 0) AS plain_count,
+    IFNULL(SUM(IFNULL(tracks.instrumental, 0) = 1), 0) AS instrumental_count,
+    IFNULL(SUM(
+      IFNULL(tracks.instrumental, 0) = 0
+      AND tracks.lrc_lyrics IS NULL
+      AND tracks.txt_lyrics IS NULL
+    ), 0) AS missing_count,
+    TOTAL(tracks.duration) AS duration"};
+
+fn coverage_from_row(row: &Row) -> rusqlite::Result<PersistentLyricsCoverage> {
+    Ok(PersistentLyricsCoverage {
+        tracks_count: row.get("tracks_count")?,
+        synced_count: row.get("synced_count")?,
+        plain_count: row.get("plain_count")?,
+        instrumental_count: row.get("instrumental_count")?,
+        missing_count: row.get("missing_count")?,
+        duration: row.get("duration")?,
+    })
+}
+
+/// Returns the lyrics coverage of the whole library, of every artist and album, and of every
+/// codec. Tracks with several artists count for each of them.
+pub fn get_library_statistics(db: &Connection) -> Result<PersistentLibraryStatistics> {
+    let query = format!("SELECT {} FROM tracks", COVERAGE_COLUMNS);
+    let total = db.query_row(&query, [], coverage_from_row)?;
+
+    let query = formatdoc! {"
+      SELECT artists.id, artists.name, {}
+      FROM artists
+      JOIN track_artists ON track_artists.artist_id = artists.id
+      JOIN tracks ON tracks.id = track_artists.track_id
+      GROUP BY artists.id, artists.name
+      ORDER BY artists.name_lower ASC",
+        COVERAGE_COLUMNS
+    };
+    let mut statement = db.prepare(&query)?;
+    let mut rows = statement.query([])?;
+    let mut artists: Vec<PersistentArtistCoverage> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        artists.push(PersistentArtistCoverage {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            coverage: coverage_from_row(row)?,
+        });
+    }
+
+    let query = formatdoc! {"
+      SELECT albums.id, albums.name, albums.album_artist_name, {}
+      FROM albums
+      JOIN tracks ON tracks.album_id = albums.id
+      GROUP BY albums.id, albums.name, albums.album_artist_name
+      ORDER BY albums.name_lower ASC",
+        COVERAGE_COLUMNS
+    };
+    let mut statement = db.prepare(&query)?;
+    let mut rows = statement.query([])?;
+    let mut albums: Vec<PersistentAlbumCoverage> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        albums.push(PersistentAlbumCoverage {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            album_artist_name: row.get("album_artist_name")?,
+            coverage: coverage_from_row(row)?,
+        });
+    }
+
+    // The format is the extension of the file: what is after the last dot of its path
+    let query = formatdoc! {"
+      SELECT
+        LOWER(REPLACE(
+          tracks.file_path,
+          RTRIM(tracks.file_path, REPLACE(tracks.file_path, '.', '')),
+          ''
+        )) AS extension,
+        {}
+      FROM tracks
+      GROUP BY extension
+      ORDER BY tracks_count DESC, extension ASC",
+        COVERAGE_COLUMNS
+    };
+    let mut statement = db.prepare(&query)?;
+    let mut rows = statement.query([])?;
+    let mut formats: Vec<PersistentFormatCoverage> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        formats.push(PersistentFormatCoverage {
+            extension: row.get("extension")?,
+            coverage: coverage_from_row(row)?,
+        });
+    }
+
+    Ok(PersistentLibraryStatistics {
+        total,
+        artists,
+        albums,
+        formats,
+    })
 }
@@ -0,0 +1,146 @@
+use crate::persistent_entities::PersistentDatabaseBackup;
+use anyhow::Result;
+use rusqlite::Connection;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// How many backups are kept. Older ones are deleted when a new backup is made.
+const KEPT_BACKUPS_COUNT: usize = 10;
+
+/// Copy of the backup to restore on the next start, while no connection is open.
+const PENDING_RESTORE_FILE_NAME: &str = "db.restore.sqlite3";
+
+#[derive(Error, Debug)]
+pub enum DatabaseBackupError {
+    #[error("The database backup `{0}` does not exist")]
+    NotFound(String),
+}
+
+pub fn backups_dir(app_dir: &Path) -> PathBuf {
+    app_dir.join("backups")
+}
+
+/// Copies the database into `backups/db.v<version>.<timestamp>.sqlite3`. The copy is made by
+/// SQLite, so it is consistent even with changes that are still in the WAL file.
+pub fn create(db: &Connection, app_dir: &Path, version: u32) -> Result<PathBuf> {
+    let backups_dir = backups_dir(app_dir);
+    fs::create_dir_all(&backups_dir)?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut backup_path = backups_dir.join(format!("db.v{}.{}.sqlite3", version, timestamp));
+    let mut suffix = 1;
+    while backup_path.exists() {
+        backup_path = backups_dir.join(format!("db.v{}.{}-{}.sqlite3", version, timestamp, suffix));
+        suffix += 1;
+    }
+
+    db.execute("VACUUM INTO ?", [backup_path.to_string_lossy()])?;
+    println!("Database backed up to {}", backup_path.display());
+
+    if let Err(error) = prune(app_dir) {
+        println!("Deleting old database backups errored. Message: {}", error);
+    }
+
+    Ok(backup_path)
+}
+
+/// Lists the backups, newest first.
+pub fn list(app_dir: &Path) -> Result<Vec<PersistentDatabaseBackup>> {
+    let backups_dir = backups_dir(app_dir);
+    if !backups_dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut backups: Vec<PersistentDatabaseBackup> = vec![];
+    for entry in fs::read_dir(&backups_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some((version, created_at)) = parse_file_name(&file_name) else {
+            continue;
+        };
+
+        backups.push(PersistentDatabaseBackup {
+            file_name,
+            version,
+            created_at,
+            file_size: entry.metadata()?.len(),
+        });
+    }
+    backups.sort_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then_with(|| b.file_name.cmp(&a.file_name))
+    });
+
+    Ok(backups)
+}
+
+/// Replaces the database file with the backup. No connection to the database may be open.
+pub fn restore(backup_path: &Path, sqlite_path: &Path) -> Result<()> {
+    fs::copy(backup_path, sqlite_path)?;
+
+    // The WAL of the replaced database would otherwise be replayed over the backup
+    for extension in ["sqlite3-wal", "sqlite3-shm"] {
+        let path = sqlite_path.with_extension(extension);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    println!("Database restored from {}", backup_path.display());
+
+    Ok(())
+}
+
+/// Sets the backup aside to be restored on the next start of the app. The backup is copied,
+/// so deleting old backups in the meantime doesn't affect it.
+pub fn schedule_restore(app_dir: &Path, file_name: &str) -> Result<()> {
+    let backup_path = find(app_dir, file_name)?;
+    fs::copy(backup_path, app_dir.join(PENDING_RESTORE_FILE_NAME))?;
+
+    Ok(())
+}
+
+/// Restores the backup scheduled by `schedule_restore`, if there is one.
+pub fn restore_pending(app_dir: &Path, sqlite_path: &Path) -> Result<()> {
+    let pending_path = app_dir.join(PENDING_RESTORE_FILE_NAME);
+    if !pending_path.exists() {
+        return Ok(());
+    }
+
+    restore(&pending_path, sqlite_path)?;
+    fs::remove_file(&pending_path)?;
+
+    Ok(())
+}
+
+/// Finds a backup by its file name, which must be one of the listed backups.
+fn find(app_dir: &Path, file_name: &str) -> Result<PathBuf> {
+    let is_listed = list(app_dir)?
+        .iter()
+        .any(|backup| backup.file_name == file_name);
+    if !is_listed {
+        return Err(DatabaseBackupError::NotFound(file_name.to_owned()).into());
+    }
+
+    Ok(backups_dir(app_dir).join(file_name))
+}
+
+fn prune(app_dir: &Path) -> Result<()> {
+    for backup in list(app_dir)?.iter().skip(KEPT_BACKUPS_COUNT) {
+        fs::remove_file(backups_dir(app_dir).join(&backup.file_name))?;
+    }
+
+    Ok(())
+}
+
+/// Reads the version and timestamp out of `db.v<version>.<timestamp>[-<n>].sqlite3`.
+fn parse_file_name(file_name: &str) -> Option<(u32, u64)> {
+    let stem = file_name.strip_prefix("db.v")?.strip_suffix(".sqlite3")?;
+    let (version, timestamp) = stem.split_once('.')?;
+    let timestamp = timestamp.split('-').next()?;
+
+    Some((version.parse().ok()?, timestamp.parse().ok()?))
+}
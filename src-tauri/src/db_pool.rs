@@ -0,0 +1,89 @@
+use rusqlite::{Connection, OpenFlags};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How many read-only connections serve the UI next to the writer.
+const READERS_COUNT: usize = 4;
+
+/// How long a connection waits for a lock of another connection before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connections to the library database: a single writer, and a few read-only
+/// connections for everything else. The database is in WAL mode, so readers see the last
+/// committed data and keep working while a scan or a download holds the writer.
+pub struct DbPool {
+    writer: Mutex<Connection>,
+    readers: Mutex<Vec<Connection>>,
+    reader_returned: Condvar,
+}
+
+/// A reader borrowed from the pool, returned to it when dropped.
+pub struct DbReader<'a> {
+    pool: &'a DbPool,
+    connection: Option<Connection>,
+}
+
+impl DbPool {
+    /// Takes over the connection the database was migrated with as the writer, and opens the
+    /// readers next to it.
+    pub fn new(writer: Connection, path: &Path) -> Result<DbPool, rusqlite::Error> {
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+
+        let mut readers: Vec<Connection> = Vec::with_capacity(READERS_COUNT);
+        for _ in 0..READERS_COUNT {
+            let reader = Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            reader.busy_timeout(BUSY_TIMEOUT)?;
+            readers.push(reader);
+        }
+
+        Ok(DbPool {
+            writer: Mutex::new(writer),
+            readers: Mutex::new(readers),
+            reader_returned: Condvar::new(),
+        })
+    }
+
+    /// Borrows a reader, waiting for one to be returned if they are all in use.
+    pub fn reader(&self) -> DbReader<'_> {
+        let mut readers = self.readers.lock().unwrap();
+        loop {
+            if let Some(connection) = readers.pop() {
+                return DbReader {
+                    pool: self,
+                    connection: Some(connection),
+                };
+            }
+            readers = self.reader_returned.wait(readers).unwrap();
+        }
+    }
+
+    /// Locks the writer. Only one write runs at a time, so long writes such as scans hold it
+    /// until they are done.
+    pub fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().unwrap()
+    }
+}
+
+impl Deref for DbReader<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl Drop for DbReader<'_> {
+    fn drop(&mut self) {
+        if let (Some(connection), Ok(mut readers)) =
+            (self.connection.take(), self.pool.readers.lock())
+        {
+            readers.push(connection);
+            self.pool.reader_returned.notify_one();
+        }
+    }
+}
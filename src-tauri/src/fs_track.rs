@@ -1,19 +1,32 @@
+use crate::artist_splitter::ArtistSplitter;
+use crate::artwork;
+use crate::content_hash;
+use crate::cue_sheet::{self, CueSheet};
 use crate::db;
+use crate::lyrics;
+use crate::path_template::{self, PathField, PathTemplate};
+use crate::persistent_entities::PersistentDirectory;
+use crate::state::AppState;
 use anyhow::Result;
-use globwalk::{glob, DirEntry};
+use ignore::overrides::{Override, OverrideBuilder};
 use lofty::error::LoftyError;
 use lofty::file::AudioFile;
-use lofty::file::TaggedFileExt;
+use lofty::file::{FileType, TaggedFile, TaggedFileExt};
 use lofty::read_from_path;
-use lofty::tag::Accessor;
+use lofty::tag::{Accessor, ItemKey, Tag, TagType};
 use rayon::prelude::*;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::Instant;
-use tauri::{AppHandle, Emitter};
+use std::slice;
+use std::sync::atomic::Ordering;
+use std::time::{Instant, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager, State};
 use thiserror::Error;
+use walkdir::{DirEntry, WalkDir};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FsTrack {
@@ -22,11 +35,49 @@ pub struct FsTrack {
     title: String,
     album: String,
     artist: String,
+    artists: Vec<String>,
     album_artist: String,
     duration: f64,
     txt_lyrics: Option<String>,
     lrc_lyrics: Option<String>,
+    lyrics_source: Option<LyricsSource>,
     track_number: Option<u32>,
+    track_total: Option<u32>,
+    disc_number: Option<u32>,
+    disc_total: Option<u32>,
+    year: Option<u32>,
+    genre: Option<String>,
+    isrc: Option<String>,
+    musicbrainz_recording_id: Option<String>,
+    musicbrainz_release_id: Option<String>,
+    codec: Option<String>,
+    bitrate: Option<u32>,
+    sample_rate: Option<u32>,
+    channels: Option<u8>,
+    file_mtime: Option<i64>,
+    file_size: Option<i64>,
+    content_hash: Option<String>,
+    cue_track_number: Option<u32>,
+    cue_start: Option<f64>,
+    cue_end: Option<f64>,
+    inferred_fields: Vec<String>,
+}
+
+/// Where the lyrics of a track were read from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LyricsSource {
+    Sidecar,
+    Tag,
+}
+
+impl LyricsSource {
+    /// The name used to record the source in the database.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LyricsSource::Sidecar => "sidecar",
+            LyricsSource::Tag => "tag",
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -39,8 +90,80 @@ pub enum FsTrackError {
     AlbumNotFound(String),
     #[error("No artist name was found from track: `{0}`")]
     ArtistNotFound(String),
-    #[error("No primary tag was found from track: `{0}`")]
-    PrimaryTagNotFound(String),
+    #[error("No tag was found from track: `{0}`")]
+    TagNotFound(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ScanError {
+    #[error("The library scan was cancelled")]
+    Cancelled,
+}
+
+impl FsTrackError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FsTrackError::ParseFailed(..) => "ParseFailed",
+            FsTrackError::TitleNotFound(..) => "TitleNotFound",
+            FsTrackError::AlbumNotFound(..) => "AlbumNotFound",
+            FsTrackError::ArtistNotFound(..) => "ArtistNotFound",
+            FsTrackError::TagNotFound(..) => "TagNotFound",
+        }
+    }
+}
+
+/// A file that was found during a scan but could not be added to the library.
+#[derive(Clone, Debug)]
+pub struct ScanFailure {
+    pub file_path: String,
+    pub error_kind: String,
+    pub message: String,
+}
+
+impl ScanFailure {
+    pub fn new(file_path: String, error: &anyhow::Error) -> ScanFailure {
+        let error_kind = error
+            .downcast_ref::<FsTrackError>()
+            .map_or("Unknown", |fs_track_error| fs_track_error.kind());
+
+        ScanFailure {
+            file_path,
+            error_kind: error_kind.to_owned(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// The order in which tags are consulted when a file carries more than one. The primary tag of
+/// the file format always comes first.
+const TAG_PRIORITY: [TagType; 7] = [
+    TagType::Id3v2,
+    TagType::VorbisComments,
+    TagType::Mp4Ilst,
+    TagType::Ape,
+    TagType::AiffText,
+    TagType::RiffInfo,
+    TagType::Id3v1,
+];
+
+pub const AUDIO_EXTENSIONS: [&str; 12] = [
+    "mp3", "m4a", "m4b", "flac", "ogg", "opus", "wav", "aiff", "aif", "ape", "wv", "mpc",
+];
+
+/// Library-wide settings that affect how a file is read into a track.
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    pub path_templates: Vec<PathTemplate>,
+    pub artist_splitter: ArtistSplitter,
+}
+
+impl ScanOptions {
+    pub fn load(conn: &Connection) -> Result<ScanOptions> {
+        Ok(ScanOptions {
+            path_templates: path_template::parse_templates(&db::get_path_templates(conn)?),
+            artist_splitter: ArtistSplitter::new(&db::get_artist_separators(conn)?),
+        })
+    }
 }
 
 #[derive(Clone, Serialize)]
@@ -63,6 +186,8 @@ impl FsTrack {
         txt_lyrics: Option<String>,
         lrc_lyrics: Option<String>,
         track_number: Option<u32>,
+        file_mtime: Option<i64>,
+        file_size: Option<i64>,
     ) -> FsTrack {
         FsTrack {
             file_path,
@@ -70,42 +195,100 @@ impl FsTrack {
             title,
             album,
             artist,
+            artists: vec![],
             album_artist,
             duration,
             txt_lyrics,
             lrc_lyrics,
+            lyrics_source: None,
             track_number,
+            track_total: None,
+            disc_number: None,
+            disc_total: None,
+            year: None,
+            genre: None,
+            isrc: None,
+            musicbrainz_recording_id: None,
+            musicbrainz_release_id: None,
+            codec: None,
+            bitrate: None,
+            sample_rate: None,
+            channels: None,
+            file_mtime,
+            file_size,
+            content_hash: None,
+            cue_track_number: None,
+            cue_start: None,
+            cue_end: None,
+            inferred_fields: vec![],
         }
     }
 
-    fn new_from_path(path: &Path) -> Result<FsTrack> {
+    pub fn new_from_path(path: &Path, options: &ScanOptions) -> Result<FsTrack> {
         let file_path = path.display().to_string();
         let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
         let tagged_file = read_from_path(&file_path)
             .or_else(|err| Err(FsTrackError::ParseFailed(file_path.to_owned(), err)))?;
-        let tag = tagged_file
-            .primary_tag()
-            .ok_or(FsTrackError::PrimaryTagNotFound(file_path.to_owned()))?
-            .to_owned();
+        let merged_tag = merge_tags(&tagged_file);
+        let tag = merged_tag.as_ref();
         let properties = tagged_file.properties();
-        let title = tag
-            .title()
-            .ok_or(FsTrackError::TitleNotFound(file_path.to_owned()))?
-            .to_string();
-        let album = tag
-            .album()
-            .ok_or(FsTrackError::AlbumNotFound(file_path.to_owned()))?
-            .to_string();
-        let artist = tag
-            .artist()
-            .ok_or(FsTrackError::ArtistNotFound(file_path.to_owned()))?
-            .to_string();
-        let album_artist = tag
-            .get_string(&lofty::tag::ItemKey::AlbumArtist)
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| artist.clone());
+        let mut title = tag.and_then(|tag| tag.title()).map(|s| s.to_string());
+        let mut album = tag.and_then(|tag| tag.album()).map(|s| s.to_string());
+        let mut artist = tag.and_then(|tag| tag.artist()).map(|s| s.to_string());
+        let mut album_artist = tag
+            .and_then(|tag| tag.get_string(&ItemKey::AlbumArtist))
+            .map(|s| s.to_string());
+        let mut track_number = tag.and_then(|tag| tag.track());
+        let mut inferred_fields: Vec<String> = vec![];
+
+        if title.is_none() || album.is_none() || artist.is_none() || track_number.is_none() {
+            if let Some(path_fields) = path_template::infer_fields(path, &options.path_templates) {
+                fill_inferred(
+                    &mut title,
+                    path_fields.title,
+                    PathField::Title,
+                    &mut inferred_fields,
+                );
+                fill_inferred(
+                    &mut album,
+                    path_fields.album,
+                    PathField::Album,
+                    &mut inferred_fields,
+                );
+                fill_inferred(
+                    &mut artist,
+                    path_fields.artist,
+                    PathField::Artist,
+                    &mut inferred_fields,
+                );
+                fill_inferred(
+                    &mut album_artist,
+                    path_fields.album_artist,
+                    PathField::AlbumArtist,
+                    &mut inferred_fields,
+                );
+                fill_inferred(
+                    &mut track_number,
+                    path_fields.track_number,
+                    PathField::TrackNumber,
+                    &mut inferred_fields,
+                );
+            }
+        }
+
+        if tag.is_none() && inferred_fields.is_empty() {
+            return Err(FsTrackError::TagNotFound(file_path.to_owned()).into());
+        }
+
+        let title = title.ok_or(FsTrackError::TitleNotFound(file_path.to_owned()))?;
+        let album = album.ok_or(FsTrackError::AlbumNotFound(file_path.to_owned()))?;
+        let artist = artist.ok_or(FsTrackError::ArtistNotFound(file_path.to_owned()))?;
+        let album_artist = album_artist.unwrap_or_else(|| artist.clone());
+        let artists = options
+            .artist_splitter
+            .split(&artist_tag_values(tag, &artist));
         let duration = properties.duration().as_secs_f64();
-        let track_number = tag.track();
+        let (file_mtime, file_size) = read_track_file_state(path);
 
         let mut track = FsTrack::new(
             file_path,
@@ -118,13 +301,123 @@ impl FsTrack {
             None,
             None,
             track_number,
+            file_mtime,
+            file_size,
         );
         track.txt_lyrics = track.get_txt_lyrics();
         track.lrc_lyrics = track.get_lrc_lyrics();
+        if track.txt_lyrics.is_some() || track.lrc_lyrics.is_some() {
+            track.lyrics_source = Some(LyricsSource::Sidecar);
+        } else {
+            let embedded_lyrics = lyrics::read_embedded_lyrics(path, &tagged_file);
+            if !embedded_lyrics.is_empty() {
+                track.txt_lyrics = embedded_lyrics.plain_lyrics;
+                track.lrc_lyrics = embedded_lyrics.synced_lyrics;
+                track.lyrics_source = Some(LyricsSource::Tag);
+            }
+        }
+        track.track_total = tag.and_then(|tag| tag.track_total());
+        track.disc_number = tag.and_then(|tag| tag.disk());
+        track.disc_total = tag.and_then(|tag| tag.disk_total());
+        track.artists = artists;
+        track.year = tag.and_then(|tag| tag.year());
+        track.genre = tag.and_then(|tag| tag.genre()).map(|s| s.to_string());
+        track.isrc = tag
+            .and_then(|tag| tag.get_string(&ItemKey::Isrc))
+            .map(|s| s.to_string());
+        track.musicbrainz_recording_id = tag
+            .and_then(|tag| tag.get_string(&ItemKey::MusicBrainzRecordingId))
+            .map(|s| s.to_string());
+        track.musicbrainz_release_id = tag
+            .and_then(|tag| tag.get_string(&ItemKey::MusicBrainzReleaseId))
+            .map(|s| s.to_string());
+        track.codec = Some(codec_name(&tagged_file).to_owned());
+        track.bitrate = properties.audio_bitrate().or(properties.overall_bitrate());
+        track.sample_rate = properties.sample_rate();
+        track.channels = properties.channels();
+        track.content_hash = match content_hash::compute(path) {
+            Ok(content_hash) => Some(content_hash),
+            Err(error) => {
+                println!(
+                    "Cannot hash the audio of `{}`. Error: {}",
+                    path.display(),
+                    error
+                );
+                None
+            }
+        };
+        track.inferred_fields = inferred_fields;
 
         Ok(track)
     }
 
+    /// Reads the tracks of an audio file: the file itself, or the virtual tracks of the CUE
+    /// sheet next to it.
+    pub fn load_from_path(path: &Path, options: &ScanOptions) -> Result<Vec<FsTrack>> {
+        let track = FsTrack::new_from_path(path, options)?;
+
+        match cue_sheet::find_for(path) {
+            Some(cue_path) => {
+                let sheet = CueSheet::read(&cue_path)?;
+                track.split_by_cue_sheet(&sheet, options)
+            }
+            None => Ok(vec![track]),
+        }
+    }
+
+    /// Turns the track of a whole-album rip into the virtual tracks of its CUE sheet. A virtual
+    /// track ends where the next one starts, the last one at the end of the file. Fields the
+    /// sheet doesn't have are taken from the tags of the file.
+    fn split_by_cue_sheet(self, sheet: &CueSheet, options: &ScanOptions) -> Result<Vec<FsTrack>> {
+        let cue_tracks = sheet.tracks_for(Path::new(&self.file_path))?;
+        let mut tracks: Vec<FsTrack> = vec![];
+
+        for cue_track in cue_tracks.iter() {
+            let mut track = self.clone();
+
+            track.title = cue_track
+                .title
+                .to_owned()
+                .unwrap_or_else(|| format!("Track {:02}", cue_track.number));
+            if let Some(performer) = cue_track.performer.as_ref().or(sheet.performer.as_ref()) {
+                track.artist = performer.to_owned();
+                track.artists = options.artist_splitter.split(&[performer.to_owned()]);
+            }
+            if let Some(title) = &sheet.title {
+                track.album = title.to_owned();
+            }
+            if let Some(performer) = &sheet.performer {
+                track.album_artist = performer.to_owned();
+            }
+            track.duration = (cue_track.end.unwrap_or(self.duration) - cue_track.start).max(0.0);
+            track.track_number = Some(cue_track.number);
+            track.track_total = Some(cue_tracks.len() as u32);
+            track.year = sheet.year.or(self.year);
+            track.genre = sheet.genre.to_owned().or(self.genre.to_owned());
+            track.isrc = cue_track.isrc.to_owned();
+            track.musicbrainz_recording_id = None;
+            // Virtual tracks share the audio of the file, so the hash can't tell them apart
+            track.content_hash = None;
+            track.inferred_fields = vec![];
+            track.cue_track_number = Some(cue_track.number);
+            track.cue_start = Some(cue_track.start);
+            track.cue_end = cue_track.end;
+
+            // The lyrics of the whole file don't belong to any single virtual track
+            track.txt_lyrics = track.get_txt_lyrics();
+            track.lrc_lyrics = track.get_lrc_lyrics();
+            track.lyrics_source = if track.txt_lyrics.is_some() || track.lrc_lyrics.is_some() {
+                Some(LyricsSource::Sidecar)
+            } else {
+                None
+            };
+
+            tracks.push(track);
+        }
+
+        Ok(tracks)
+    }
+
     pub fn file_path(&self) -> String {
         self.file_path.to_owned()
     }
@@ -145,6 +438,11 @@ impl FsTrack {
         self.artist.to_owned()
     }
 
+    /// The individual artists credited on the track, split from the artist tag values.
+    pub fn artists(&self) -> Vec<String> {
+        self.artists.to_owned()
+    }
+
     pub fn album_artist(&self) -> String {
         self.album_artist.to_owned()
     }
@@ -161,26 +459,98 @@ impl FsTrack {
         self.lrc_lyrics.to_owned()
     }
 
+    pub fn lyrics_source(&self) -> Option<LyricsSource> {
+        self.lyrics_source
+    }
+
     pub fn track_number(&self) -> Option<u32> {
         self.track_number
     }
 
-    fn get_txt_path(&self) -> String {
-        let path = PathBuf::from(self.file_path.to_owned());
-        let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
-        let parent_path = path.parent().unwrap();
-        let file_name_without_extension = std::path::Path::new(&file_name)
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_owned();
-        let mut txt_file_name = file_name_without_extension.to_owned();
-        txt_file_name.push_str(".txt");
+    pub fn track_total(&self) -> Option<u32> {
+        self.track_total
+    }
+
+    pub fn disc_number(&self) -> Option<u32> {
+        self.disc_number
+    }
 
-        let txt_file_path = parent_path.join(txt_file_name).display().to_string();
+    pub fn disc_total(&self) -> Option<u32> {
+        self.disc_total
+    }
 
-        txt_file_path
+    pub fn year(&self) -> Option<u32> {
+        self.year
+    }
+
+    pub fn genre(&self) -> Option<String> {
+        self.genre.to_owned()
+    }
+
+    pub fn isrc(&self) -> Option<String> {
+        self.isrc.to_owned()
+    }
+
+    pub fn musicbrainz_recording_id(&self) -> Option<String> {
+        self.musicbrainz_recording_id.to_owned()
+    }
+
+    pub fn musicbrainz_release_id(&self) -> Option<String> {
+        self.musicbrainz_release_id.to_owned()
+    }
+
+    pub fn codec(&self) -> Option<String> {
+        self.codec.to_owned()
+    }
+
+    /// Audio bitrate in kbps.
+    pub fn bitrate(&self) -> Option<u32> {
+        self.bitrate
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> Option<u8> {
+        self.channels
+    }
+
+    pub fn file_mtime(&self) -> Option<i64> {
+        self.file_mtime
+    }
+
+    pub fn file_size(&self) -> Option<i64> {
+        self.file_size
+    }
+
+    pub fn content_hash(&self) -> Option<String> {
+        self.content_hash.to_owned()
+    }
+
+    /// Number of the track in the CUE sheet, for the virtual tracks of a CUE sheet rip.
+    pub fn cue_track_number(&self) -> Option<u32> {
+        self.cue_track_number
+    }
+
+    /// Offset in seconds where the virtual track starts in the file.
+    pub fn cue_start(&self) -> Option<f64> {
+        self.cue_start
+    }
+
+    /// Offset in seconds where the virtual track ends in the file, or `None` if it plays to
+    /// the end.
+    pub fn cue_end(&self) -> Option<f64> {
+        self.cue_end
+    }
+
+    /// Names of the fields that were taken from the file path instead of the tags.
+    pub fn inferred_fields(&self) -> Vec<String> {
+        self.inferred_fields.to_owned()
+    }
+
+    fn get_txt_path(&self) -> PathBuf {
+        lyrics::build_sidecar_path(&self.file_path, self.cue_track_number.map(i64::from), "txt")
     }
 
     fn get_txt_lyrics(&self) -> Option<String> {
@@ -193,22 +563,8 @@ impl FsTrack {
         }
     }
 
-    fn get_lrc_path(&self) -> String {
-        let path = PathBuf::from(self.file_path.to_owned());
-        let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
-        let parent_path = path.parent().unwrap();
-        let file_name_without_extension = std::path::Path::new(&file_name)
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_owned();
-        let mut lrc_file_name = file_name_without_extension.to_owned();
-        lrc_file_name.push_str(".lrc");
-
-        let lrc_file_path = parent_path.join(lrc_file_name).display().to_string();
-
-        lrc_file_path
+    fn get_lrc_path(&self) -> PathBuf {
+        lyrics::build_sidecar_path(&self.file_path, self.cue_track_number.map(i64::from), "lrc")
     }
 
     fn get_lrc_lyrics(&self) -> Option<String> {
@@ -222,51 +578,417 @@ impl FsTrack {
     }
 }
 
-fn load_tracks_from_entry_batch(entry_batch: &Vec<DirEntry>) -> Result<Vec<FsTrack>> {
-    let track_results: Vec<Result<FsTrack>> = entry_batch
+/// Decides which files of a library directory are scanned, from its extensions and exclude
+/// patterns. The patterns follow gitignore rules and match case-insensitively, and an
+/// excluded directory excludes everything below it. Scans and the watcher both go through it,
+/// so they always agree on what belongs to the library.
+pub struct DirectoryMatcher {
+    root: PathBuf,
+    overrides: Override,
+}
+
+impl DirectoryMatcher {
+    /// Without extensions, every supported audio format is scanned. Fails on an invalid
+    /// exclude pattern.
+    pub fn new(
+        path: &str,
+        extensions: &[String],
+        exclude_patterns: &[String],
+    ) -> Result<DirectoryMatcher> {
+        let extensions = if extensions.is_empty() {
+            AUDIO_EXTENSIONS.join(",")
+        } else {
+            extensions.join(",")
+        };
+
+        let mut builder = OverrideBuilder::new(path);
+        builder.case_insensitive(true)?;
+        builder.add(&format!("**/*.{{{}}}", extensions))?;
+        for exclude_pattern in exclude_patterns.iter() {
+            builder.add(&format!("!{}", exclude_pattern))?;
+        }
+
+        Ok(DirectoryMatcher {
+            root: PathBuf::from(path),
+            overrides: builder.build()?,
+        })
+    }
+
+    pub fn for_directory(directory: &PersistentDirectory) -> Result<DirectoryMatcher> {
+        DirectoryMatcher::new(
+            &directory.path,
+            &directory.extensions,
+            &directory.exclude_patterns,
+        )
+    }
+
+    /// Tells whether the file is scanned: it has one of the extensions, and neither the file
+    /// nor any directory between it and the library directory is excluded.
+    pub fn is_included(&self, path: &Path) -> bool {
+        let Ok(relative_path) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let is_in_excluded_directory = relative_path
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| self.is_excluded_directory(ancestor));
+
+        !is_in_excluded_directory && self.overrides.matched(relative_path, false).is_whitelist()
+    }
+
+    fn is_excluded_directory(&self, relative_path: &Path) -> bool {
+        !relative_path.as_os_str().is_empty()
+            && self.overrides.matched(relative_path, true).is_ignore()
+    }
+
+    /// Walks the scanned files below the path, which is the library directory or one of its
+    /// subdirectories. Excluded directories aren't entered at all.
+    pub fn walk<'a>(
+        &'a self,
+        path: &Path,
+        follow_symlinks: bool,
+    ) -> impl Iterator<Item = walkdir::Result<DirEntry>> + 'a {
+        WalkDir::new(path)
+            .follow_links(follow_symlinks)
+            .into_iter()
+            .filter_entry(move |entry| {
+                !entry.file_type().is_dir()
+                    || entry
+                        .path()
+                        .strip_prefix(&self.root)
+                        .map_or(true, |relative_path| {
+                            !self.is_excluded_directory(relative_path)
+                        })
+            })
+            .filter(move |item| match item {
+                Ok(entry) => !entry.file_type().is_dir() && self.is_included(entry.path()),
+                Err(_) => true,
+            })
+    }
+}
+
+/// Returns the library directory that contains the path, if any.
+pub fn find_directory<'a>(
+    directories: &'a [PersistentDirectory],
+    path: &Path,
+) -> Option<&'a PersistentDirectory> {
+    directories
+        .iter()
+        .filter(|directory| path.starts_with(&directory.path))
+        .max_by_key(|directory| directory.path.len())
+}
+
+/// Tells whether a file would be picked up by scanning the directory. Invalid scan settings
+/// are logged and include nothing.
+pub fn is_included(directory: &PersistentDirectory, path: &Path) -> bool {
+    match DirectoryMatcher::for_directory(directory) {
+        Ok(matcher) => matcher.is_included(path),
+        Err(error) => {
+            println!(
+                "Invalid scan settings of `{}`. Error: {}",
+                directory.path, error
+            );
+            false
+        }
+    }
+}
+
+/// Tells whether a scanned track is long enough to be kept in the library.
+pub fn is_long_enough(directory: &PersistentDirectory, track: &FsTrack) -> bool {
+    directory
+        .min_duration
+        .map_or(true, |min_duration| track.duration() >= min_duration)
+}
+
+pub fn is_lyrics_sidecar(path: &Path) -> bool {
+    has_extension(path, &["lrc", "txt"])
+}
+
+/// Moves the `.lrc`/`.txt` files left behind by a moved or renamed track next to its new
+/// location, unless the new location already has its own. Returns whether anything was moved.
+pub fn move_lyrics_sidecars(old_path: &Path, new_path: &Path) -> bool {
+    let mut moved = false;
+
+    for extension in ["lrc", "txt"] {
+        let old_sidecar = old_path.with_extension(extension);
+        let new_sidecar = new_path.with_extension(extension);
+        if !old_sidecar.is_file() || new_sidecar.exists() {
+            continue;
+        }
+
+        match fs::rename(&old_sidecar, &new_sidecar) {
+            Ok(()) => moved = true,
+            Err(error) => println!(
+                "Cannot move `{}` to `{}`. Error: {}",
+                old_sidecar.display(),
+                new_sidecar.display(),
+                error
+            ),
+        }
+    }
+
+    moved
+}
+
+/// Looks among the tracks with the same content hash for one whose file no longer exists,
+/// meaning the file was moved or renamed, and returns its id so the row is updated instead of
+/// replaced. The lyrics sidecars are carried along, and the track is re-read if that brought
+/// lyrics with it.
+pub fn resolve_moved_track(
+    track: FsTrack,
+    candidates: &[(i64, String)],
+    options: &ScanOptions,
+) -> (Option<i64>, FsTrack) {
+    let file_path = track.file_path();
+    let moved_from = candidates
+        .iter()
+        .find(|(_, old_path)| *old_path != file_path && !Path::new(old_path).exists());
+    let Some((track_id, old_path)) = moved_from else {
+        return (None, track);
+    };
+
+    println!("Track moved from `{}` to `{}`", old_path, file_path);
+    if move_lyrics_sidecars(Path::new(old_path), Path::new(&file_path)) {
+        if let Ok(moved_track) = FsTrack::new_from_path(Path::new(&file_path), options) {
+            return (Some(*track_id), moved_track);
+        }
+    }
+
+    (Some(*track_id), track)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extensions
+                .iter()
+                .any(|candidate| candidate.eq_ignore_ascii_case(extension))
+        })
+}
+
+fn fill_inferred<T>(
+    value: &mut Option<T>,
+    inferred_value: Option<T>,
+    field: PathField,
+    inferred_fields: &mut Vec<String>,
+) {
+    if value.is_none() && inferred_value.is_some() {
+        *value = inferred_value;
+        inferred_fields.push(field.name().to_owned());
+    }
+}
+
+/// Names the codec of the file from its format. MP4 files carry either AAC or ALAC, and only
+/// the lossless ALAC streams report a bit depth.
+fn codec_name(tagged_file: &TaggedFile) -> &'static str {
+    match tagged_file.file_type() {
+        FileType::Aac => "AAC",
+        FileType::Aiff => "PCM",
+        FileType::Ape => "APE",
+        FileType::Flac => "FLAC",
+        FileType::Mpeg => "MP3",
+        FileType::Mp4 => match tagged_file.properties().bit_depth() {
+            Some(_) => "ALAC",
+            None => "AAC",
+        },
+        FileType::Mpc => "Musepack",
+        FileType::Opus => "Opus",
+        FileType::Vorbis => "Vorbis",
+        FileType::Speex => "Speex",
+        FileType::Wav => "PCM",
+        FileType::WavPack => "WavPack",
+        _ => "Unknown",
+    }
+}
+
+/// Merges every tag of the file into one, so fields missing from the primary tag (or a missing
+/// primary tag, e.g. an MP3 with only ID3v1 or a FLAC with only ID3v2) are taken from the other
+/// tags in `TAG_PRIORITY` order. Returns `None` if the file has no tag at all.
+fn merge_tags(tagged_file: &TaggedFile) -> Option<Tag> {
+    let primary_tag_type = tagged_file.primary_tag_type();
+    let mut tags: Vec<&Tag> = tagged_file.tags().iter().collect();
+    tags.sort_by_key(|tag| {
+        if tag.tag_type() == primary_tag_type {
+            0
+        } else {
+            TAG_PRIORITY
+                .iter()
+                .position(|tag_type| *tag_type == tag.tag_type())
+                .map_or(TAG_PRIORITY.len() + 1, |position| position + 1)
+        }
+    });
+
+    let mut tags = tags.into_iter();
+    let mut merged_tag = tags.next()?.clone();
+
+    for tag in tags {
+        let existing_keys: Vec<ItemKey> =
+            merged_tag.items().map(|item| item.key().clone()).collect();
+        for item in tag.items() {
+            if !existing_keys.contains(item.key()) {
+                merged_tag.push_unchecked(item.clone());
+            }
+        }
+
+        if merged_tag.picture_count() == 0 {
+            for picture in tag.pictures() {
+                merged_tag.push_picture(picture.clone());
+            }
+        }
+    }
+
+    Some(merged_tag)
+}
+
+/// Returns the values to split into individual artists: the multi-valued `ARTISTS` tag when
+/// present, otherwise every artist value of the tag, falling back to the artist of the track.
+fn artist_tag_values(tag: Option<&Tag>, artist: &str) -> Vec<String> {
+    let artists_values: Vec<String> = tag
+        .map(|tag| {
+            tag.items()
+                .filter(|item| is_artists_key(item.key()))
+                .filter_map(|item| item.value().text())
+                .map(|value| value.to_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    if !artists_values.is_empty() {
+        return artists_values;
+    }
+
+    let artist_values: Vec<String> = tag
+        .map(|tag| {
+            tag.get_strings(&ItemKey::TrackArtist)
+                .map(|value| value.to_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    if artist_values.len() > 1 {
+        return artist_values;
+    }
+
+    vec![artist.to_owned()]
+}
+
+/// The `ARTISTS` tag has no `ItemKey` of its own, so it is found by the key it is stored
+/// under: `ARTISTS` in Vorbis comments, APE tags and ID3v2 `TXXX` frames, and
+/// `----:com.apple.iTunes:ARTISTS` in MP4 files.
+fn is_artists_key(key: &ItemKey) -> bool {
+    match key {
+        ItemKey::Unknown(key) => key
+            .rsplit(':')
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case("ARTISTS")),
+        _ => false,
+    }
+}
+
+/// Returns the modification time (in seconds since the Unix epoch) and the size of a file,
+/// used to tell whether a track has to be re-read on library refresh.
+fn read_file_state(path: &Path) -> (Option<i64>, Option<i64>) {
+    match std::fs::metadata(path) {
+        Ok(metadata) => {
+            let file_mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64);
+            (file_mtime, Some(metadata.len() as i64))
+        }
+        Err(_) => (None, None),
+    }
+}
+
+/// The scan state of an audio file. The CUE sheet next to it counts as part of the file, so
+/// editing the sheet makes a refresh re-read the file too.
+fn read_track_file_state(path: &Path) -> (Option<i64>, Option<i64>) {
+    let (file_mtime, file_size) = read_file_state(path);
+    let cue_mtime = cue_sheet::find_for(path).and_then(|cue_path| read_file_state(&cue_path).0);
+
+    (file_mtime.max(cue_mtime), file_size)
+}
+
+fn load_tracks_from_entry_batch(
+    entry_batch: &Vec<DirEntry>,
+    directory: &PersistentDirectory,
+    options: &ScanOptions,
+) -> Result<(Vec<FsTrack>, Vec<ScanFailure>)> {
+    let track_results: Vec<(&DirEntry, Result<Vec<FsTrack>>)> = entry_batch
         .par_iter()
-        .map(|file| FsTrack::new_from_path(file.path()))
+        .map(|file| (file, FsTrack::load_from_path(file.path(), options)))
         .collect();
 
     let mut tracks: Vec<FsTrack> = vec![];
+    let mut failures: Vec<ScanFailure> = vec![];
 
-    for track_result in track_results {
+    for (file, track_result) in track_results {
         match track_result {
-            Ok(track) => {
-                tracks.push(track);
-            }
+            Ok(file_tracks) => tracks.extend(
+                file_tracks
+                    .into_iter()
+                    .filter(|track| is_long_enough(directory, track)),
+            ),
             Err(error) => {
                 println!("{}", error);
+                failures.push(ScanFailure::new(file.path().display().to_string(), &error));
             }
         }
     }
 
-    Ok(tracks)
+    Ok((tracks, failures))
 }
 
+/// Scans the directories into an empty or partially initialized library. Files that are
+/// already in the library (or failed to scan) and directories that were completed by an
+/// interrupted scan are skipped, so a scan resumes where it left off. The scan can be
+/// cancelled between batches, keeping what was scanned so far.
 pub fn load_tracks_from_directories(
-    directories: &Vec<String>,
+    directories: &[PersistentDirectory],
     conn: &mut Connection,
     app_handle: AppHandle,
 ) -> Result<()> {
     let now = Instant::now();
-    let files_count = count_files_from_directories(directories)?;
+    let options = ScanOptions::load(conn)?;
+    let completed_directories = db::get_completed_scan_directories(conn)?;
+    let scanned_paths = db::get_scanned_file_paths(conn)?;
+    let mut files_count: usize = 0;
+    for directory in directories.iter() {
+        files_count += match completed_directories.get(&directory.path) {
+            Some(directory_files_count) => *directory_files_count,
+            None => count_files_from_directories(slice::from_ref(directory))?,
+        };
+    }
     println!("Files count: {}", files_count);
     let mut files_scanned: usize = 0;
     for directory in directories.iter() {
+        if let Some(directory_files_scanned) = completed_directories.get(&directory.path) {
+            files_scanned += directory_files_scanned;
+            continue;
+        }
+
+        let matcher = DirectoryMatcher::for_directory(directory)?;
+        let mut directory_files_scanned: usize = 0;
         let mut entry_batch: Vec<DirEntry> = vec![];
-        let globwalker = glob(format!(
-            "{}/**/*.{{mp3,m4a,flac,ogg,opus,wav,MP3,M4A,FLAC,OGG,OPUS,WAV}}",
-            directory
-        ))?;
-        for item in globwalker {
+        let walker = matcher.walk(Path::new(&directory.path), directory.follow_symlinks);
+        for item in walker {
             let entry = item?;
+            if scanned_paths.contains(&entry.path().display().to_string()) {
+                files_scanned += 1;
+                directory_files_scanned += 1;
+                continue;
+            }
+
             entry_batch.push(entry);
             if entry_batch.len() == 100 {
-                let tracks = load_tracks_from_entry_batch(&entry_batch)?;
+                let (tracks, failures) =
+                    load_tracks_from_entry_batch(&entry_batch, directory, &options)?;
 
                 db::add_tracks(&tracks, conn)?;
+                db::add_scan_failures(&failures, conn)?;
                 files_scanned += entry_batch.len();
+                directory_files_scanned += entry_batch.len();
+                db::save_scan_checkpoint(&directory.path, directory_files_scanned, false, conn)?;
                 app_handle
                     .emit(
                         "initialize-progress",
@@ -278,11 +1000,15 @@ pub fn load_tracks_from_directories(
                     )
                     .unwrap();
                 entry_batch.clear();
+                check_scan_cancelled(&app_handle)?;
             }
         }
-        let tracks = load_tracks_from_entry_batch(&entry_batch)?;
+        let (tracks, failures) = load_tracks_from_entry_batch(&entry_batch, directory, &options)?;
         db::add_tracks(&tracks, conn)?;
+        db::add_scan_failures(&failures, conn)?;
         files_scanned += entry_batch.len();
+        directory_files_scanned += entry_batch.len();
+        db::save_scan_checkpoint(&directory.path, directory_files_scanned, true, conn)?;
         app_handle
             .emit(
                 "initialize-progress",
@@ -293,21 +1019,337 @@ pub fn load_tracks_from_directories(
                 },
             )
             .unwrap();
+        check_scan_cancelled(&app_handle)?;
     }
     println!("==> Scanning tracks take: {}ms", now.elapsed().as_millis());
 
     Ok(())
 }
 
-pub fn count_files_from_directories(directories: &Vec<String>) -> Result<usize> {
+/// Pairs the tracks of a refresh batch with the rows they update: the row with the same path
+/// and CUE sheet track number, or else the row of a moved file with the same content hash.
+/// Claimed rows are added to `seen_ids` so no two files take over the same row.
+fn pair_with_existing_tracks(
+    tracks: Vec<FsTrack>,
+    existing_tracks: &HashMap<String, Vec<db::TrackFileState>>,
+    tracks_by_content_hash: &HashMap<String, Vec<(i64, String)>>,
+    seen_ids: &mut HashSet<i64>,
+    options: &ScanOptions,
+) -> Vec<(Option<i64>, FsTrack)> {
+    tracks
+        .into_iter()
+        .map(|track| {
+            if let Some(file_tracks) = existing_tracks.get(&track.file_path()) {
+                let existing_id = file_tracks
+                    .iter()
+                    .find(|existing_track| {
+                        existing_track.cue_track_number == track.cue_track_number()
+                    })
+                    .map(|existing_track| existing_track.id);
+                if let Some(existing_id) = existing_id {
+                    seen_ids.insert(existing_id);
+                }
+                return (existing_id, track);
+            }
+
+            let candidates: Vec<(i64, String)> = track
+                .content_hash()
+                .and_then(|content_hash| tracks_by_content_hash.get(&content_hash))
+                .map(|candidates| {
+                    candidates
+                        .iter()
+                        .filter(|(id, _)| !seen_ids.contains(id))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            let (existing_id, track) = resolve_moved_track(track, &candidates, options);
+            if let Some(existing_id) = existing_id {
+                seen_ids.insert(existing_id);
+            }
+            (existing_id, track)
+        })
+        .collect()
+}
+
+/// Pairs the tracks read from a file with the rows that were read from it before, matching
+/// them by CUE sheet track number.
+pub fn pair_with_file_tracks(
+    tracks: Vec<FsTrack>,
+    existing_tracks: &[(i64, Option<u32>)],
+) -> Vec<(Option<i64>, FsTrack)> {
+    tracks
+        .into_iter()
+        .map(|track| {
+            let existing_id = existing_tracks
+                .iter()
+                .find(|(_, cue_track_number)| *cue_track_number == track.cue_track_number())
+                .map(|(id, _)| *id);
+            (existing_id, track)
+        })
+        .collect()
+}
+
+fn check_scan_cancelled(app_handle: &AppHandle) -> Result<()> {
+    let app_state: State<AppState> = app_handle.state();
+    if app_state.scan_cancelled.load(Ordering::SeqCst) {
+        return Err(ScanError::Cancelled.into());
+    }
+
+    Ok(())
+}
+
+pub fn count_files_from_directories(directories: &[PersistentDirectory]) -> Result<usize> {
     let mut files_count = 0;
     for directory in directories.iter() {
-        let files_in_dir = glob(format!(
-            "{}/**/*.{{mp3,m4a,flac,ogg,opus,wav,MP3,M4A,FLAC,OGG,OPUS,WAV}}",
-            directory
-        ))?;
-        files_count += files_in_dir.into_iter().count();
+        let matcher = DirectoryMatcher::for_directory(directory)?;
+        files_count += matcher
+            .walk(Path::new(&directory.path), directory.follow_symlinks)
+            .count();
     }
 
     Ok(files_count)
 }
+
+/// Brings the library in sync with the directories without rebuilding it: new files are
+/// inserted, changed files are re-read in place and vanished files are deleted. Unchanged
+/// tracks keep their rows and ids, and so do moved or renamed files, which are recognized by
+/// their content hash. A cancelled refresh keeps the batches applied so far but
+/// doesn't delete anything.
+pub fn refresh_tracks_from_directories(
+    directories: &[PersistentDirectory],
+    conn: &mut Connection,
+    app_handle: AppHandle,
+) -> Result<()> {
+    let now = Instant::now();
+    let existing_tracks = db::get_track_file_states(conn)?;
+    let mut tracks_by_content_hash: HashMap<String, Vec<(i64, String)>> = HashMap::new();
+    for (file_path, file_tracks) in existing_tracks.iter() {
+        for existing_track in file_tracks.iter() {
+            if let Some(content_hash) = &existing_track.content_hash {
+                tracks_by_content_hash
+                    .entry(content_hash.to_owned())
+                    .or_default()
+                    .push((existing_track.id, file_path.to_owned()));
+            }
+        }
+    }
+    db::clean_scan_failures(conn)?;
+    let options = ScanOptions::load(conn)?;
+    // Lyrics changed on disk since the last scan are recorded as one batch of revisions
+    let batch_id = db::new_lyrics_batch_id()?;
+    let files_count = count_files_from_directories(directories)?;
+    println!("Files count: {}", files_count);
+    let mut files_scanned: usize = 0;
+    let mut seen_ids: HashSet<i64> = HashSet::new();
+    for directory in directories.iter() {
+        let mut entry_batch: Vec<DirEntry> = vec![];
+        let matcher = DirectoryMatcher::for_directory(directory)?;
+        let walker = matcher.walk(Path::new(&directory.path), directory.follow_symlinks);
+        for item in walker {
+            let entry = item?;
+            let file_path = entry.path().display().to_string();
+
+            if let Some(file_tracks) = existing_tracks.get(&file_path) {
+                let (file_mtime, file_size) = read_track_file_state(entry.path());
+                let has_cue_sheet = cue_sheet::find_for(entry.path()).is_some();
+                let is_unchanged = file_tracks.iter().all(|existing_track| {
+                    existing_track.file_mtime.is_some()
+                        && existing_track.file_mtime == file_mtime
+                        && existing_track.file_size == file_size
+                        && existing_track.cue_track_number.is_some() == has_cue_sheet
+                });
+                if is_unchanged {
+                    seen_ids.extend(file_tracks.iter().map(|existing_track| existing_track.id));
+                    files_scanned += 1;
+                    continue;
+                }
+            }
+
+            entry_batch.push(entry);
+            if entry_batch.len() == 100 {
+                let (tracks, failures) =
+                    load_tracks_from_entry_batch(&entry_batch, directory, &options)?;
+                let tracks = pair_with_existing_tracks(
+                    tracks,
+                    &existing_tracks,
+                    &tracks_by_content_hash,
+                    &mut seen_ids,
+                    &options,
+                );
+                seen_ids.extend(db::upsert_tracks(&tracks, &batch_id, conn)?);
+                db::add_scan_failures(&failures, conn)?;
+                files_scanned += entry_batch.len();
+                app_handle
+                    .emit(
+                        "initialize-progress",
+                        ScanProgress {
+                            progress: None,
+                            files_scanned,
+                            files_count: Some(files_count),
+                        },
+                    )
+                    .unwrap();
+                entry_batch.clear();
+                check_scan_cancelled(&app_handle)?;
+            }
+        }
+        let (tracks, failures) = load_tracks_from_entry_batch(&entry_batch, directory, &options)?;
+        let tracks = pair_with_existing_tracks(
+            tracks,
+            &existing_tracks,
+            &tracks_by_content_hash,
+            &mut seen_ids,
+            &options,
+        );
+        seen_ids.extend(db::upsert_tracks(&tracks, &batch_id, conn)?);
+        db::add_scan_failures(&failures, conn)?;
+        files_scanned += entry_batch.len();
+        app_handle
+            .emit(
+                "initialize-progress",
+                ScanProgress {
+                    progress: None,
+                    files_scanned,
+                    files_count: Some(files_count),
+                },
+            )
+            .unwrap();
+        check_scan_cancelled(&app_handle)?;
+    }
+
+    let vanished_track_ids: Vec<i64> = existing_tracks
+        .values()
+        .flatten()
+        .map(|existing_track| existing_track.id)
+        .filter(|id| !seen_ids.contains(id))
+        .collect();
+    println!("Removing {} vanished tracks", vanished_track_ids.len());
+    db::delete_tracks(&vanished_track_ids, conn)?;
+    let image_paths = db::clean_orphan_albums_and_artists(conn)?;
+    artwork::remove_thumbnails(&image_paths);
+
+    println!(
+        "==> Refreshing tracks take: {}ms",
+        now.elapsed().as_millis()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lofty::config::WriteOptions;
+    use lofty::probe::Probe;
+    use lofty::tag::TagExt;
+    use std::io::Cursor;
+
+    fn tag(tag_type: TagType, title: Option<&str>, artist: Option<&str>) -> Tag {
+        let mut tag = Tag::new(tag_type);
+        if let Some(title) = title {
+            tag.set_title(title.to_owned());
+        }
+        if let Some(artist) = artist {
+            tag.set_artist(artist.to_owned());
+        }
+        tag
+    }
+
+    fn dump(tag: &Tag) -> Vec<u8> {
+        let mut bytes = vec![];
+        tag.dump_to(&mut bytes, WriteOptions::default()).unwrap();
+        bytes
+    }
+
+    /// MPEG-1 Layer III frames at 128 kbps and 44.1 kHz.
+    fn mpeg_frames() -> Vec<u8> {
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x64];
+        frame.resize(417, 0);
+        frame.repeat(10)
+    }
+
+    /// A FLAC stream with only its STREAMINFO block, at 44.1 kHz in 16-bit stereo.
+    fn flac_stream(vorbis_comments: &[(&str, &str)]) -> Vec<u8> {
+        let stream_info_header = if vorbis_comments.is_empty() { 0x80 } else { 0x00 };
+        let mut bytes = b"fLaC".to_vec();
+        bytes.extend([stream_info_header, 0x00, 0x00, 0x22]);
+        bytes.extend([0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+        bytes.extend(((44100u64 << 44) | (1 << 41) | (15 << 36) | 44100).to_be_bytes());
+        bytes.extend([0u8; 16]);
+
+        if !vorbis_comments.is_empty() {
+            let mut block = 0u32.to_le_bytes().to_vec();
+            block.extend((vorbis_comments.len() as u32).to_le_bytes());
+            for (key, value) in vorbis_comments {
+                let comment = format!("{}={}", key, value);
+                block.extend((comment.len() as u32).to_le_bytes());
+                block.extend(comment.bytes());
+            }
+            bytes.extend([0x84, 0x00, (block.len() >> 8) as u8, block.len() as u8]);
+            bytes.extend(block);
+        }
+
+        bytes.extend([0xff, 0xf8, 0x69, 0x08]);
+        bytes.resize(bytes.len() + 1000, 0);
+        bytes
+    }
+
+    fn read(bytes: Vec<u8>) -> TaggedFile {
+        Probe::new(Cursor::new(bytes))
+            .guess_file_type()
+            .unwrap()
+            .read()
+            .unwrap()
+    }
+
+    #[test]
+    fn id3v1_only_mp3_has_a_tag() {
+        let id3v1 = tag(TagType::Id3v1, Some("Title"), Some("Artist"));
+        let tagged_file = read([mpeg_frames(), dump(&id3v1)].concat());
+
+        let merged_tag = merge_tags(&tagged_file).unwrap();
+        assert_eq!(merged_tag.title().as_deref(), Some("Title"));
+        assert_eq!(merged_tag.artist().as_deref(), Some("Artist"));
+    }
+
+    #[test]
+    fn id3v2_only_flac_has_a_tag() {
+        let id3v2 = tag(TagType::Id3v2, Some("Title"), Some("Artist"));
+        let tagged_file = read([dump(&id3v2), flac_stream(&[])].concat());
+
+        assert_eq!(tagged_file.file_type(), FileType::Flac);
+        let merged_tag = merge_tags(&tagged_file).unwrap();
+        assert_eq!(merged_tag.title().as_deref(), Some("Title"));
+        assert_eq!(merged_tag.artist().as_deref(), Some("Artist"));
+    }
+
+    #[test]
+    fn untagged_file_has_no_tag() {
+        assert!(merge_tags(&read(mpeg_frames())).is_none());
+    }
+
+    #[test]
+    fn primary_tag_wins_over_id3v2() {
+        let id3v2 = tag(TagType::Id3v2, Some("ID3v2 title"), Some("ID3v2 artist"));
+        let tagged_file = read([dump(&id3v2), flac_stream(&[("TITLE", "Vorbis title")])].concat());
+
+        let merged_tag = merge_tags(&tagged_file).unwrap();
+        assert_eq!(merged_tag.tag_type(), TagType::VorbisComments);
+        assert_eq!(merged_tag.title().as_deref(), Some("Vorbis title"));
+        assert_eq!(merged_tag.artist().as_deref(), Some("ID3v2 artist"));
+    }
+
+    #[test]
+    fn tags_are_merged_in_priority_order() {
+        let mut tagged_file = read(mpeg_frames());
+        tagged_file.insert_tag(tag(TagType::Id3v1, Some("ID3v1 title"), Some("ID3v1 artist")));
+        tagged_file.insert_tag(tag(TagType::Ape, Some("APE title"), None));
+        tagged_file.insert_tag(tag(TagType::Id3v2, None, None));
+
+        let merged_tag = merge_tags(&tagged_file).unwrap();
+        assert_eq!(merged_tag.tag_type(), TagType::Id3v2);
+        assert_eq!(merged_tag.title().as_deref(), Some("APE title"));
+        assert_eq!(merged_tag.artist().as_deref(), Some("ID3v1 artist"));
+    }
+}
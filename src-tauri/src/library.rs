@@ -1,45 +1,143 @@
-use crate::db;
-use crate::fs_track::{self, FsTrack};
-use crate::persistent_entities::{PersistentAlbum, PersistentArtist, PersistentTrack};
+use crate::artwork;
+use crate::db::{self, DuplicateCandidate, ListOptions, TrackFilters};
+use crate::fs_track::{self, DirectoryMatcher, FsTrack, ScanFailure, ScanOptions};
+use crate::library_query::LibraryQuery;
+use crate::persistent_entities::{
+    PersistentAlbum, PersistentArtist, PersistentDuplicateGroup, PersistentTrack,
+};
+use crate::state::AppState;
 use anyhow::Result;
 use rusqlite::Connection;
-use tauri::AppHandle;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use tauri::{AppHandle, Manager, State};
 
+/// How many seconds the durations of two copies of a recording may differ by default.
+pub const DEFAULT_DUPLICATE_DURATION_TOLERANCE: f64 = 2.0;
+
+/// Scans the library directories into the database. An initialization that was cancelled or
+/// interrupted is resumed instead of starting over.
 pub fn initialize_library(conn: &mut Connection, app_handle: AppHandle) -> Result<()> {
     let init = db::get_init(conn)?;
     if init {
         return Ok(());
     }
 
-    db::clean_library(conn)?;
-
-    let directories = db::get_directories(conn)?;
-    let result = fs_track::load_tracks_from_directories(&directories, conn, app_handle);
+    reset_scan_cancelled(&app_handle);
 
-    match result {
-        Ok(()) => {
-            db::set_init(true, conn)?;
-            Ok(())
-        }
-        Err(err) => {
-            let uninitialization = uninitialize_library(conn);
-            if let Err(uninit_error) = uninitialization {
-                println!(
-                    "Uninitialization library errored. Message: {}",
-                    uninit_error.to_string()
-                );
-            }
-            Err(err)
-        }
+    if db::get_scan_in_progress(conn)? {
+        println!("Resuming the interrupted library initialization");
+    } else {
+        let image_paths = db::clean_library(conn)?;
+        artwork::remove_thumbnails(&image_paths);
+        db::set_scan_in_progress(true, conn)?;
     }
+
+    let directories = db::get_directory_settings(conn)?;
+    fs_track::load_tracks_from_directories(&directories, conn, app_handle.clone())?;
+
+    db::set_init(true, conn)?;
+    db::set_scan_in_progress(false, conn)?;
+    db::clean_scan_checkpoints(conn)?;
+    update_album_artworks(conn, &app_handle);
+
+    Ok(())
 }
 
 pub fn uninitialize_library(conn: &Connection) -> Result<()> {
-    db::clean_library(conn)?;
+    let image_paths = db::clean_library(conn)?;
+    artwork::remove_thumbnails(&image_paths);
     db::set_init(false, conn)?;
+    db::set_scan_in_progress(false, conn)?;
+    Ok(())
+}
+
+pub fn refresh_library(conn: &mut Connection, app_handle: AppHandle) -> Result<()> {
+    let init = db::get_init(conn)?;
+    if !init {
+        return initialize_library(conn, app_handle);
+    }
+
+    reset_scan_cancelled(&app_handle);
+    let directories = db::get_directory_settings(conn)?;
+    fs_track::refresh_tracks_from_directories(&directories, conn, app_handle.clone())?;
+    update_album_artworks(conn, &app_handle);
+
     Ok(())
 }
 
+fn reset_scan_cancelled(app_handle: &AppHandle) {
+    let app_state: State<AppState> = app_handle.state();
+    app_state.scan_cancelled.store(false, Ordering::SeqCst);
+}
+
+/// Missing artwork never fails a scan, so errors are only logged.
+fn update_album_artworks(conn: &mut Connection, app_handle: &AppHandle) {
+    if let Err(error) = artwork::update_album_artworks(conn, app_handle) {
+        println!("Updating album artworks errored. Message: {}", error);
+    }
+}
+
+/// Scans a previously failed file again. On success the tracks of the file are saved and
+/// their ids are returned; otherwise the recorded failure is updated.
+pub fn retry_scan_failure(failure_id: i64, conn: &Connection) -> Result<Vec<i64>> {
+    let failure = db::get_scan_failure_by_id(failure_id, conn)?;
+    let options = ScanOptions::load(conn)?;
+
+    match FsTrack::load_from_path(Path::new(&failure.file_path), &options) {
+        Ok(tracks) => {
+            let existing_tracks = db::get_tracks_by_file_path(&failure.file_path, conn)?;
+            let batch_id = db::new_lyrics_batch_id()?;
+            let mut track_ids: Vec<i64> = vec![];
+            for (existing_id, track) in fs_track::pair_with_file_tracks(tracks, &existing_tracks) {
+                track_ids.push(db::save_scanned_track(
+                    existing_id,
+                    &track,
+                    &batch_id,
+                    conn,
+                )?);
+            }
+            Ok(track_ids)
+        }
+        Err(error) => {
+            db::add_scan_failure(&ScanFailure::new(failure.file_path, &error), conn)?;
+            Err(error)
+        }
+    }
+}
+
+/// Validates and stores the scan settings of a library directory. Extensions are stored
+/// lowercase and without the leading dot.
+pub fn set_directory_settings(
+    path: &str,
+    extensions: Vec<String>,
+    exclude_patterns: Vec<String>,
+    min_duration: Option<f64>,
+    follow_symlinks: bool,
+    conn: &Connection,
+) -> Result<()> {
+    let extensions: Vec<String> = extensions
+        .iter()
+        .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect();
+    let exclude_patterns: Vec<String> = exclude_patterns
+        .iter()
+        .map(|exclude_pattern| exclude_pattern.trim().to_owned())
+        .filter(|exclude_pattern| !exclude_pattern.is_empty())
+        .collect();
+    DirectoryMatcher::new(path, &extensions, &exclude_patterns)?;
+
+    db::set_directory_settings(
+        path,
+        &extensions,
+        &exclude_patterns,
+        min_duration,
+        follow_symlinks,
+        conn,
+    )
+}
+
 pub fn add_tracks(tracks: Vec<FsTrack>, conn: &Connection) -> Result<()> {
     for track in tracks.iter() {
         db::add_track(&track, conn)?;
@@ -47,59 +145,192 @@ pub fn add_tracks(tracks: Vec<FsTrack>, conn: &Connection) -> Result<()> {
     Ok(())
 }
 
-pub fn get_tracks(conn: &Connection) -> Result<Vec<PersistentTrack>> {
-    db::get_tracks(conn)
+pub fn get_tracks(list_options: &ListOptions, conn: &Connection) -> Result<Vec<PersistentTrack>> {
+    db::get_tracks(list_options, conn)
 }
 
-pub fn get_track_ids(search_query: Option<String>, without_plain_lyrics: bool, without_synced_lyrics: bool, conn: &Connection) -> Result<Vec<i64>> {
-    match search_query {
-        Some(search_query) => db::get_search_track_ids(&search_query, conn),
-        None => db::get_track_ids(without_plain_lyrics, without_synced_lyrics, conn),
-    }
+pub fn get_track_ids(
+    search_query: Option<String>,
+    without_plain_lyrics: bool,
+    without_synced_lyrics: bool,
+    filters: &TrackFilters,
+    list_options: &ListOptions,
+    conn: &Connection,
+) -> Result<Vec<i64>> {
+    db::get_track_ids(
+        &parse_search_query(search_query)?,
+        without_plain_lyrics,
+        without_synced_lyrics,
+        filters,
+        list_options,
+        conn,
+    )
+}
+
+fn parse_search_query(search_query: Option<String>) -> Result<LibraryQuery> {
+    LibraryQuery::parse(search_query.as_deref().unwrap_or_default())
 }
 
 pub fn get_track(id: i64, conn: &Connection) -> Result<PersistentTrack> {
     db::get_track_by_id(id, conn)
 }
 
-pub fn get_albums(conn: &Connection) -> Result<Vec<PersistentAlbum>> {
-    db::get_albums(conn)
+pub fn get_albums(list_options: &ListOptions, conn: &Connection) -> Result<Vec<PersistentAlbum>> {
+    db::get_albums(list_options, conn)
 }
 
-pub fn get_album_ids(conn: &Connection) -> Result<Vec<i64>> {
-    db::get_album_ids(conn)
+pub fn get_album_ids(
+    search_query: Option<String>,
+    list_options: &ListOptions,
+    conn: &Connection,
+) -> Result<Vec<i64>> {
+    db::get_album_ids(&parse_search_query(search_query)?, list_options, conn)
 }
 
 pub fn get_album(id: i64, conn: &Connection) -> Result<PersistentAlbum> {
     db::get_album_by_id(id, conn)
 }
 
-pub fn get_artists(conn: &Connection) -> Result<Vec<PersistentArtist>> {
-    db::get_artists(conn)
+pub fn get_artists(list_options: &ListOptions, conn: &Connection) -> Result<Vec<PersistentArtist>> {
+    db::get_artists(list_options, conn)
 }
 
-pub fn get_artist_ids(conn: &Connection) -> Result<Vec<i64>> {
-    db::get_artist_ids(conn)
+pub fn get_artist_ids(
+    search_query: Option<String>,
+    list_options: &ListOptions,
+    conn: &Connection,
+) -> Result<Vec<i64>> {
+    db::get_artist_ids(&parse_search_query(search_query)?, list_options, conn)
 }
 
 pub fn get_artist(id: i64, conn: &Connection) -> Result<PersistentArtist> {
     db::get_artist_by_id(id, conn)
 }
 
-pub fn get_album_tracks(album_id: i64, conn: &Connection) -> Result<Vec<PersistentTrack>> {
-    db::get_album_tracks(album_id, conn)
+pub fn get_album_tracks(
+    album_id: i64,
+    list_options: &ListOptions,
+    conn: &Connection,
+) -> Result<Vec<PersistentTrack>> {
+    db::get_album_tracks(album_id, list_options, conn)
+}
+
+pub fn get_artist_tracks(
+    artist_id: i64,
+    list_options: &ListOptions,
+    conn: &Connection,
+) -> Result<Vec<PersistentTrack>> {
+    db::get_artist_tracks(artist_id, list_options, conn)
 }
 
-pub fn get_artist_tracks(artist_id: i64, conn: &Connection) -> Result<Vec<PersistentTrack>> {
-    db::get_artist_tracks(artist_id, conn)
+pub fn get_album_track_ids(
+    album_id: i64,
+    search_query: Option<String>,
+    without_plain_lyrics: bool,
+    without_synced_lyrics: bool,
+    filters: &TrackFilters,
+    list_options: &ListOptions,
+    conn: &Connection,
+) -> Result<Vec<i64>> {
+    db::get_album_track_ids(
+        album_id,
+        &parse_search_query(search_query)?,
+        without_plain_lyrics,
+        without_synced_lyrics,
+        filters,
+        list_options,
+        conn,
+    )
 }
 
-pub fn get_album_track_ids(album_id: i64, without_plain_lyrics: bool, without_synced_lyrics: bool, conn: &Connection) -> Result<Vec<i64>> {
-    db::get_album_track_ids(album_id, without_plain_lyrics, without_synced_lyrics, conn)
+pub fn get_artist_track_ids(
+    artist_id: i64,
+    search_query: Option<String>,
+    without_plain_lyrics: bool,
+    without_synced_lyrics: bool,
+    filters: &TrackFilters,
+    list_options: &ListOptions,
+    conn: &Connection,
+) -> Result<Vec<i64>> {
+    db::get_artist_track_ids(
+        artist_id,
+        &parse_search_query(search_query)?,
+        without_plain_lyrics,
+        without_synced_lyrics,
+        filters,
+        list_options,
+        conn,
+    )
 }
 
-pub fn get_artist_track_ids(artist_id: i64, without_plain_lyrics: bool, without_synced_lyrics: bool, conn: &Connection) -> Result<Vec<i64>> {
-    db::get_artist_track_ids(artist_id, without_plain_lyrics, without_synced_lyrics, conn)
+/// Groups the tracks that are likely copies of the same recording: their titles and artists
+/// match after `prepare_input` normalization and their durations are within
+/// `duration_tolerance` seconds of the shortest one. With `match_audio_hash`, the copies must
+/// also have the same audio content hash. Only groups of two or more tracks are returned.
+pub fn find_duplicate_tracks(
+    duration_tolerance: f64,
+    match_audio_hash: bool,
+    conn: &Connection,
+) -> Result<Vec<PersistentDuplicateGroup>> {
+    let candidates = db::get_duplicate_candidates(conn)?;
+    let mut clusters: Vec<Vec<DuplicateCandidate>> = vec![];
+
+    // Candidates come sorted by title, artist and duration, so each cluster only has to be
+    // compared with the candidate that follows it
+    for candidate in candidates {
+        match clusters.last_mut() {
+            Some(cluster)
+                if cluster[0].title_lower == candidate.title_lower
+                    && cluster[0].artist_name_lower == candidate.artist_name_lower
+                    && candidate.track.duration - cluster[0].track.duration
+                        <= duration_tolerance =>
+            {
+                cluster.push(candidate)
+            }
+            _ => clusters.push(vec![candidate]),
+        }
+    }
+
+    let mut groups: Vec<PersistentDuplicateGroup> = vec![];
+    for cluster in clusters.into_iter().filter(|cluster| cluster.len() > 1) {
+        if !match_audio_hash {
+            groups.push(build_duplicate_group(cluster));
+            continue;
+        }
+
+        let mut clusters_by_hash: Vec<Vec<DuplicateCandidate>> = vec![];
+        for candidate in cluster {
+            if candidate.content_hash.is_none() {
+                continue;
+            }
+            match clusters_by_hash
+                .iter_mut()
+                .find(|hash_cluster| hash_cluster[0].content_hash == candidate.content_hash)
+            {
+                Some(hash_cluster) => hash_cluster.push(candidate),
+                None => clusters_by_hash.push(vec![candidate]),
+            }
+        }
+        groups.extend(
+            clusters_by_hash
+                .into_iter()
+                .filter(|hash_cluster| hash_cluster.len() > 1)
+                .map(build_duplicate_group),
+        );
+    }
+
+    Ok(groups)
+}
+
+fn build_duplicate_group(cluster: Vec<DuplicateCandidate>) -> PersistentDuplicateGroup {
+    PersistentDuplicateGroup {
+        title: cluster[0].track.title.to_owned(),
+        artist_name: cluster[0].track.artist_name.to_owned(),
+        tracks: cluster
+            .into_iter()
+            .map(|candidate| candidate.track)
+            .collect(),
+    }
 }
 
 pub fn get_init(conn: &Connection) -> Result<bool> {
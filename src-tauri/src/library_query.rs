@@ -0,0 +1,469 @@
+use crate::utils::prepare_input;
+use anyhow::Result;
+use indoc::indoc;
+use rusqlite::types::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LibraryQueryError {
+    #[error("Invalid value `{1}` for `{0}:`")]
+    InvalidValue(String, String),
+}
+
+/// A library search parsed from the query syntax of the search box. Words and quoted phrases
+/// are searched in the titles, artists and albums, and `key:value` terms filter on a field:
+///
+/// - `title:`, `artist:`, `album:`, `albumartist:`, `genre:` and `path:` match part of the
+///   field, e.g. `artist:"Radiohead"`
+/// - `codec:` matches the codec name, e.g. `codec:flac`
+/// - `year:`, `duration:` (seconds or `m:ss`) and `bitrate:` compare numbers, e.g.
+///   `duration:>300`, `year:1990..1999`
+/// - `has:plain`, `has:synced` and `has:lyrics` require lyrics of that kind
+/// - `instrumental:yes` or `instrumental:no`
+///
+/// Any term can be negated with a leading `-`, e.g. `-has:synced`. Unknown keys are searched
+/// as plain text.
+#[derive(Debug, Default)]
+pub struct LibraryQuery {
+    terms: Vec<Term>,
+}
+
+#[derive(Debug)]
+struct Term {
+    negated: bool,
+    kind: TermKind,
+}
+
+#[derive(Debug)]
+enum TermKind {
+    Word(String),
+    Phrase(String),
+    Contains(TextField, String),
+    Codec(String),
+    Compare(NumberField, Comparison),
+    HasPlainLyrics,
+    HasSyncedLyrics,
+    HasLyrics,
+    Instrumental(bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TextField {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Path,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NumberField {
+    Year,
+    Duration,
+    Bitrate,
+}
+
+#[derive(Debug)]
+enum Comparison {
+    Equal(f64),
+    Greater(f64),
+    GreaterOrEqual(f64),
+    Less(f64),
+    LessOrEqual(f64),
+    Between(f64, f64),
+}
+
+impl LibraryQuery {
+    pub fn parse(query: &str) -> Result<LibraryQuery> {
+        let mut terms: Vec<Term> = vec![];
+
+        for (token, quoted) in split_tokens(query) {
+            let negated = token.len() > 1 && token.starts_with('-');
+            let token = if negated {
+                token[1..].to_owned()
+            } else {
+                token
+            };
+            let kind = if quoted {
+                TermKind::Phrase(token)
+            } else {
+                match token.split_once(':') {
+                    Some((key, value)) if !value.is_empty() => parse_field_term(key, value)?
+                        .unwrap_or_else(|| TermKind::Word(token.to_owned())),
+                    _ => TermKind::Word(token.to_owned()),
+                }
+            };
+
+            terms.push(Term { negated, kind });
+        }
+
+        Ok(LibraryQuery { terms })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// The FTS5 query of the words and phrases to search for in `tracks_fts`, if any.
+    pub fn match_query(&self) -> Option<String> {
+        build_match_query(self.terms.iter().filter(|term| !term.negated))
+    }
+
+    /// Builds the ` AND ...` conditions of the field terms and negated words, along with their
+    /// parameters. The words to search for are left to `match_query`.
+    pub fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut conditions = String::new();
+        let mut values: Vec<Value> = vec![];
+
+        for term in self.terms.iter() {
+            let (condition, term_values) = match &term.kind {
+                TermKind::Word(_) | TermKind::Phrase(_) if !term.negated => continue,
+                TermKind::Word(_) | TermKind::Phrase(_) => match build_match_query([term]) {
+                    Some(match_query) => (
+                        "tracks.id IN (SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?)"
+                            .to_owned(),
+                        vec![Value::from(match_query)],
+                    ),
+                    None => continue,
+                },
+                TermKind::Contains(field, text) => contains_condition(*field, text),
+                TermKind::Codec(codec) => (
+                    "tracks.codec = ? COLLATE NOCASE".to_owned(),
+                    vec![Value::from(codec.to_owned())],
+                ),
+                TermKind::Compare(field, comparison) => comparison.to_sql(field.column()),
+                TermKind::HasPlainLyrics => ("tracks.txt_lyrics IS NOT NULL".to_owned(), vec![]),
+                TermKind::HasSyncedLyrics => (
+                    "tracks.lrc_lyrics IS NOT NULL AND tracks.instrumental = false".to_owned(),
+                    vec![],
+                ),
+                TermKind::HasLyrics => (
+                    "(tracks.txt_lyrics IS NOT NULL OR tracks.lrc_lyrics IS NOT NULL)".to_owned(),
+                    vec![],
+                ),
+                TermKind::Instrumental(instrumental) => (
+                    "tracks.instrumental = ?".to_owned(),
+                    vec![Value::from(*instrumental)],
+                ),
+            };
+
+            // Unknown values count as not matching, so negated terms keep them
+            if term.negated {
+                conditions.push_str(&format!(" AND NOT IFNULL(({}), 0)", condition));
+            } else {
+                conditions.push_str(&format!(" AND ({})", condition));
+            }
+            values.extend(term_values);
+        }
+
+        (conditions, values)
+    }
+}
+
+impl TextField {
+    fn parse(key: &str) -> Option<TextField> {
+        match key {
+            "title" => Some(TextField::Title),
+            "artist" => Some(TextField::Artist),
+            "album" => Some(TextField::Album),
+            "albumartist" => Some(TextField::AlbumArtist),
+            "genre" => Some(TextField::Genre),
+            "path" => Some(TextField::Path),
+            _ => None,
+        }
+    }
+}
+
+impl NumberField {
+    fn parse(key: &str) -> Option<NumberField> {
+        match key {
+            "year" => Some(NumberField::Year),
+            "duration" => Some(NumberField::Duration),
+            "bitrate" => Some(NumberField::Bitrate),
+            _ => None,
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            NumberField::Year => "tracks.year",
+            NumberField::Duration => "tracks.duration",
+            NumberField::Bitrate => "tracks.bitrate",
+        }
+    }
+
+    fn parse_number(&self, value: &str) -> Option<f64> {
+        match (self, value.split_once(':')) {
+            (NumberField::Duration, Some((minutes, seconds))) => {
+                Some(minutes.parse::<f64>().ok()? * 60.0 + seconds.parse::<f64>().ok()?)
+            }
+            _ => value.parse().ok(),
+        }
+    }
+}
+
+impl Comparison {
+    fn parse(field: NumberField, value: &str) -> Option<Comparison> {
+        let number = |value: &str| field.parse_number(value);
+
+        if let Some((from, to)) = value.split_once("..") {
+            Some(Comparison::Between(number(from)?, number(to)?))
+        } else if let Some(value) = value.strip_prefix(">=") {
+            Some(Comparison::GreaterOrEqual(number(value)?))
+        } else if let Some(value) = value.strip_prefix("<=") {
+            Some(Comparison::LessOrEqual(number(value)?))
+        } else if let Some(value) = value.strip_prefix('>') {
+            Some(Comparison::Greater(number(value)?))
+        } else if let Some(value) = value.strip_prefix('<') {
+            Some(Comparison::Less(number(value)?))
+        } else {
+            Some(Comparison::Equal(number(value.trim_start_matches('='))?))
+        }
+    }
+
+    fn to_sql(&self, column: &str) -> (String, Vec<Value>) {
+        match self {
+            Comparison::Equal(number) => (format!("{} = ?", column), vec![Value::from(*number)]),
+            Comparison::Greater(number) => (format!("{} > ?", column), vec![Value::from(*number)]),
+            Comparison::GreaterOrEqual(number) => {
+                (format!("{} >= ?", column), vec![Value::from(*number)])
+            }
+            Comparison::Less(number) => (format!("{} < ?", column), vec![Value::from(*number)]),
+            Comparison::LessOrEqual(number) => {
+                (format!("{} <= ?", column), vec![Value::from(*number)])
+            }
+            Comparison::Between(from, to) => (
+                format!("{} BETWEEN ? AND ?", column),
+                vec![Value::from(*from), Value::from(*to)],
+            ),
+        }
+    }
+}
+
+/// Parses a `key:value` term. Returns `None` if the key isn't a known field.
+fn parse_field_term(key: &str, value: &str) -> Result<Option<TermKind>> {
+    let key = key.to_lowercase();
+    let invalid_value = || LibraryQueryError::InvalidValue(key.to_owned(), value.to_owned());
+
+    if let Some(field) = TextField::parse(&key) {
+        return Ok(Some(TermKind::Contains(field, value.to_owned())));
+    }
+    if let Some(field) = NumberField::parse(&key) {
+        let comparison = Comparison::parse(field, value).ok_or_else(invalid_value)?;
+        return Ok(Some(TermKind::Compare(field, comparison)));
+    }
+
+    let kind = match (key.as_str(), value.to_lowercase().as_str()) {
+        ("codec", _) => TermKind::Codec(value.to_owned()),
+        ("has", "plain") => TermKind::HasPlainLyrics,
+        ("has", "synced") => TermKind::HasSyncedLyrics,
+        ("has", "lyrics") => TermKind::HasLyrics,
+        ("instrumental", "yes" | "true") => TermKind::Instrumental(true),
+        ("instrumental", "no" | "false") => TermKind::Instrumental(false),
+        ("has" | "instrumental", _) => return Err(invalid_value().into()),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(kind))
+}
+
+fn contains_condition(field: TextField, text: &str) -> (String, Vec<Value>) {
+    // The `*_lower` columns hold `prepare_input` output, which has no LIKE wildcards left
+    let normalized = Value::from(format!("%{}%", prepare_input(text)));
+    let escaped = Value::from(format!(
+        "%{}%",
+        text.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    ));
+
+    match field {
+        TextField::Title => ("tracks.title_lower LIKE ?".to_owned(), vec![normalized]),
+        TextField::Artist => (
+            indoc! {"
+            tracks.artist_id IN (SELECT id FROM artists WHERE name_lower LIKE ?)
+            OR tracks.id IN (
+              SELECT track_artists.track_id FROM track_artists
+              JOIN artists ON track_artists.artist_id = artists.id
+              WHERE artists.name_lower LIKE ?
+            )"}
+            .to_owned(),
+            vec![normalized.clone(), normalized],
+        ),
+        TextField::Album => (
+            "tracks.album_id IN (SELECT id FROM albums WHERE name_lower LIKE ?)".to_owned(),
+            vec![normalized],
+        ),
+        TextField::AlbumArtist => (
+            "tracks.album_id IN (SELECT id FROM albums WHERE album_artist_name_lower LIKE ?)"
+                .to_owned(),
+            vec![normalized],
+        ),
+        TextField::Genre => ("tracks.genre LIKE ? ESCAPE '\\'".to_owned(), vec![escaped]),
+        TextField::Path => (
+            "tracks.file_path LIKE ? ESCAPE '\\'".to_owned(),
+            vec![escaped],
+        ),
+    }
+}
+
+/// Joins the words and phrases of the terms into an FTS5 query. Words match as prefixes,
+/// phrases as whole words in order.
+fn build_match_query<'a>(terms: impl IntoIterator<Item = &'a Term>) -> Option<String> {
+    let mut match_terms: Vec<String> = vec![];
+
+    for term in terms {
+        match &term.kind {
+            TermKind::Word(word) => match_terms.extend(
+                prepare_input(word)
+                    .split_whitespace()
+                    .map(|word| format!("\"{}\"*", word)),
+            ),
+            TermKind::Phrase(phrase) => {
+                let phrase = prepare_input(phrase);
+                if !phrase.trim().is_empty() {
+                    match_terms.push(format!("\"{}\"", phrase.trim()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if match_terms.is_empty() {
+        None
+    } else {
+        Some(match_terms.join(" "))
+    }
+}
+
+/// Splits the query on whitespace, keeping double-quoted parts together. A token that is
+/// entirely quoted is flagged so it can be searched as a phrase. An unterminated quote runs
+/// to the end of the query.
+fn split_tokens(query: &str) -> Vec<(String, bool)> {
+    let mut tokens: Vec<(String, bool)> = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut quoted = false;
+
+    for character in query.chars().chain([' ']) {
+        match character {
+            '"' => {
+                in_quotes = !in_quotes;
+                quoted = current.is_empty() || current == "-" || quoted;
+            }
+            character if character.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push((current.to_owned(), quoted));
+                }
+                current.clear();
+                quoted = false;
+            }
+            character => current.push(character),
+        }
+    }
+    if !current.trim().is_empty() {
+        tokens.push((current.trim().to_owned(), quoted));
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_sql(query: &str) -> (String, Vec<Value>) {
+        LibraryQuery::parse(query).unwrap().to_sql()
+    }
+
+    #[test]
+    fn quoted_field_value_is_kept_together() {
+        let (conditions, values) = to_sql(r#"artist:"Massive Attack""#);
+
+        assert!(conditions.starts_with(" AND (tracks.artist_id IN"));
+        assert_eq!(
+            values,
+            vec![
+                Value::from("%massive attack%".to_owned()),
+                Value::from("%massive attack%".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn negated_term_keeps_unknown_values() {
+        let (conditions, values) = to_sql("-has:synced");
+
+        assert_eq!(
+            conditions,
+            " AND NOT IFNULL((tracks.lrc_lyrics IS NOT NULL AND tracks.instrumental = false), 0)"
+        );
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    fn duration_accepts_minutes_and_seconds() {
+        let (conditions, values) = to_sql("duration:>3:00");
+
+        assert_eq!(conditions, " AND (tracks.duration > ?)");
+        assert_eq!(values, vec![Value::from(180.0)]);
+    }
+
+    #[test]
+    fn year_range_is_inclusive() {
+        let (conditions, values) = to_sql("year:1990..1999");
+
+        assert_eq!(conditions, " AND (tracks.year BETWEEN ? AND ?)");
+        assert_eq!(values, vec![Value::from(1990.0), Value::from(1999.0)]);
+    }
+
+    #[test]
+    fn words_and_phrases_go_to_the_match_query() {
+        let query = LibraryQuery::parse(r#"karma "paranoid android" -creep"#).unwrap();
+        let (conditions, values) = query.to_sql();
+
+        assert_eq!(
+            query.match_query().as_deref(),
+            Some(r#""karma"* "paranoid android""#)
+        );
+        assert_eq!(
+            conditions,
+            " AND NOT IFNULL((tracks.id IN (SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?)), 0)"
+        );
+        assert_eq!(values, vec![Value::from(r#""creep"*"#.to_owned())]);
+    }
+
+    #[test]
+    fn unknown_key_is_searched_as_text() {
+        let query = LibraryQuery::parse("mood:calm").unwrap();
+
+        assert_eq!(query.match_query().as_deref(), Some(r#""mood"* "calm"*"#));
+        assert_eq!(query.to_sql().0, "");
+    }
+
+    #[test]
+    fn invalid_values_fail() {
+        for query in [
+            "year:abc",
+            "duration:>x:10",
+            "year:1990..",
+            "has:cover",
+            "instrumental:maybe",
+        ] {
+            assert!(LibraryQuery::parse(query).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn unterminated_quote_runs_to_the_end() {
+        let query = LibraryQuery::parse(r#"album:"OK Computer"#).unwrap();
+        assert_eq!(
+            query.to_sql().1,
+            vec![Value::from("%ok computer%".to_owned())]
+        );
+
+        let query = LibraryQuery::parse(r#""no surprises"#).unwrap();
+        assert_eq!(query.match_query().as_deref(), Some(r#""no surprises""#));
+    }
+}
@@ -9,6 +9,7 @@ use thiserror::Error;
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RawResponse {
+    pub id: Option<i64>,
     pub plain_lyrics: Option<String>,
     pub synced_lyrics: Option<String>,
     instrumental: bool,
@@ -55,6 +56,39 @@ impl Response {
     }
 }
 
+/// How a lookup went, as kept in the log of download attempts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LookupOutcome {
+    Found,
+    NotFound,
+    NetworkError,
+    ServerError,
+}
+
+impl LookupOutcome {
+    pub fn from_result(result: &Result<(Response, Option<i64>)>) -> LookupOutcome {
+        match result {
+            Ok((Response::None, _)) => LookupOutcome::NotFound,
+            Ok(_) => LookupOutcome::Found,
+            Err(error) => match error.downcast_ref::<reqwest::Error>() {
+                Some(error) if error.is_connect() || error.is_timeout() || error.is_request() => {
+                    LookupOutcome::NetworkError
+                }
+                _ => LookupOutcome::ServerError,
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LookupOutcome::Found => "found",
+            LookupOutcome::NotFound => "not_found",
+            LookupOutcome::NetworkError => "network_error",
+            LookupOutcome::ServerError => "server_error",
+        }
+    }
+}
+
 #[derive(Error, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[error("{error}: {message}")]
@@ -140,23 +174,25 @@ pub async fn request_raw(
     }
 }
 
+/// Returns the lyrics along with the id of the LRCLIB record they come from.
 pub async fn request(
     title: &str,
     album_name: &str,
     artist_name: &str,
     duration: f64,
     lrclib_instance: &str,
-) -> Result<Response> {
+) -> Result<(Response, Option<i64>)> {
     let res = make_request(title, album_name, artist_name, duration, lrclib_instance).await?;
 
     match res.status() {
         reqwest::StatusCode::OK => {
             let lrclib_response = res.json::<RawResponse>().await?;
+            let lrclib_id = lrclib_response.id;
 
-            Ok(Response::from_raw_response(lrclib_response))
+            Ok((Response::from_raw_response(lrclib_response), lrclib_id))
         }
 
-        reqwest::StatusCode::NOT_FOUND => Ok(Response::None),
+        reqwest::StatusCode::NOT_FOUND => Ok((Response::None, None)),
 
         reqwest::StatusCode::BAD_REQUEST
         | reqwest::StatusCode::SERVICE_UNAVAILABLE
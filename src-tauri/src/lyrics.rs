@@ -1,45 +1,63 @@
-use crate::lrclib::get::request;
 use crate::lrclib::get::Response;
 use crate::persistent_entities::PersistentTrack;
 use anyhow::Result;
 use lofty::{
     config::{ParseOptions, WriteOptions},
-    file::AudioFile,
+    file::{AudioFile, TaggedFile, TaggedFileExt},
     flac::FlacFile,
     id3::v2::{
         BinaryFrame, Frame, FrameId, Id3v2Tag, SyncTextContentType, SynchronizedTextFrame,
         TimestampFormat, UnsynchronizedTextFrame,
     },
+    iff::{aiff::AiffFile, wav::WavFile},
     mpeg::MpegFile,
+    read_from_path,
+    tag::{ItemKey, Tag, TagExt},
     TextEncoding,
 };
 use lrc::Lyrics;
-use std::fs::{remove_file, write, OpenOptions};
+use regex::Regex;
+use std::fs::{remove_file, write, File, OpenOptions};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::LazyLock;
 use thiserror::Error;
 
+/// Matches a line starting with an LRC timestamp such as `[01:23.45]`.
+static LRC_LINE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\[\d+:\d+(\.\d+)?\]").unwrap());
+
 #[derive(Error, Clone, Debug)]
 pub enum GetLyricsError {
     #[error("This track does not exist in LRCLIB database")]
     NotFound,
 }
 
-pub async fn download_lyrics_for_track(
-    track: PersistentTrack,
-    is_try_embed_lyrics: bool,
-    lrclib_instance: &str,
-) -> Result<Response> {
-    let lyrics = request(
-        &track.title,
-        &track.album_name,
-        &track.artist_name,
-        track.duration,
-        lrclib_instance,
-    )
-    .await?;
+/// Lyrics found in the tags of an audio file.
+#[derive(Default, Debug)]
+pub struct EmbeddedLyrics {
+    pub plain_lyrics: Option<String>,
+    pub synced_lyrics: Option<String>,
+}
 
-    apply_lyrics_for_track(track, lyrics, is_try_embed_lyrics).await
+impl EmbeddedLyrics {
+    pub fn is_empty(&self) -> bool {
+        self.plain_lyrics.is_none() && self.synced_lyrics.is_none()
+    }
+
+    /// Files the text as synced lyrics if it has LRC timestamps, as plain lyrics otherwise.
+    /// The first value found for each kind wins.
+    fn add(&mut self, text: &str) {
+        if text.trim().is_empty() {
+            return;
+        }
+
+        if LRC_LINE_REGEX.is_match(text) {
+            self.synced_lyrics.get_or_insert_with(|| text.to_owned());
+        } else {
+            self.plain_lyrics.get_or_insert_with(|| text.to_owned());
+        }
+    }
 }
 
 pub async fn apply_string_lyrics_for_track(
@@ -48,8 +66,10 @@ pub async fn apply_string_lyrics_for_track(
     synced_lyrics: &str,
     is_try_embed_lyrics: bool,
 ) -> Result<()> {
-    save_plain_lyrics(&track.file_path, plain_lyrics)?;
-    save_synced_lyrics(&track.file_path, synced_lyrics)?;
+    // The tags of a CUE sheet rip belong to the whole album, not to its virtual tracks
+    let is_try_embed_lyrics = is_try_embed_lyrics && track.cue_track_number.is_none();
+    save_plain_lyrics(track, plain_lyrics)?;
+    save_synced_lyrics(track, synced_lyrics)?;
 
     if is_try_embed_lyrics {
         embed_lyrics(&track.file_path, &plain_lyrics, &synced_lyrics);
@@ -63,32 +83,34 @@ pub async fn apply_lyrics_for_track(
     lyrics: Response,
     is_try_embed_lyrics: bool,
 ) -> Result<Response> {
+    // The tags of a CUE sheet rip belong to the whole album, not to its virtual tracks
+    let is_try_embed_lyrics = is_try_embed_lyrics && track.cue_track_number.is_none();
     match &lyrics {
         Response::SyncedLyrics(synced_lyrics, plain_lyrics) => {
-            save_synced_lyrics(&track.file_path, &synced_lyrics)?;
+            save_synced_lyrics(&track, &synced_lyrics)?;
             if is_try_embed_lyrics {
                 embed_lyrics(&track.file_path, &plain_lyrics, &synced_lyrics);
             }
             Ok(lyrics)
         }
         Response::UnsyncedLyrics(plain_lyrics) => {
-            save_plain_lyrics(&track.file_path, &plain_lyrics)?;
+            save_plain_lyrics(&track, &plain_lyrics)?;
             if is_try_embed_lyrics {
                 embed_lyrics(&track.file_path, &plain_lyrics, "");
             }
             Ok(lyrics)
         }
         Response::IsInstrumental => {
-            save_instrumental(&track.file_path)?;
+            save_instrumental(&track)?;
             Ok(lyrics)
         }
         _ => Ok(lyrics),
     }
 }
 
-fn save_plain_lyrics(track_path: &str, lyrics: &str) -> Result<()> {
-    let txt_path = build_txt_path(track_path)?;
-    let lrc_path = build_lrc_path(track_path)?;
+fn save_plain_lyrics(track: &PersistentTrack, lyrics: &str) -> Result<()> {
+    let txt_path = build_txt_path(track)?;
+    let lrc_path = build_lrc_path(track)?;
 
     let _ = remove_file(lrc_path);
 
@@ -100,9 +122,9 @@ fn save_plain_lyrics(track_path: &str, lyrics: &str) -> Result<()> {
     Ok(())
 }
 
-fn save_synced_lyrics(track_path: &str, lyrics: &str) -> Result<()> {
-    let txt_path = build_txt_path(track_path)?;
-    let lrc_path = build_lrc_path(track_path)?;
+fn save_synced_lyrics(track: &PersistentTrack, lyrics: &str) -> Result<()> {
+    let txt_path = build_txt_path(track)?;
+    let lrc_path = build_lrc_path(track)?;
     if lyrics.is_empty() {
         let _ = remove_file(lrc_path);
     } else {
@@ -112,9 +134,9 @@ fn save_synced_lyrics(track_path: &str, lyrics: &str) -> Result<()> {
     Ok(())
 }
 
-fn save_instrumental(track_path: &str) -> Result<()> {
-    let txt_path = build_txt_path(track_path)?;
-    let lrc_path = build_lrc_path(track_path)?;
+fn save_instrumental(track: &PersistentTrack) -> Result<()> {
+    let txt_path = build_txt_path(track)?;
+    let lrc_path = build_lrc_path(track)?;
 
     let _ = remove_file(&lrc_path);
     let _ = remove_file(txt_path);
@@ -124,38 +146,147 @@ fn save_instrumental(track_path: &str) -> Result<()> {
     Ok(())
 }
 
-fn build_txt_path(track_path: &str) -> Result<PathBuf> {
-    let path = Path::new(track_path);
-    let parent_path = path.parent().unwrap();
-    let file_name_without_extension = path.file_stem().unwrap().to_str().unwrap();
-    let txt_path =
-        Path::new(parent_path).join(format!("{}.{}", file_name_without_extension, "txt"));
+fn build_txt_path(track: &PersistentTrack) -> Result<PathBuf> {
+    Ok(build_sidecar_path(
+        &track.file_path,
+        track.cue_track_number,
+        "txt",
+    ))
+}
 
-    Ok(txt_path)
+fn build_lrc_path(track: &PersistentTrack) -> Result<PathBuf> {
+    Ok(build_sidecar_path(
+        &track.file_path,
+        track.cue_track_number,
+        "lrc",
+    ))
 }
 
-fn build_lrc_path(track_path: &str) -> Result<PathBuf> {
+/// Builds the path of a lyrics sidecar (`lrc` or `txt`). Sidecars sit next to the audio file
+/// and share its file stem: `Song.flac` gets `Song.lrc` and `Song.txt`. The virtual tracks of
+/// a CUE sheet share one audio file, so their sidecars add the two-digit track number of the
+/// sheet: track 3 of `Album.flac` gets `Album.03.lrc` and `Album.03.txt`.
+pub fn build_sidecar_path(
+    track_path: &str,
+    cue_track_number: Option<i64>,
+    extension: &str,
+) -> PathBuf {
     let path = Path::new(track_path);
     let parent_path = path.parent().unwrap();
     let file_name_without_extension = path.file_stem().unwrap().to_str().unwrap();
-    let lrc_path =
-        Path::new(parent_path).join(format!("{}.{}", file_name_without_extension, "lrc"));
 
-    Ok(lrc_path)
+    match cue_track_number {
+        Some(cue_track_number) => parent_path.join(format!(
+            "{}.{:02}.{}",
+            file_name_without_extension, cue_track_number, extension
+        )),
+        None => parent_path.join(format!("{}.{}", file_name_without_extension, extension)),
+    }
 }
 
 fn embed_lyrics(track_path: &str, plain_lyrics: &str, synced_lyrics: &str) {
-    if track_path.to_lowercase().ends_with(".mp3") {
-        match embed_lyrics_mp3(track_path, plain_lyrics, synced_lyrics) {
-            Ok(_) => (),
-            Err(e) => println!("Error embedding lyrics in MP3: {}", e),
+    let extension = Path::new(track_path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let result = match extension.as_str() {
+        "mp3" => embed_lyrics_mp3(track_path, plain_lyrics, synced_lyrics),
+        "flac" => embed_lyrics_flac(track_path, plain_lyrics, synced_lyrics),
+        "aiff" | "aif" => embed_lyrics_aiff(track_path, plain_lyrics, synced_lyrics),
+        "wav" => embed_lyrics_wav(track_path, plain_lyrics, synced_lyrics),
+        "ape" | "wv" | "mpc" | "m4a" | "m4b" | "ogg" | "opus" => {
+            embed_lyrics_primary_tag(track_path, plain_lyrics, synced_lyrics)
+        }
+        _ => return,
+    };
+
+    if let Err(e) = result {
+        println!(
+            "Error embedding lyrics in {}: {}",
+            extension.to_uppercase(),
+            e
+        );
+    }
+}
+
+/// Reads the lyrics embedded in the tags of a track: ID3v2 `SYLT` (converted to LRC) and
+/// `USLT` frames, Vorbis `LYRICS`/`UNSYNCEDLYRICS` comments, MP4 `©lyr` atoms and APEv2
+/// `Lyrics` items.
+pub fn read_embedded_lyrics(track_path: &Path, tagged_file: &TaggedFile) -> EmbeddedLyrics {
+    let mut lyrics = EmbeddedLyrics::default();
+    let extension = track_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match read_id3v2_tag(track_path, &extension) {
+        Ok(Some(id3v2)) => {
+            if let Some(synced_lyrics) = read_id3v2_sylt_lyrics(&id3v2) {
+                lyrics.add(&synced_lyrics);
+            }
         }
-    } else if track_path.to_lowercase().ends_with(".flac") {
-        match embed_lyrics_flac(track_path, plain_lyrics, synced_lyrics) {
-            Ok(_) => (),
-            Err(e) => println!("Error embedding lyrics in FLAC: {}", e),
+        Ok(None) => (),
+        Err(e) => println!("Error reading ID3v2 tag of {}: {}", track_path.display(), e),
+    }
+
+    let unsynced_lyrics_key = ItemKey::Unknown("UNSYNCEDLYRICS".to_string());
+    for tag in tagged_file.tags() {
+        for key in [&ItemKey::Lyrics, &unsynced_lyrics_key] {
+            for text in tag.get_strings(key) {
+                lyrics.add(text);
+            }
         }
     }
+
+    lyrics
+}
+
+/// Reads the ID3v2 tag of the formats that can carry `SYLT` frames, which the generic tag
+/// doesn't expose.
+fn read_id3v2_tag(track_path: &Path, extension: &str) -> Result<Option<Id3v2Tag>> {
+    let parse_options = ParseOptions::new().read_properties(false);
+    let id3v2 = match extension {
+        "mp3" => {
+            let mut file_content = File::open(track_path)?;
+            MpegFile::read_from(&mut file_content, parse_options)?
+                .id3v2()
+                .cloned()
+        }
+        "aiff" | "aif" => {
+            let mut file_content = File::open(track_path)?;
+            AiffFile::read_from(&mut file_content, parse_options)?
+                .id3v2()
+                .cloned()
+        }
+        "wav" => {
+            let mut file_content = File::open(track_path)?;
+            WavFile::read_from(&mut file_content, parse_options)?
+                .id3v2()
+                .cloned()
+        }
+        _ => None,
+    };
+
+    Ok(id3v2)
+}
+
+fn read_id3v2_sylt_lyrics(id3v2: &Id3v2Tag) -> Option<String> {
+    let frame = id3v2.get(&FrameId::new("SYLT").ok()?)?;
+    let Frame::Binary(binary_frame) = frame else {
+        return None;
+    };
+    let sylt_frame = SynchronizedTextFrame::parse(&binary_frame.data, frame.flags()).ok()?;
+
+    // Timestamps in MPEG frames can't be converted without the frame rate of the file
+    let is_ms = matches!(sylt_frame.timestamp_format, TimestampFormat::MS);
+    if !is_ms || sylt_frame.content.is_empty() {
+        return None;
+    }
+
+    Some(sylt_vec_to_synced_lyrics(&sylt_frame.content))
 }
 
 fn embed_lyrics_flac(track_path: &str, plain_lyrics: &str, synced_lyrics: &str) -> Result<()> {
@@ -195,6 +326,69 @@ fn embed_lyrics_mp3(track_path: &str, plain_lyrics: &str, synced_lyrics: &str) -
     Ok(())
 }
 
+fn embed_lyrics_aiff(track_path: &str, plain_lyrics: &str, synced_lyrics: &str) -> Result<()> {
+    let mut file_content = OpenOptions::new().read(true).write(true).open(track_path)?;
+    let mut aiff_file = AiffFile::read_from(&mut file_content, ParseOptions::new())?;
+
+    if let Some(id3v2) = aiff_file.id3v2_mut() {
+        insert_id3v2_uslt_frame(id3v2, plain_lyrics)?;
+        insert_id3v2_sylt_frame(id3v2, synced_lyrics)?;
+
+        aiff_file.save_to_path(track_path, WriteOptions::default())?;
+    }
+
+    Ok(())
+}
+
+fn embed_lyrics_wav(track_path: &str, plain_lyrics: &str, synced_lyrics: &str) -> Result<()> {
+    let mut file_content = OpenOptions::new().read(true).write(true).open(track_path)?;
+    let mut wav_file = WavFile::read_from(&mut file_content, ParseOptions::new())?;
+
+    if let Some(id3v2) = wav_file.id3v2_mut() {
+        insert_id3v2_uslt_frame(id3v2, plain_lyrics)?;
+        insert_id3v2_sylt_frame(id3v2, synced_lyrics)?;
+
+        wav_file.save_to_path(track_path, WriteOptions::default())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the lyrics to the single lyrics field of the file's native tag: APEv2 `Lyrics` for
+/// Monkey's Audio, WavPack and Musepack, `©lyr` for MP4 and `LYRICS` for Ogg files. Synced
+/// lyrics are preferred when there are both.
+fn embed_lyrics_primary_tag(
+    track_path: &str,
+    plain_lyrics: &str,
+    synced_lyrics: &str,
+) -> Result<()> {
+    let mut tagged_file = read_from_path(track_path)?;
+
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+
+    let tag = tagged_file
+        .primary_tag_mut()
+        .expect("The primary tag should exist after inserting it");
+    let lyrics = if !synced_lyrics.is_empty() {
+        synced_lyrics
+    } else {
+        plain_lyrics
+    };
+
+    if lyrics.is_empty() {
+        tag.remove_key(&ItemKey::Lyrics);
+    } else {
+        tag.insert_text(ItemKey::Lyrics, lyrics.to_string());
+    }
+
+    tag.save_to_path(track_path, WriteOptions::default())?;
+
+    Ok(())
+}
+
 fn insert_id3v2_uslt_frame(id3v2: &mut Id3v2Tag, plain_lyrics: &str) -> Result<()> {
     if !plain_lyrics.is_empty() {
         let uslt_frame = UnsynchronizedTextFrame::new(
@@ -237,6 +431,22 @@ fn insert_id3v2_sylt_frame(id3v2: &mut Id3v2Tag, synced_lyrics: &str) -> Result<
     Ok(())
 }
 
+fn sylt_vec_to_synced_lyrics(sylt_vec: &[(u32, String)]) -> String {
+    sylt_vec
+        .iter()
+        .map(|(timestamp, text)| {
+            format!(
+                "[{:02}:{:02}.{:02}]{}",
+                timestamp / 60_000,
+                timestamp / 1000 % 60,
+                timestamp % 1000 / 10,
+                text
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 fn synced_lyrics_to_sylt_vec(synced_lyrics: &str) -> Result<Vec<(u32, String)>> {
     let lyrics = Lyrics::from_str(synced_lyrics)?;
     let lyrics_vec = lyrics.get_timed_lines();
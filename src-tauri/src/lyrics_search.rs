@@ -0,0 +1,287 @@
+use crate::db::{self, LyricsSearchCandidate};
+use crate::persistent_entities::PersistentLyricsMatch;
+use anyhow::Result;
+use regex::Regex;
+use rusqlite::Connection;
+use secular::lower_lay_string;
+use std::sync::LazyLock;
+
+/// Separates the lines of a multi-line match in a snippet.
+const LINE_SEPARATOR: &str = " / ";
+
+/// Matches a `[...]` tag at the start of an LRC line.
+static TAG_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*\[([^\]]*)\]").unwrap());
+/// Matches the `mm:ss.xx` content of a timestamp tag.
+static TIMESTAMP_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\d+):(\d+(?:\.\d+)?)$").unwrap());
+
+/// A word of the lyrics text, with its byte range in the text.
+struct Word {
+    text: String,
+    start: usize,
+    end: usize,
+}
+
+/// Finds the tracks whose lyrics contain the query as a phrase, ignoring case, accents,
+/// punctuation and line breaks, with the last word matching as a prefix. Synced lyrics are
+/// searched without their timestamps, and report the timestamp of the line where the match
+/// starts.
+pub fn search(query: &str, limit: usize, conn: &Connection) -> Result<Vec<PersistentLyricsMatch>> {
+    let query_words: Vec<String> = split_words(query)
+        .into_iter()
+        .map(|word| word.text)
+        .collect();
+    if query_words.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // The index only narrows down the candidates, the phrase is matched below
+    let match_query = split_words(&query.to_lowercase())
+        .iter()
+        .map(|word| format!("\"{}\"*", word.text.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ");
+    db::find_lyrics_search_matches(
+        &match_query,
+        limit,
+        |candidate| match_candidate(candidate, &query_words),
+        conn,
+    )
+}
+
+fn match_candidate(
+    candidate: &LyricsSearchCandidate,
+    query_words: &[String],
+) -> Option<PersistentLyricsMatch> {
+    let synced_lines = candidate.lrc_lyrics.as_deref().map(parse_synced_lines);
+    let plain_lines = candidate.txt_lyrics.as_deref().map(|lyrics| {
+        lyrics
+            .lines()
+            .map(|line| (None, line.to_owned()))
+            .collect::<Vec<(Option<f64>, String)>>()
+    });
+
+    let (before, matched, after, timestamp) = synced_lines
+        .into_iter()
+        .chain(plain_lines)
+        .find_map(|lines| match_lines(&lines, query_words))?;
+
+    Some(PersistentLyricsMatch {
+        track_id: candidate.id,
+        title: candidate.title.to_owned(),
+        artist_name: candidate.artist_name.to_owned(),
+        album_name: candidate.album_name.to_owned(),
+        snippet_before: before,
+        snippet_match: matched,
+        snippet_after: after,
+        timestamp,
+    })
+}
+
+/// Looks for the phrase in the lines, and returns the text before, of and after the match
+/// within its lines, along with the timestamp of the line where the match starts.
+fn match_lines(
+    lines: &[(Option<f64>, String)],
+    query_words: &[String],
+) -> Option<(String, String, String, Option<f64>)> {
+    let text = lines
+        .iter()
+        .map(|(_, line)| line.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let words = split_words(&text);
+
+    let (last_query_word, first_query_words) = query_words.split_last()?;
+    let position = words.windows(query_words.len()).position(|window| {
+        window
+            .iter()
+            .zip(first_query_words)
+            .all(|(word, query_word)| word.text == *query_word)
+            && window[query_words.len() - 1]
+                .text
+                .starts_with(last_query_word.as_str())
+    })?;
+
+    let match_start = words[position].start;
+    let match_end = words[position + query_words.len() - 1].end;
+    let line_start = text[..match_start].rfind('\n').map_or(0, |index| index + 1);
+    let line_end = text[match_end..]
+        .find('\n')
+        .map_or(text.len(), |index| match_end + index);
+    let line_index = text[..match_start].matches('\n').count();
+
+    Some((
+        text[line_start..match_start].replace('\n', LINE_SEPARATOR),
+        text[match_start..match_end].replace('\n', LINE_SEPARATOR),
+        text[match_end..line_end].replace('\n', LINE_SEPARATOR),
+        lines[line_index].0,
+    ))
+}
+
+/// The text of LRC lyrics without timestamps and metadata lines, as the search index holds it.
+pub fn synced_lyrics_text(lyrics: &str) -> String {
+    parse_synced_lines(lyrics)
+        .into_iter()
+        .map(|(_, line)| line)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Splits LRC lyrics into timed lines. A line with several timestamps is kept at the first
+/// one, and metadata lines such as `[ar: ...]` are dropped.
+fn parse_synced_lines(lyrics: &str) -> Vec<(Option<f64>, String)> {
+    let mut lines: Vec<(Option<f64>, String)> = vec![];
+
+    for line in lyrics.lines() {
+        let mut rest = line;
+        let mut timestamp: Option<f64> = None;
+        let mut has_tags = false;
+
+        while let Some(captures) = TAG_REGEX.captures(rest) {
+            has_tags = true;
+            if let Some(time) = TIMESTAMP_REGEX.captures(captures[1].trim()) {
+                let minutes: f64 = time[1].parse().unwrap_or_default();
+                let seconds: f64 = time[2].parse().unwrap_or_default();
+                timestamp.get_or_insert(minutes * 60.0 + seconds);
+            }
+            rest = &rest[captures[0].len()..];
+        }
+
+        if !has_tags || timestamp.is_some() {
+            lines.push((timestamp, rest.trim().to_owned()));
+        }
+    }
+
+    lines
+}
+
+/// Splits text into lowercase words without accents, keeping where each word is in the text.
+fn split_words(text: &str) -> Vec<Word> {
+    let mut words: Vec<Word> = vec![];
+    let mut word_start: Option<usize> = None;
+
+    for (index, character) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (character.is_alphanumeric(), word_start) {
+            (true, None) => word_start = Some(index),
+            (false, Some(start)) => {
+                words.push(Word {
+                    text: lower_lay_string(&text[start..index]),
+                    start,
+                    end: index,
+                });
+                word_start = None;
+            }
+            _ => (),
+        }
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        split_words(text)
+            .into_iter()
+            .map(|word| word.text)
+            .collect()
+    }
+
+    fn lines(lyrics: &[&str]) -> Vec<(Option<f64>, String)> {
+        lyrics.iter().map(|line| (None, line.to_string())).collect()
+    }
+
+    #[test]
+    fn split_words_ignores_case_accents_and_punctuation() {
+        assert_eq!(
+            words("Ça, c'est L'AMOUR!"),
+            vec!["ca", "c", "est", "l", "amour"]
+        );
+
+        let text = "  héllo, world";
+        let split = split_words(text);
+        assert_eq!(&text[split[0].start..split[0].end], "héllo");
+        assert_eq!(&text[split[1].start..split[1].end], "world");
+    }
+
+    #[test]
+    fn parse_synced_lines_keeps_the_first_timestamp() {
+        let lyrics = "[00:01.00][01:30.50]Chorus line\n[00:12.34] Verse line";
+
+        assert_eq!(
+            parse_synced_lines(lyrics),
+            vec![
+                (Some(1.0), "Chorus line".to_owned()),
+                (Some(12.34), "Verse line".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_synced_lines_drops_metadata_lines() {
+        let lyrics = "[ar: Artist]\n[ti:Title]\n[length: 03:20]\n[00:05.00]First line\nNo tags";
+
+        assert_eq!(
+            parse_synced_lines(lyrics),
+            vec![
+                (Some(5.0), "First line".to_owned()),
+                (None, "No tags".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn synced_lyrics_text_has_no_timestamps() {
+        let lyrics = "[ar: Artist]\n[00:01.00][00:30.00]Hello there\n[00:05.50]General Kenobi";
+
+        assert_eq!(synced_lyrics_text(lyrics), "Hello there\nGeneral Kenobi");
+    }
+
+    #[test]
+    fn match_lines_finds_the_phrase_with_a_prefix() {
+        let lines = lines(&["We will, we will rock you"]);
+        let query = words("will rock y");
+
+        assert_eq!(
+            match_lines(&lines, &query),
+            Some((
+                "We will, we ".to_owned(),
+                "will rock you".to_owned(),
+                "".to_owned(),
+                None
+            ))
+        );
+        assert_eq!(match_lines(&lines, &words("rock we")), None);
+    }
+
+    #[test]
+    fn match_lines_matches_across_lines() {
+        let lines = lines(&["Is this the real life?", "Is this just fantasy?"]);
+        let query = words("real life is this");
+
+        assert_eq!(
+            match_lines(&lines, &query),
+            Some((
+                "Is this the ".to_owned(),
+                "real life? / Is this".to_owned(),
+                " just fantasy?".to_owned(),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn match_lines_reports_the_timestamp_of_the_first_line() {
+        let lines = parse_synced_lines("[00:10.00]Open your eyes\n[00:15.00]Look up to the skies");
+        let query = words("eyes look");
+
+        let (_, matched, _, timestamp) = match_lines(&lines, &query).unwrap();
+        assert_eq!(matched, "eyes / Look");
+        assert_eq!(timestamp, Some(10.0));
+
+        let (_, _, _, timestamp) = match_lines(&lines, &words("skies")).unwrap();
+        assert_eq!(timestamp, Some(15.0));
+    }
+}
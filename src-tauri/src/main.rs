@@ -3,23 +3,40 @@
     windows_subsystem = "windows"
 )]
 
+pub mod artist_splitter;
+pub mod artwork;
+pub mod content_hash;
+pub mod cue_sheet;
 pub mod db;
+pub mod db_backup;
+pub mod db_pool;
 pub mod fs_track;
 pub mod library;
+pub mod library_query;
 pub mod lrclib;
 pub mod lyrics;
+pub mod lyrics_search;
+pub mod path_template;
 pub mod persistent_entities;
 pub mod player;
 pub mod state;
 pub mod utils;
-
-use persistent_entities::{PersistentAlbum, PersistentArtist, PersistentConfig, PersistentTrack};
+pub mod watcher;
+
+use db::{ListOptions, LyricsChange, LyricsChangeSource, TrackFilters};
+use persistent_entities::{
+    PersistentAlbum, PersistentArtist, PersistentConfig, PersistentDatabaseBackup,
+    PersistentDirectory, PersistentDuplicateGroup, PersistentLibraryStatistics,
+    PersistentLyricsMatch, PersistentLyricsRevision, PersistentScanFailureGroup, PersistentTrack,
+};
 use player::Player;
 use regex::Regex;
 use rusqlite::Connection;
 use serde::Serialize;
 use state::{AppState, ServiceAccess};
+use std::sync::atomic::Ordering;
 use tauri::{AppHandle, Manager, State, Emitter};
+use tauri_plugin_dialog::{DialogExt, MessageDialogKind};
 
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -39,8 +56,7 @@ struct FlagLyricsProgress {
 
 #[tauri::command]
 async fn get_directories(app_state: State<'_, AppState>) -> Result<Vec<String>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &app_state.db_reader();
     let directories = db::get_directories(conn);
     match directories {
         Ok(directories) => Ok(directories),
@@ -52,21 +68,90 @@ async fn get_directories(app_state: State<'_, AppState>) -> Result<Vec<String>,
 }
 
 #[tauri::command]
-async fn set_directories(
-    directories: Vec<String>,
-    app_state: State<'_, AppState>,
+async fn set_directories(directories: Vec<String>, app_handle: AppHandle) -> Result<(), String> {
+    app_handle
+        .db_mut(|db| db::set_directories(directories, db))
+        .map_err(|err| err.to_string())?;
+
+    if watcher::is_running(&app_handle) {
+        watcher::start(&app_handle).map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_directory_settings(app_handle: AppHandle) -> Result<Vec<PersistentDirectory>, String> {
+    app_handle
+        .db(db::get_directory_settings)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn set_directory_settings(
+    path: String,
+    extensions: Vec<String>,
+    exclude_patterns: Vec<String>,
+    min_duration: Option<f64>,
+    follow_symlinks: bool,
+    app_handle: AppHandle,
 ) -> Result<(), String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    db::set_directories(directories, conn).map_err(|err| err.to_string())?;
+    app_handle
+        .db_mut(|db| {
+            library::set_directory_settings(
+                &path,
+                extensions,
+                exclude_patterns,
+                min_duration,
+                follow_symlinks,
+                db,
+            )
+        })
+        .map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_path_templates(app_handle: AppHandle) -> Result<Vec<String>, String> {
+    app_handle
+        .db(db::get_path_templates)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn set_path_templates(templates: Vec<String>, app_handle: AppHandle) -> Result<(), String> {
+    for template in templates.iter() {
+        path_template::PathTemplate::parse(template).map_err(|err| err.to_string())?;
+    }
+
+    app_handle
+        .db_mut(|db| db::set_path_templates(templates, db))
+        .map_err(|err| err.to_string())?;
 
     Ok(())
 }
 
+#[tauri::command]
+async fn get_artist_separators(app_handle: AppHandle) -> Result<Vec<String>, String> {
+    app_handle
+        .db(db::get_artist_separators)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn set_artist_separators(
+    separators: Vec<String>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    app_handle
+        .db_mut(|db| db::set_artist_separators(separators, db))
+        .map_err(|err| err.to_string())
+}
+
 #[tauri::command]
 async fn get_init(app_state: State<'_, AppState>) -> Result<bool, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &app_state.db_reader();
     let init = library::get_init(conn).map_err(|err| err.to_string())?;
 
     Ok(init)
@@ -74,8 +159,7 @@ async fn get_init(app_state: State<'_, AppState>) -> Result<bool, String> {
 
 #[tauri::command]
 async fn get_config(app_state: State<'_, AppState>) -> Result<PersistentConfig, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &app_state.db_reader();
     let config = db::get_config(conn).map_err(|err| err.to_string())?;
 
     Ok(config)
@@ -88,16 +172,17 @@ async fn set_config(
     try_embed_lyrics: bool,
     theme_mode: &str,
     lrclib_instance: &str,
+    retry_not_found_days: u32,
     app_state: State<'_, AppState>,
 ) -> Result<(), String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &mut app_state.db_writer();
     db::set_config(
         skip_tracks_with_synced_lyrics,
         skip_tracks_with_plain_lyrics,
         try_embed_lyrics,
         theme_mode,
         lrclib_instance,
+        retry_not_found_days,
         conn,
     )
     .map_err(|err| err.to_string())?;
@@ -105,22 +190,42 @@ async fn set_config(
     Ok(())
 }
 
+#[tauri::command]
+async fn set_watch_library(watch_library: bool, app_handle: AppHandle) -> Result<(), String> {
+    app_handle
+        .db_mut(|db| db::set_watch_library(watch_library, db))
+        .map_err(|err| err.to_string())?;
+
+    if watch_library {
+        watcher::start(&app_handle).map_err(|err| err.to_string())?;
+    } else {
+        watcher::stop(&app_handle);
+    }
+
+    Ok(())
+}
+
 #[tauri::command]
 async fn initialize_library(
     app_state: State<'_, AppState>,
     app_handle: AppHandle,
 ) -> Result<(), String> {
-    let mut conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_mut().unwrap();
+    let conn = &mut app_state.db_writer();
     library::initialize_library(conn, app_handle).map_err(|err| err.to_string())?;
 
     Ok(())
 }
 
+#[tauri::command]
+async fn cancel_scan(app_state: State<'_, AppState>) -> Result<(), String> {
+    app_state.scan_cancelled.store(true, Ordering::SeqCst);
+
+    Ok(())
+}
+
 #[tauri::command]
 async fn uninitialize_library(app_state: State<'_, AppState>) -> Result<(), String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &mut app_state.db_writer();
 
     library::uninitialize_library(conn).map_err(|err| err.to_string())?;
 
@@ -132,20 +237,88 @@ async fn refresh_library(
     app_state: State<'_, AppState>,
     app_handle: AppHandle,
 ) -> Result<(), String> {
-    let mut conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_mut().unwrap();
+    let conn = &mut app_state.db_writer();
 
-    library::uninitialize_library(conn).map_err(|err| err.to_string())?;
-    library::initialize_library(conn, app_handle).map_err(|err| err.to_string())?;
+    library::refresh_library(conn, app_handle).map_err(|err| err.to_string())?;
 
     Ok(())
 }
 
 #[tauri::command]
-async fn get_tracks(app_state: State<'_, AppState>) -> Result<Vec<PersistentTrack>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let tracks = library::get_tracks(conn).map_err(|err| err.to_string())?;
+async fn get_scan_failures(
+    app_handle: AppHandle,
+) -> Result<Vec<PersistentScanFailureGroup>, String> {
+    app_handle
+        .db(db::get_scan_failure_groups)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn retry_scan_failure(failure_id: i64, app_handle: AppHandle) -> Result<Vec<i64>, String> {
+    let track_ids = app_handle
+        .db_mut(|db| library::retry_scan_failure(failure_id, db))
+        .map_err(|err| err.to_string())?;
+    for track_id in track_ids.iter() {
+        app_handle.emit("reload-track-id", track_id).unwrap();
+    }
+
+    Ok(track_ids)
+}
+
+#[tauri::command]
+async fn find_duplicate_tracks(
+    duration_tolerance: Option<f64>,
+    match_audio_hash: Option<bool>,
+    app_handle: AppHandle,
+) -> Result<Vec<PersistentDuplicateGroup>, String> {
+    app_handle
+        .db(|db| {
+            library::find_duplicate_tracks(
+                duration_tolerance.unwrap_or(library::DEFAULT_DUPLICATE_DURATION_TOLERANCE),
+                match_audio_hash.unwrap_or(false),
+                db,
+            )
+        })
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn get_database_backups(
+    app_handle: AppHandle,
+) -> Result<Vec<PersistentDatabaseBackup>, String> {
+    db_backup::list(&db::app_dir(&app_handle)).map_err(|err| err.to_string())
+}
+
+/// Restores a database backup by restarting the app, since the database can't be replaced
+/// while it is open. The current database is backed up first, so the restore can be undone.
+#[tauri::command]
+async fn restore_database_backup(file_name: String, app_handle: AppHandle) -> Result<(), String> {
+    let app_dir = db::app_dir(&app_handle);
+    db_backup::schedule_restore(&app_dir, &file_name).map_err(|err| err.to_string())?;
+    app_handle
+        .db_mut(|db| db_backup::create(db, &app_dir, db::CURRENT_DB_VERSION))
+        .map_err(|err| err.to_string())?;
+
+    app_handle.restart();
+}
+
+#[tauri::command]
+async fn get_library_statistics(
+    app_handle: AppHandle,
+) -> Result<PersistentLibraryStatistics, String> {
+    app_handle
+        .db(db::get_library_statistics)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn get_tracks(
+    list_options: Option<ListOptions>,
+    app_state: State<'_, AppState>,
+) -> Result<Vec<PersistentTrack>, String> {
+    let conn = &app_state.db_reader();
+    let tracks = library::get_tracks(&list_options.unwrap_or_default(), conn)
+        .map_err(|err| err.to_string())?;
 
     Ok(tracks)
 }
@@ -155,12 +328,21 @@ async fn get_track_ids(
     search_query: Option<String>,
     without_plain_lyrics: Option<bool>,
     without_synced_lyrics: Option<bool>,
+    filters: Option<TrackFilters>,
+    list_options: Option<ListOptions>,
     app_state: State<'_, AppState>,
 ) -> Result<Vec<i64>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &app_state.db_reader();
     let search_query = search_query.filter(|s| !s.is_empty());
-    let track_ids = library::get_track_ids(search_query, without_plain_lyrics.unwrap_or(false), without_synced_lyrics.unwrap_or(false), conn).map_err(|err| err.to_string())?;
+    let track_ids = library::get_track_ids(
+        search_query,
+        without_plain_lyrics.unwrap_or(false),
+        without_synced_lyrics.unwrap_or(false),
+        &filters.unwrap_or_default(),
+        &list_options.unwrap_or_default(),
+        conn,
+    )
+    .map_err(|err| err.to_string())?;
 
     Ok(track_ids)
 }
@@ -170,27 +352,33 @@ async fn get_track(
     track_id: i64,
     app_state: State<'_, AppState>,
 ) -> Result<PersistentTrack, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &app_state.db_reader();
     let track = library::get_track(track_id, conn).map_err(|err| err.to_string())?;
 
     Ok(track)
 }
 
 #[tauri::command]
-async fn get_albums(app_state: State<'_, AppState>) -> Result<Vec<PersistentAlbum>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let albums = library::get_albums(conn).map_err(|err| err.to_string())?;
+async fn get_albums(
+    list_options: Option<ListOptions>,
+    app_state: State<'_, AppState>,
+) -> Result<Vec<PersistentAlbum>, String> {
+    let conn = &app_state.db_reader();
+    let albums = library::get_albums(&list_options.unwrap_or_default(), conn)
+        .map_err(|err| err.to_string())?;
 
     Ok(albums)
 }
 
 #[tauri::command]
-async fn get_album_ids(app_state: State<'_, AppState>) -> Result<Vec<i64>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let album_ids = library::get_album_ids(conn).map_err(|err| err.to_string())?;
+async fn get_album_ids(
+    search_query: Option<String>,
+    list_options: Option<ListOptions>,
+    app_state: State<'_, AppState>,
+) -> Result<Vec<i64>, String> {
+    let conn = &app_state.db_reader();
+    let album_ids = library::get_album_ids(search_query, &list_options.unwrap_or_default(), conn)
+        .map_err(|err| err.to_string())?;
 
     Ok(album_ids)
 }
@@ -200,27 +388,33 @@ async fn get_album(
     album_id: i64,
     app_state: State<'_, AppState>,
 ) -> Result<PersistentAlbum, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &app_state.db_reader();
     let album = library::get_album(album_id, conn).map_err(|err| err.to_string())?;
 
     Ok(album)
 }
 
 #[tauri::command]
-async fn get_artists(app_state: State<'_, AppState>) -> Result<Vec<PersistentArtist>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let artists = library::get_artists(conn).map_err(|err| err.to_string())?;
+async fn get_artists(
+    list_options: Option<ListOptions>,
+    app_state: State<'_, AppState>,
+) -> Result<Vec<PersistentArtist>, String> {
+    let conn = &app_state.db_reader();
+    let artists = library::get_artists(&list_options.unwrap_or_default(), conn)
+        .map_err(|err| err.to_string())?;
 
     Ok(artists)
 }
 
 #[tauri::command]
-async fn get_artist_ids(app_state: State<'_, AppState>) -> Result<Vec<i64>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let artist_ids = library::get_artist_ids(conn).map_err(|err| err.to_string())?;
+async fn get_artist_ids(
+    search_query: Option<String>,
+    list_options: Option<ListOptions>,
+    app_state: State<'_, AppState>,
+) -> Result<Vec<i64>, String> {
+    let conn = &app_state.db_reader();
+    let artist_ids = library::get_artist_ids(search_query, &list_options.unwrap_or_default(), conn)
+        .map_err(|err| err.to_string())?;
 
     Ok(artist_ids)
 }
@@ -230,8 +424,7 @@ async fn get_artist(
     artist_id: i64,
     app_state: State<'_, AppState>,
 ) -> Result<PersistentArtist, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
+    let conn = &app_state.db_reader();
     let artist = library::get_artist(artist_id, conn).map_err(|err| err.to_string())?;
 
     Ok(artist)
@@ -240,11 +433,12 @@ async fn get_artist(
 #[tauri::command]
 async fn get_album_tracks(
     album_id: i64,
+    list_options: Option<ListOptions>,
     app_state: State<'_, AppState>,
 ) -> Result<Vec<PersistentTrack>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let tracks = library::get_album_tracks(album_id, conn).map_err(|err| err.to_string())?;
+    let conn = &app_state.db_reader();
+    let tracks = library::get_album_tracks(album_id, &list_options.unwrap_or_default(), conn)
+        .map_err(|err| err.to_string())?;
 
     Ok(tracks)
 }
@@ -252,11 +446,12 @@ async fn get_album_tracks(
 #[tauri::command]
 async fn get_artist_tracks(
     artist_id: i64,
+    list_options: Option<ListOptions>,
     app_state: State<'_, AppState>,
 ) -> Result<Vec<PersistentTrack>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let tracks = library::get_artist_tracks(artist_id, conn).map_err(|err| err.to_string())?;
+    let conn = &app_state.db_reader();
+    let tracks = library::get_artist_tracks(artist_id, &list_options.unwrap_or_default(), conn)
+        .map_err(|err| err.to_string())?;
 
     Ok(tracks)
 }
@@ -264,13 +459,24 @@ async fn get_artist_tracks(
 #[tauri::command]
 async fn get_album_track_ids(
     album_id: i64,
+    search_query: Option<String>,
     without_plain_lyrics: Option<bool>,
     without_synced_lyrics: Option<bool>,
+    filters: Option<TrackFilters>,
+    list_options: Option<ListOptions>,
     app_state: State<'_, AppState>,
 ) -> Result<Vec<i64>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let track_ids = library::get_album_track_ids(album_id, without_plain_lyrics.unwrap_or(false), without_synced_lyrics.unwrap_or(false), conn).map_err(|err| err.to_string())?;
+    let conn = &app_state.db_reader();
+    let track_ids = library::get_album_track_ids(
+        album_id,
+        search_query,
+        without_plain_lyrics.unwrap_or(false),
+        without_synced_lyrics.unwrap_or(false),
+        &filters.unwrap_or_default(),
+        &list_options.unwrap_or_default(),
+        conn,
+    )
+    .map_err(|err| err.to_string())?;
 
     Ok(track_ids)
 }
@@ -278,35 +484,72 @@ async fn get_album_track_ids(
 #[tauri::command]
 async fn get_artist_track_ids(
     artist_id: i64,
+    search_query: Option<String>,
     without_plain_lyrics: Option<bool>,
     without_synced_lyrics: Option<bool>,
+    filters: Option<TrackFilters>,
+    list_options: Option<ListOptions>,
     app_state: State<'_, AppState>,
 ) -> Result<Vec<i64>, String> {
-    let conn_guard = app_state.db.lock().unwrap();
-    let conn = conn_guard.as_ref().unwrap();
-    let track_ids =
-        library::get_artist_track_ids(artist_id, without_plain_lyrics.unwrap_or(false), without_synced_lyrics.unwrap_or(false), conn).map_err(|err| err.to_string())?;
+    let conn = &app_state.db_reader();
+    let track_ids = library::get_artist_track_ids(
+        artist_id,
+        search_query,
+        without_plain_lyrics.unwrap_or(false),
+        without_synced_lyrics.unwrap_or(false),
+        &filters.unwrap_or_default(),
+        &list_options.unwrap_or_default(),
+        conn,
+    )
+    .map_err(|err| err.to_string())?;
 
     Ok(track_ids)
 }
 
 #[tauri::command]
-async fn download_lyrics(track_id: i64, app_handle: AppHandle) -> Result<String, String> {
+async fn download_lyrics(
+    track_id: i64,
+    batch_id: Option<String>,
+    app_handle: AppHandle,
+) -> Result<String, String> {
     let track = app_handle
         .db(|db| db::get_track_by_id(track_id, db))
         .map_err(|err| err.to_string())?;
     let config = app_handle
         .db(|db| db::get_config(db))
         .map_err(|err| err.to_string())?;
-    let lyrics =
-        lyrics::download_lyrics_for_track(track, config.try_embed_lyrics, &config.lrclib_instance)
-            .await
-            .map_err(|err| err.to_string())?;
+    let lookup = lrclib::get::request(
+        &track.title,
+        &track.album_name,
+        &track.artist_name,
+        track.duration,
+        &config.lrclib_instance,
+    )
+    .await;
+    let outcome = lrclib::get::LookupOutcome::from_result(&lookup);
+    app_handle
+        .db_mut(|db: &mut Connection| {
+            db::add_download_attempt(track_id, outcome.name(), &config.lrclib_instance, db)
+        })
+        .map_err(|err| err.to_string())?;
+
+    let (lyrics, lrclib_id) = lookup.map_err(|err| err.to_string())?;
+    let lyrics = lyrics::apply_lyrics_for_track(track, lyrics, config.try_embed_lyrics)
+        .await
+        .map_err(|err| err.to_string())?;
+    let change = LyricsChange::new(LyricsChangeSource::Lrclib(lrclib_id), batch_id)
+        .map_err(|err| err.to_string())?;
     match lyrics {
         lrclib::get::Response::SyncedLyrics(synced_lyrics, plain_lyrics) => {
             app_handle
-                .db(|db: &Connection| {
-                    db::update_track_synced_lyrics(track_id, &synced_lyrics, &plain_lyrics, db)
+                .db_mut(|db: &mut Connection| {
+                    db::update_track_synced_lyrics(
+                        track_id,
+                        &synced_lyrics,
+                        &plain_lyrics,
+                        &change,
+                        db,
+                    )
                 })
                 .map_err(|err| err.to_string())?;
             app_handle.emit("reload-track-id", track_id).unwrap();
@@ -314,14 +557,16 @@ async fn download_lyrics(track_id: i64, app_handle: AppHandle) -> Result<String,
         }
         lrclib::get::Response::UnsyncedLyrics(plain_lyrics) => {
             app_handle
-                .db(|db: &Connection| db::update_track_plain_lyrics(track_id, &plain_lyrics, db))
+                .db_mut(|db: &mut Connection| {
+                    db::update_track_plain_lyrics(track_id, &plain_lyrics, &change, db)
+                })
                 .map_err(|err| err.to_string())?;
             app_handle.emit("reload-track-id", track_id).unwrap();
             Ok("Plain lyrics downloaded".to_owned())
         }
         lrclib::get::Response::IsInstrumental => {
             app_handle
-                .db(|db: &Connection| db::update_track_instrumental(track_id, db))
+                .db_mut(|db: &mut Connection| db::update_track_instrumental(track_id, &change, db))
                 .map_err(|err| err.to_string())?;
             Ok("Marked track as instrumental".to_owned())
         }
@@ -343,6 +588,8 @@ async fn apply_lyrics(
         .map_err(|err| err.to_string())?
         .try_embed_lyrics;
 
+    let change = LyricsChange::new(LyricsChangeSource::Lrclib(lrclib_response.id), None)
+        .map_err(|err| err.to_string())?;
     let lyrics = lrclib::get::Response::from_raw_response(lrclib_response);
     let lyrics = lyrics::apply_lyrics_for_track(track, lyrics, is_try_embed_lyrics)
         .await
@@ -351,8 +598,14 @@ async fn apply_lyrics(
     match lyrics {
         lrclib::get::Response::SyncedLyrics(synced_lyrics, plain_lyrics) => {
             app_handle
-                .db(|db: &Connection| {
-                    db::update_track_synced_lyrics(track_id, &synced_lyrics, &plain_lyrics, db)
+                .db_mut(|db: &mut Connection| {
+                    db::update_track_synced_lyrics(
+                        track_id,
+                        &synced_lyrics,
+                        &plain_lyrics,
+                        &change,
+                        db,
+                    )
                 })
                 .map_err(|err| err.to_string())?;
             std::thread::spawn(move || {
@@ -362,7 +615,9 @@ async fn apply_lyrics(
         }
         lrclib::get::Response::UnsyncedLyrics(plain_lyrics) => {
             app_handle
-                .db(|db: &Connection| db::update_track_plain_lyrics(track_id, &plain_lyrics, db))
+                .db_mut(|db: &mut Connection| {
+                    db::update_track_plain_lyrics(track_id, &plain_lyrics, &change, db)
+                })
                 .map_err(|err| err.to_string())?;
             std::thread::spawn(move || {
                 app_handle.emit("reload-track-id", track_id).unwrap();
@@ -371,7 +626,7 @@ async fn apply_lyrics(
         }
         lrclib::get::Response::IsInstrumental => {
             app_handle
-                .db(|db: &Connection| db::update_track_instrumental(track_id, db))
+                .db_mut(|db: &mut Connection| db::update_track_instrumental(track_id, &change, db))
                 .map_err(|err| err.to_string())?;
             Ok("Marked track as instrumental".to_owned())
         }
@@ -444,6 +699,17 @@ async fn search_lyrics(
     Ok(response)
 }
 
+#[tauri::command]
+async fn search_library_lyrics(
+    query: String,
+    limit: Option<usize>,
+    app_handle: AppHandle,
+) -> Result<Vec<PersistentLyricsMatch>, String> {
+    app_handle
+        .db(|db| lyrics_search::search(&query, limit.unwrap_or(100), db))
+        .map_err(|err| err.to_string())
+}
+
 #[tauri::command]
 async fn save_lyrics(
     track_id: i64,
@@ -472,23 +738,27 @@ async fn save_lyrics(
     .await
     .map_err(|err| err.to_string())?;
 
+    let change =
+        LyricsChange::new(LyricsChangeSource::Manual, None).map_err(|err| err.to_string())?;
     if is_instrumental {
         app_handle
-            .db(|db: &Connection| db::update_track_instrumental(track.id, db))
+            .db_mut(|db: &mut Connection| db::update_track_instrumental(track.id, &change, db))
             .map_err(|err| err.to_string())?;
     } else if !synced_lyrics.is_empty() {
         app_handle
-            .db(|db: &Connection| {
-                db::update_track_synced_lyrics(track.id, &synced_lyrics, &plain_lyrics, db)
+            .db_mut(|db: &mut Connection| {
+                db::update_track_synced_lyrics(track.id, &synced_lyrics, &plain_lyrics, &change, db)
             })
             .map_err(|err| err.to_string())?;
     } else if !plain_lyrics.is_empty() {
         app_handle
-            .db(|db: &Connection| db::update_track_plain_lyrics(track.id, &plain_lyrics, db))
+            .db_mut(|db: &mut Connection| {
+                db::update_track_plain_lyrics(track.id, &plain_lyrics, &change, db)
+            })
             .map_err(|err| err.to_string())?;
     } else {
         app_handle
-            .db(|db: &Connection| db::update_track_null_lyrics(track.id, db))
+            .db_mut(|db: &mut Connection| db::update_track_null_lyrics(track.id, &change, db))
             .map_err(|err| err.to_string())?;
     }
 
@@ -497,6 +767,65 @@ async fn save_lyrics(
     Ok("Lyrics saved successfully".to_owned())
 }
 
+#[tauri::command]
+async fn get_lyrics_revisions(
+    track_id: i64,
+    app_handle: AppHandle,
+) -> Result<Vec<PersistentLyricsRevision>, String> {
+    app_handle
+        .db(|db| db::get_lyrics_revisions(track_id, db))
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn restore_lyrics_revision(
+    revision_id: i64,
+    app_handle: AppHandle,
+) -> Result<String, String> {
+    let revision = app_handle
+        .db(|db| db::get_lyrics_revision_by_id(revision_id, db))
+        .map_err(|err| err.to_string())?;
+    let track = app_handle
+        .db(|db| db::get_track_by_id(revision.track_id, db))
+        .map_err(|err| err.to_string())?;
+    let is_try_embed_lyrics = app_handle
+        .db(db::get_config)
+        .map_err(|err| err.to_string())?
+        .try_embed_lyrics;
+
+    let plain_lyrics = revision.txt_lyrics.unwrap_or_default();
+    let synced_lyrics = revision.lrc_lyrics.unwrap_or_default();
+    lyrics::apply_string_lyrics_for_track(
+        &track,
+        &plain_lyrics,
+        &synced_lyrics,
+        is_try_embed_lyrics,
+    )
+    .await
+    .map_err(|err| err.to_string())?;
+
+    // The lyrics being replaced become a revision too, so a restore can be undone
+    let change =
+        LyricsChange::new(LyricsChangeSource::Restore, None).map_err(|err| err.to_string())?;
+    app_handle
+        .db_mut(|db: &mut Connection| {
+            if revision.instrumental {
+                db::update_track_instrumental(track.id, &change, db)
+            } else if !synced_lyrics.is_empty() {
+                db::update_track_synced_lyrics(track.id, &synced_lyrics, &plain_lyrics, &change, db)
+            } else if !plain_lyrics.is_empty() {
+                db::update_track_plain_lyrics(track.id, &plain_lyrics, &change, db)
+            } else {
+                db::update_track_null_lyrics(track.id, &change, db)
+            }
+        })
+        .map_err(|err| err.to_string())?;
+
+    app_handle.emit("reload-track-id", track.id).unwrap();
+
+    Ok("Lyrics restored".to_owned())
+}
+
 #[tauri::command]
 async fn publish_lyrics(
     title: String,
@@ -698,17 +1027,44 @@ async fn main() {
         .manage(AppState {
             db: Default::default(),
             player: Default::default(),
+            watcher: Default::default(),
+            scan_cancelled: Default::default(),
         })
         .setup(|app| {
             let handle = app.handle();
 
             let app_state: State<AppState> = handle.state();
-            let db = db::initialize_database(&handle).expect("Database initialize should succeed");
-            *app_state.db.lock().unwrap() = Some(db);
+            let (db, upgrade_error) =
+                db::initialize_database(&handle).expect("Database initialize should succeed");
+            if app_state.db.set(db).is_err() {
+                panic!("The database should be initialized once");
+            }
+            if let Some(error) = &upgrade_error {
+                handle
+                    .dialog()
+                    .message(format!(
+                        "{:#}\n\nSome features won't work until the database is upgraded. Please report this error.",
+                        error
+                    ))
+                    .kind(MessageDialogKind::Error)
+                    .title("Database upgrade failed")
+                    .show(|_| {});
+            }
 
             let player = Player::new().expect("Failed to initialize audio player");
             *app_state.player.lock().unwrap() = Some(player);
 
+            let watch_library = handle
+                .db(db::get_config)
+                .map(|config| config.watch_library)
+                .unwrap_or(false);
+            // The watcher would write scan results into the old schema
+            if watch_library && upgrade_error.is_none() {
+                if let Err(error) = watcher::start(handle) {
+                    eprintln!("Failed to start library watcher: {}", error);
+                }
+            }
+
             let handle_clone = handle.clone();
 
             tokio::spawn(async move {
@@ -743,12 +1099,26 @@ async fn main() {
         .invoke_handler(tauri::generate_handler![
             get_directories,
             set_directories,
+            get_directory_settings,
+            set_directory_settings,
+            get_path_templates,
+            set_path_templates,
+            get_artist_separators,
+            set_artist_separators,
             get_init,
             get_config,
             set_config,
+            set_watch_library,
             initialize_library,
+            cancel_scan,
             uninitialize_library,
             refresh_library,
+            get_scan_failures,
+            retry_scan_failure,
+            find_duplicate_tracks,
+            get_library_statistics,
+            get_database_backups,
+            restore_database_backup,
             get_tracks,
             get_track_ids,
             get_track,
@@ -767,7 +1137,10 @@ async fn main() {
             retrieve_lyrics,
             retrieve_lyrics_by_id,
             search_lyrics,
+            search_library_lyrics,
             save_lyrics,
+            get_lyrics_revisions,
+            restore_lyrics_revision,
             publish_lyrics,
             flag_lyrics,
             play_track,
@@ -0,0 +1,261 @@
+use regex::Regex;
+use std::path::{Component, Path};
+use thiserror::Error;
+
+/// Fields a path template can fill in when the tags of a track don't have them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathField {
+    Title,
+    Album,
+    Artist,
+    AlbumArtist,
+    TrackNumber,
+}
+
+impl PathField {
+    fn from_placeholder(placeholder: &str) -> Option<PathField> {
+        match placeholder {
+            "title" => Some(PathField::Title),
+            "album" => Some(PathField::Album),
+            "artist" => Some(PathField::Artist),
+            "album_artist" => Some(PathField::AlbumArtist),
+            "track" => Some(PathField::TrackNumber),
+            _ => None,
+        }
+    }
+
+    /// The name used to record the field as inferred in the database.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PathField::Title => "title",
+            PathField::Album => "album",
+            PathField::Artist => "artist",
+            PathField::AlbumArtist => "album_artist",
+            PathField::TrackNumber => "track_number",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PathTemplateError {
+    #[error("Path template is empty")]
+    Empty,
+    #[error("Unknown placeholder `{{{1}}}` in path template: `{0}`")]
+    UnknownPlaceholder(String, String),
+    #[error("Unclosed placeholder in path template: `{0}`")]
+    UnclosedPlaceholder(String),
+}
+
+/// A template such as `{artist}/{album}/{track} - {title}` that is matched against the last
+/// directories and the file name (without extension) of a track.
+#[derive(Clone, Debug)]
+pub struct PathTemplate {
+    segments_count: usize,
+    regex: Regex,
+    fields: Vec<PathField>,
+}
+
+#[derive(Default, Debug)]
+pub struct PathFields {
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<PathTemplate, PathTemplateError> {
+        let template = template.trim().trim_matches('/');
+        if template.is_empty() {
+            return Err(PathTemplateError::Empty);
+        }
+
+        let mut pattern = String::from("^");
+        let mut fields: Vec<PathField> = vec![];
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            pattern.push_str(&regex::escape(&rest[..start]));
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| PathTemplateError::UnclosedPlaceholder(template.to_owned()))?;
+            let placeholder = &rest[start + 1..start + end];
+            let field = PathField::from_placeholder(placeholder).ok_or_else(|| {
+                PathTemplateError::UnknownPlaceholder(template.to_owned(), placeholder.to_owned())
+            })?;
+
+            match field {
+                PathField::TrackNumber => pattern.push_str(r"([0-9]+)"),
+                _ => pattern.push_str(r"([^/]+?)"),
+            }
+            fields.push(field);
+            rest = &rest[start + end + 1..];
+        }
+        pattern.push_str(&regex::escape(rest));
+        pattern.push('$');
+
+        Ok(PathTemplate {
+            segments_count: template.split('/').count(),
+            regex: Regex::new(&pattern).expect("Path template regex should be valid"),
+            fields,
+        })
+    }
+
+    /// Matches the template against the tail of the path. Returns `None` if it doesn't match.
+    pub fn match_path(&self, path: &Path) -> Option<PathFields> {
+        let stem = path.file_stem()?.to_str()?;
+        let mut segments: Vec<&str> = path
+            .parent()?
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+        segments.push(stem);
+
+        if segments.len() < self.segments_count {
+            return None;
+        }
+
+        let tail = segments[segments.len() - self.segments_count..].join("/");
+        let captures = self.regex.captures(&tail)?;
+        let mut path_fields = PathFields::default();
+
+        for (index, field) in self.fields.iter().enumerate() {
+            let value = match captures.get(index + 1) {
+                Some(value) => value.as_str().trim(),
+                None => continue,
+            };
+            if value.is_empty() {
+                continue;
+            }
+
+            let value = value.to_owned();
+            match field {
+                PathField::Title => {
+                    path_fields.title.get_or_insert(value);
+                }
+                PathField::Album => {
+                    path_fields.album.get_or_insert(value);
+                }
+                PathField::Artist => {
+                    path_fields.artist.get_or_insert(value);
+                }
+                PathField::AlbumArtist => {
+                    path_fields.album_artist.get_or_insert(value);
+                }
+                PathField::TrackNumber => {
+                    if let Ok(track_number) = value.parse::<u32>() {
+                        path_fields.track_number.get_or_insert(track_number);
+                    }
+                }
+            }
+        }
+
+        Some(path_fields)
+    }
+}
+
+/// Parses the stored templates, skipping (and logging) any that are no longer valid.
+pub fn parse_templates(templates: &[String]) -> Vec<PathTemplate> {
+    templates
+        .iter()
+        .filter_map(|template| match PathTemplate::parse(template) {
+            Ok(path_template) => Some(path_template),
+            Err(error) => {
+                println!("{}", error);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns the fields of the first template, in order, that matches the path.
+pub fn infer_fields(path: &Path, templates: &[PathTemplate]) -> Option<PathFields> {
+    templates
+        .iter()
+        .find_map(|template| template.match_path(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(template: &str) -> PathTemplate {
+        PathTemplate::parse(template).unwrap()
+    }
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        assert!(matches!(
+            PathTemplate::parse(" / "),
+            Err(PathTemplateError::Empty)
+        ));
+        assert!(matches!(
+            PathTemplate::parse("{artist}/{album"),
+            Err(PathTemplateError::UnclosedPlaceholder(_))
+        ));
+        assert!(matches!(
+            PathTemplate::parse("{artist}/{year} - {album}"),
+            Err(PathTemplateError::UnknownPlaceholder(_, placeholder)) if placeholder == "year"
+        ));
+    }
+
+    #[test]
+    fn match_path_fills_the_fields() {
+        let fields = template("{artist}/{album}/{track} - {title}")
+            .match_path(Path::new(
+                "/music/Muse/Absolution/03 - Time Is Running Out.flac",
+            ))
+            .unwrap();
+
+        assert_eq!(fields.artist.as_deref(), Some("Muse"));
+        assert_eq!(fields.album.as_deref(), Some("Absolution"));
+        assert_eq!(fields.track_number, Some(3));
+        assert_eq!(fields.title.as_deref(), Some("Time Is Running Out"));
+    }
+
+    #[test]
+    fn match_path_needs_as_many_segments_as_the_template() {
+        let template = template("{artist}/{album}/{title}");
+
+        assert!(template.match_path(Path::new("Album/Title.mp3")).is_none());
+        assert!(template.match_path(Path::new("Title.mp3")).is_none());
+    }
+
+    #[test]
+    fn track_placeholder_only_matches_digits() {
+        let template = template("{track} - {title}");
+
+        assert!(template
+            .match_path(Path::new("/music/A1 - Side A.mp3"))
+            .is_none());
+        assert_eq!(
+            template
+                .match_path(Path::new("/music/07 - Song.mp3"))
+                .unwrap()
+                .track_number,
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn literal_text_is_not_a_regex() {
+        let template = template("{artist} (Live)/{title} [.+]");
+
+        let fields = template
+            .match_path(Path::new("/music/Nirvana (Live)/Lithium [.+].mp3"))
+            .unwrap();
+        assert_eq!(fields.artist.as_deref(), Some("Nirvana"));
+        assert_eq!(fields.title.as_deref(), Some("Lithium"));
+
+        assert!(template
+            .match_path(Path::new("/music/Nirvana Live/Lithium [.+].mp3"))
+            .is_none());
+        assert!(template
+            .match_path(Path::new("/music/Nirvana (Live)/Lithium xx.mp3"))
+            .is_none());
+    }
+}
@@ -13,10 +13,28 @@ pub struct PersistentTrack {
     pub artist_id: i64,
     pub image_path: Option<String>,
     pub track_number: Option<i64>,
+    pub track_total: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub disc_total: Option<i64>,
+    pub year: Option<i64>,
+    pub genre: Option<String>,
+    pub isrc: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+    pub codec: Option<String>,
+    pub bitrate: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub channels: Option<i64>,
+    pub file_size: Option<i64>,
     pub txt_lyrics: Option<String>,
     pub lrc_lyrics: Option<String>,
+    pub lyrics_source: Option<String>,
     pub duration: f64,
+    pub cue_track_number: Option<i64>,
+    pub cue_start: Option<f64>,
+    pub cue_end: Option<f64>,
     pub instrumental: bool,
+    pub inferred_fields: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -44,4 +62,139 @@ pub struct PersistentConfig {
     pub try_embed_lyrics: bool,
     pub theme_mode: String,
     pub lrclib_instance: String,
+    pub watch_library: bool,
+    /// How many days bulk downloads skip a track after LRCLIB had no lyrics for it. The
+    /// window doubles with every further miss; 0 always looks the track up again.
+    pub retry_not_found_days: u32,
+}
+
+#[derive(Serialize)]
+pub struct PersistentDirectory {
+    pub id: i64,
+    pub path: String,
+    pub extensions: Vec<String>,
+    pub exclude_patterns: Vec<String>,
+    pub min_duration: Option<f64>,
+    pub follow_symlinks: bool,
+}
+
+#[derive(Serialize)]
+pub struct PersistentScanFailure {
+    pub id: i64,
+    pub file_path: String,
+    pub error_kind: String,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct PersistentScanFailureGroup {
+    pub error_kind: String,
+    pub failures_count: i64,
+    pub failures: Vec<PersistentScanFailure>,
+}
+
+#[derive(Serialize)]
+pub struct PersistentDuplicateTrack {
+    pub id: i64,
+    pub file_path: String,
+    pub title: String,
+    pub artist_name: String,
+    pub duration: f64,
+    pub lyrics_status: String,
+}
+
+#[derive(Serialize)]
+pub struct PersistentDuplicateGroup {
+    pub title: String,
+    pub artist_name: String,
+    pub tracks: Vec<PersistentDuplicateTrack>,
+}
+
+/// A track whose lyrics contain the searched words. The snippet is the matched line split
+/// around the match, so it can be highlighted without parsing markup.
+#[derive(Serialize)]
+pub struct PersistentLyricsMatch {
+    pub track_id: i64,
+    pub title: String,
+    pub artist_name: String,
+    pub album_name: String,
+    pub snippet_before: String,
+    pub snippet_match: String,
+    pub snippet_after: String,
+    /// Position of the matched line in seconds, for synced lyrics.
+    pub timestamp: Option<f64>,
+}
+
+/// Counts the tracks by lyrics status. Each track counts once: instrumental tracks first,
+/// then tracks with synced lyrics, then tracks with plain lyrics only.
+#[derive(Serialize)]
+pub struct PersistentLyricsCoverage {
+    pub tracks_count: i64,
+    pub synced_count: i64,
+    pub plain_count: i64,
+    pub instrumental_count: i64,
+    pub missing_count: i64,
+    /// Total duration of the tracks in seconds.
+    pub duration: f64,
+}
+
+#[derive(Serialize)]
+pub struct PersistentArtistCoverage {
+    pub id: i64,
+    pub name: String,
+    #[serde(flatten)]
+    pub coverage: PersistentLyricsCoverage,
+}
+
+#[derive(Serialize)]
+pub struct PersistentAlbumCoverage {
+    pub id: i64,
+    pub name: String,
+    pub album_artist_name: Option<String>,
+    #[serde(flatten)]
+    pub coverage: PersistentLyricsCoverage,
+}
+
+#[derive(Serialize)]
+pub struct PersistentFormatCoverage {
+    /// The lowercase extension of the files, e.g. `m4a` for both AAC and ALAC tracks.
+    pub extension: String,
+    #[serde(flatten)]
+    pub coverage: PersistentLyricsCoverage,
+}
+
+#[derive(Serialize)]
+pub struct PersistentLibraryStatistics {
+    pub total: PersistentLyricsCoverage,
+    pub artists: Vec<PersistentArtistCoverage>,
+    pub albums: Vec<PersistentAlbumCoverage>,
+    pub formats: Vec<PersistentFormatCoverage>,
+}
+
+#[derive(Serialize)]
+pub struct PersistentDatabaseBackup {
+    pub file_name: String,
+    /// Version of the database schema in the backup.
+    pub version: u32,
+    /// When the backup was made, in seconds since the Unix epoch.
+    pub created_at: u64,
+    pub file_size: u64,
+}
+
+/// The lyrics a track had before a change, kept so that they can be restored.
+#[derive(Serialize)]
+pub struct PersistentLyricsRevision {
+    pub id: i64,
+    pub track_id: i64,
+    pub txt_lyrics: Option<String>,
+    pub lrc_lyrics: Option<String>,
+    pub instrumental: bool,
+    /// What replaced these lyrics: `manual`, `lrclib`, `import` or `restore`.
+    pub source: String,
+    /// The LRCLIB record the replacing lyrics came from, if known.
+    pub lrclib_id: Option<i64>,
+    /// Shared by the revisions made by the same bulk operation, such as one download run.
+    pub batch_id: String,
+    /// When the lyrics were replaced, in seconds since the Unix epoch.
+    pub created_at: i64,
 }
@@ -10,6 +10,17 @@ use kira::{
 
 use crate::persistent_entities::PersistentTrack;
 use serde::Serialize;
+use std::path::Path;
+use thiserror::Error;
+
+/// Formats that can be scanned, but that symphonia has no decoder for.
+const UNPLAYABLE_EXTENSIONS: [&str; 3] = ["ape", "wv", "mpc"];
+
+#[derive(Error, Debug)]
+pub enum PlayerError {
+    #[error("Playing `{0}` files is not supported")]
+    UnsupportedFormat(String),
+}
 
 #[derive(Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -27,6 +38,12 @@ pub struct Player {
     sound_handle: Option<StreamingSoundHandle<FromFileError>>,
     #[serde(skip)]
     pub track: Option<PersistentTrack>,
+    /// Where the playing track starts and ends in its file. Only virtual tracks of a CUE
+    /// sheet rip start later or end early; progress and seeking are relative to the start.
+    #[serde(skip)]
+    start: f64,
+    #[serde(skip)]
+    end: Option<f64>,
     pub status: PlayerStatus,
     pub progress: f64,
     pub duration: f64,
@@ -41,6 +58,8 @@ impl Player {
             manager,
             sound_handle: None,
             track: None,
+            start: 0.0,
+            end: None,
             status: PlayerStatus::Stopped,
             progress: 0.0,
             duration: 0.0,
@@ -63,20 +82,37 @@ impl Player {
 
         match self.sound_handle {
             Some(ref mut sound_handle) => {
-                self.progress = sound_handle.position();
+                let position = sound_handle.position();
+                if self.end.is_some_and(|end| position >= end) {
+                    sound_handle.stop(Tween::default());
+                    self.status = PlayerStatus::Stopped;
+                }
+                self.progress = (position - self.start).clamp(0.0, self.duration);
             }
             None => {}
         }
     }
 
     pub fn play(&mut self, track: PersistentTrack) -> Result<()> {
+        let extension = Path::new(&track.file_path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        if UNPLAYABLE_EXTENSIONS.contains(&extension.as_str()) {
+            return Err(PlayerError::UnsupportedFormat(extension).into());
+        }
+
         let _ = self.stop();
         self.track = Some(track);
 
         if let Some(ref mut track) = self.track {
-            let sound_data = StreamingSoundData::from_file(&track.file_path)?;
+            self.start = track.cue_start.unwrap_or(0.0);
+            self.end = track.cue_end;
+            let sound_data =
+                StreamingSoundData::from_file(&track.file_path)?.start_position(self.start);
 
-            self.duration = sound_data.duration().as_secs_f64();
+            self.duration = self.end.unwrap_or(sound_data.duration().as_secs_f64()) - self.start;
             self.sound_handle = Some(self.manager.play(sound_data)?);
             self.sound_handle
                 .as_mut()
@@ -101,6 +137,7 @@ impl Player {
 
     pub fn seek(&mut self, position: f64) {
         if let Some(ref mut sound_handle) = self.sound_handle {
+            let position = self.start + position;
             match sound_handle.state() {
                 PlaybackState::Playing => sound_handle.seek_to(position),
                 _ => {
@@ -116,6 +153,8 @@ impl Player {
             sound_handle.stop(Tween::default());
             self.sound_handle = None;
             self.track = None;
+            self.start = 0.0;
+            self.end = None;
             self.duration = 0.0;
             self.progress = 0.0;
             self.status = PlayerStatus::Stopped;
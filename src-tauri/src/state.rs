@@ -1,13 +1,36 @@
 use rusqlite::Connection;
+use std::sync::MutexGuard;
 use tauri::{AppHandle, Manager, State};
 
+use crate::db_pool::{DbPool, DbReader};
 use crate::player::Player;
+use crate::watcher::LibraryWatcher;
 
 pub struct AppState {
-    pub db: std::sync::Mutex<Option<Connection>>,
+    pub db: std::sync::OnceLock<DbPool>,
     pub player: std::sync::Mutex<Option<Player>>,
+    pub watcher: std::sync::Mutex<Option<LibraryWatcher>>,
+    pub scan_cancelled: std::sync::atomic::AtomicBool,
 }
 
+impl AppState {
+    pub fn db_reader(&self) -> DbReader<'_> {
+        self.db
+            .get()
+            .expect("The database should be initialized.")
+            .reader()
+    }
+
+    pub fn db_writer(&self) -> MutexGuard<'_, Connection> {
+        self.db
+            .get()
+            .expect("The database should be initialized.")
+            .writer()
+    }
+}
+
+/// Runs database operations from anywhere the app handle is available. `db` runs on one of the
+/// read-only connections, so anything that writes goes through `db_mut`.
 pub trait ServiceAccess {
     fn db<F, TResult>(&self, operation: F) -> TResult
     where
@@ -24,10 +47,9 @@ impl ServiceAccess for AppHandle {
         F: FnOnce(&Connection) -> TResult,
     {
         let app_state: State<AppState> = self.state();
-        let db_connection_guard = app_state.db.lock().unwrap();
-        let db = db_connection_guard.as_ref().unwrap();
+        let db = app_state.db_reader();
 
-        operation(db)
+        operation(&db)
     }
 
     fn db_mut<F, TResult>(&self, operation: F) -> TResult
@@ -35,9 +57,8 @@ impl ServiceAccess for AppHandle {
         F: FnOnce(&mut Connection) -> TResult,
     {
         let app_state: State<AppState> = self.state();
-        let mut db_connection_guard = app_state.db.lock().unwrap();
-        let db = db_connection_guard.as_mut().unwrap();
+        let mut db = app_state.db_writer();
 
-        operation(db)
+        operation(&mut db)
     }
 }
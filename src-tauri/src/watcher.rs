@@ -0,0 +1,325 @@
+use crate::artwork;
+use crate::cue_sheet;
+use crate::db;
+use crate::fs_track::{self, DirectoryMatcher, FsTrack, ScanFailure, ScanOptions};
+use crate::persistent_entities::PersistentDirectory;
+use crate::state::{AppState, ServiceAccess};
+use anyhow::Result;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, DebouncedEvent, Debouncer};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Watches the library directories and keeps the tracks table in sync with the files on disk.
+/// Watching stops when the watcher is dropped.
+pub struct LibraryWatcher {
+    _debouncer: Debouncer<RecommendedWatcher>,
+}
+
+impl LibraryWatcher {
+    pub fn new(directories: &[String], app_handle: AppHandle) -> Result<LibraryWatcher> {
+        let event_handler = move |result: DebounceEventResult| match result {
+            Ok(events) => handle_events(events, &app_handle),
+            Err(error) => println!("Library watcher errored. Message: {}", error),
+        };
+        let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, event_handler)?;
+
+        for directory in directories.iter() {
+            debouncer
+                .watcher()
+                .watch(Path::new(directory), RecursiveMode::Recursive)?;
+        }
+
+        Ok(LibraryWatcher {
+            _debouncer: debouncer,
+        })
+    }
+}
+
+/// Starts watching the library directories, replacing the running watcher if there is one.
+pub fn start(app_handle: &AppHandle) -> Result<()> {
+    let directories = app_handle.db(db::get_directories)?;
+    let watcher = LibraryWatcher::new(&directories, app_handle.clone())?;
+
+    let app_state: State<AppState> = app_handle.state();
+    *app_state.watcher.lock().unwrap() = Some(watcher);
+
+    Ok(())
+}
+
+pub fn stop(app_handle: &AppHandle) {
+    let app_state: State<AppState> = app_handle.state();
+    *app_state.watcher.lock().unwrap() = None;
+}
+
+pub fn is_running(app_handle: &AppHandle) -> bool {
+    let app_state: State<AppState> = app_handle.state();
+    let is_running = app_state.watcher.lock().unwrap().is_some();
+    is_running
+}
+
+fn handle_events(events: Vec<DebouncedEvent>, app_handle: &AppHandle) {
+    match app_handle.db(db::get_init) {
+        Ok(true) => (),
+        _ => return,
+    }
+
+    let options = match app_handle.db(ScanOptions::load) {
+        Ok(options) => options,
+        Err(_) => return,
+    };
+    let directories = match app_handle.db(db::get_directory_settings) {
+        Ok(directories) => directories,
+        Err(_) => return,
+    };
+    let mut paths: Vec<PathBuf> = events
+        .into_iter()
+        .map(|event| event.path)
+        .collect::<HashSet<PathBuf>>()
+        .into_iter()
+        .collect();
+    // Existing paths go first, so a moved file takes over its track before the old path
+    // would delete it
+    paths.sort_by_key(|path| !path.exists());
+    let mut affected_track_ids: HashSet<i64> = HashSet::new();
+
+    for path in paths.iter() {
+        match handle_path(path, &directories, &options, app_handle) {
+            Ok(track_ids) => affected_track_ids.extend(track_ids),
+            Err(error) => println!(
+                "Cannot sync watched path `{}`. Error: {}",
+                path.display(),
+                error
+            ),
+        }
+    }
+
+    if affected_track_ids.is_empty() {
+        return;
+    }
+
+    match app_handle.db_mut(|db| db::clean_orphan_albums_and_artists(db)) {
+        Ok(image_paths) => artwork::remove_thumbnails(&image_paths),
+        Err(error) => println!("Cannot clean orphan albums and artists. Error: {}", error),
+    }
+
+    if let Err(error) = app_handle.db_mut(|db| artwork::update_album_artworks(db, app_handle)) {
+        println!("Cannot update album artworks. Error: {}", error);
+    }
+
+    for track_id in affected_track_ids {
+        app_handle.emit("reload-track-id", track_id).unwrap();
+    }
+}
+
+/// Syncs a single changed path and returns the ids of the tracks it affected.
+fn handle_path(
+    path: &Path,
+    directories: &[PersistentDirectory],
+    options: &ScanOptions,
+    app_handle: &AppHandle,
+) -> Result<Vec<i64>> {
+    let Some(directory) = fs_track::find_directory(directories, path) else {
+        return Ok(vec![]);
+    };
+
+    if fs_track::is_lyrics_sidecar(path) {
+        handle_lyrics_sidecar(path, directory, options, app_handle)
+    } else if cue_sheet::is_cue_sheet(path) {
+        handle_cue_sheet(path, directory, options, app_handle)
+    } else if path.is_dir() {
+        handle_directory(path, directory, options, app_handle)
+    } else if fs_track::is_included(directory, path) {
+        handle_audio_file(path, directory, options, app_handle)
+    } else if !path.exists() {
+        handle_removed_directory(path, app_handle)
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// Re-reads an audio file into its tracks: one track, or one per virtual track of its CUE
+/// sheet. Tracks that the file no longer yields are deleted.
+fn handle_audio_file(
+    path: &Path,
+    directory: &PersistentDirectory,
+    options: &ScanOptions,
+    app_handle: &AppHandle,
+) -> Result<Vec<i64>> {
+    let file_path = path.display().to_string();
+    let existing_tracks = app_handle.db(|db| db::get_tracks_by_file_path(&file_path, db))?;
+    let mut tracks: Vec<FsTrack> = vec![];
+
+    if path.is_file() {
+        match FsTrack::load_from_path(path, options) {
+            Ok(file_tracks) => tracks.extend(
+                file_tracks
+                    .into_iter()
+                    .filter(|track| fs_track::is_long_enough(directory, track)),
+            ),
+            Err(error) => {
+                println!("{}", error);
+                let failure = ScanFailure::new(file_path.to_owned(), &error);
+                app_handle.db_mut(|db| db::add_scan_failure(&failure, db))?;
+            }
+        }
+    } else {
+        app_handle.db_mut(|db| db::delete_scan_failure(&file_path, db))?;
+    }
+
+    let mut tracks = fs_track::pair_with_file_tracks(tracks, &existing_tracks);
+    if existing_tracks.is_empty() && tracks.len() == 1 {
+        let (_, track) = tracks.remove(0);
+        let candidates = match track.content_hash() {
+            Some(content_hash) => {
+                app_handle.db(|db| db::get_tracks_by_content_hash(&content_hash, db))?
+            }
+            None => vec![],
+        };
+        tracks.push(fs_track::resolve_moved_track(track, &candidates, options));
+    }
+
+    let batch_id = db::new_lyrics_batch_id()?;
+    let mut track_ids: Vec<i64> = vec![];
+    for (existing_id, track) in tracks.iter() {
+        track_ids.push(
+            app_handle.db_mut(|db| db::save_scanned_track(*existing_id, track, &batch_id, db))?,
+        );
+    }
+
+    let vanished_track_ids: Vec<i64> = existing_tracks
+        .iter()
+        .map(|(track_id, _)| *track_id)
+        .filter(|track_id| !track_ids.contains(track_id))
+        .collect();
+    if !vanished_track_ids.is_empty() {
+        app_handle.db_mut(|db| db::delete_tracks(&vanished_track_ids, db))?;
+        track_ids.extend(vanished_track_ids);
+    }
+
+    Ok(track_ids)
+}
+
+/// Re-reads the audio files that a changed `.lrc`/`.txt` file belongs to: the files with the
+/// same stem, and for a virtual track sidecar such as `Album.03.lrc` the CUE sheet rip
+/// `Album.*` too.
+fn handle_lyrics_sidecar(
+    path: &Path,
+    directory: &PersistentDirectory,
+    options: &ScanOptions,
+    app_handle: &AppHandle,
+) -> Result<Vec<i64>> {
+    let (Some(parent_path), Some(file_stem)) = (path.parent(), path.file_stem()) else {
+        return Ok(vec![]);
+    };
+    let mut file_stems: Vec<OsString> = vec![file_stem.to_owned()];
+    if let Some(cue_file_stem) = Path::new(file_stem)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .filter(|extension| extension.chars().all(|c| c.is_ascii_digit()))
+        .and(Path::new(file_stem).file_stem())
+    {
+        file_stems.push(cue_file_stem.to_owned());
+    }
+
+    let mut file_paths: HashSet<String> = HashSet::new();
+    for file_stem in file_stems.iter() {
+        let prefix = parent_path.join(file_stem).display().to_string();
+        let candidates = app_handle.db(|db| db::get_tracks_by_file_path_prefix(&prefix, db))?;
+        file_paths.extend(
+            candidates
+                .into_iter()
+                .map(|(_, file_path)| file_path)
+                .filter(|file_path| {
+                    let track_path = Path::new(file_path);
+                    track_path.parent() == Some(parent_path)
+                        && track_path.file_stem() == Some(file_stem.as_os_str())
+                }),
+        );
+    }
+
+    let mut track_ids: Vec<i64> = vec![];
+    for file_path in file_paths.iter() {
+        track_ids.extend(handle_audio_file(
+            Path::new(file_path),
+            directory,
+            options,
+            app_handle,
+        )?);
+    }
+
+    Ok(track_ids)
+}
+
+/// Re-reads the audio file that a created, changed or removed CUE sheet describes, which
+/// splits it into virtual tracks or merges them back into one track.
+fn handle_cue_sheet(
+    path: &Path,
+    directory: &PersistentDirectory,
+    options: &ScanOptions,
+    app_handle: &AppHandle,
+) -> Result<Vec<i64>> {
+    let (Some(parent_path), Some(file_stem)) = (path.parent(), path.file_stem()) else {
+        return Ok(vec![]);
+    };
+    let Ok(entries) = fs::read_dir(parent_path) else {
+        return Ok(vec![]);
+    };
+
+    let mut track_ids: Vec<i64> = vec![];
+    for entry in entries {
+        let audio_path = entry?.path();
+        let is_described =
+            audio_path.file_stem() == Some(file_stem) || audio_path.file_name() == Some(file_stem);
+        if is_described && audio_path.is_file() && fs_track::is_included(directory, &audio_path) {
+            track_ids.extend(handle_audio_file(
+                &audio_path,
+                directory,
+                options,
+                app_handle,
+            )?);
+        }
+    }
+
+    Ok(track_ids)
+}
+
+/// Picks up audio files from a directory that was created or moved into the library.
+fn handle_directory(
+    path: &Path,
+    directory: &PersistentDirectory,
+    options: &ScanOptions,
+    app_handle: &AppHandle,
+) -> Result<Vec<i64>> {
+    let mut track_ids: Vec<i64> = vec![];
+    let matcher = DirectoryMatcher::for_directory(directory)?;
+    for item in matcher.walk(path, directory.follow_symlinks) {
+        let entry = item?;
+        track_ids.extend(handle_audio_file(
+            entry.path(),
+            directory,
+            options,
+            app_handle,
+        )?);
+    }
+
+    Ok(track_ids)
+}
+
+fn handle_removed_directory(path: &Path, app_handle: &AppHandle) -> Result<Vec<i64>> {
+    let prefix = format!("{}{}", path.display(), MAIN_SEPARATOR);
+    let track_ids: Vec<i64> = app_handle
+        .db(|db| db::get_tracks_by_file_path_prefix(&prefix, db))?
+        .into_iter()
+        .map(|(track_id, _)| track_id)
+        .collect();
+    app_handle.db_mut(|db| db::delete_tracks(&track_ids, db))?;
+
+    Ok(track_ids)
+}
@@ -11,6 +11,8 @@ const successCount = ref(0)
 const failureCount = ref(0)
 const isDownloading = ref(false)
 const totalCount = ref(0)
+// Groups the lyrics revisions made by one download run
+const batchId = ref(null)
 
 const downloadedCount = computed(() => {
   return downloadedItems.value.length
@@ -25,7 +27,7 @@ const addLog = (logObj) => {
 
 const downloadLyrics = async (track) => {
   try {
-    const result = await invoke('download_lyrics', { trackId: track.id })
+    const result = await invoke('download_lyrics', { trackId: track.id, batchId: batchId.value })
 
     if (!isDownloading.value) {
       return
@@ -75,6 +77,10 @@ const downloadProgress = computed(() => {
 const addToQueue = (trackIds) => {
   isDownloading.value = true
 
+  if (!batchId.value) {
+    batchId.value = crypto.randomUUID()
+  }
+
   for (let i = 0; i < trackIds.length; i++) {
     downloadQueue.value.push(trackIds[i])
   }
@@ -91,6 +97,7 @@ const startOver = () => {
   failureCount.value = 0
   totalCount.value = 0
   isDownloading.value = false
+  batchId.value = null
 }
 
 const stopDownloading = () => {
@@ -101,6 +108,7 @@ const stopDownloading = () => {
   failureCount.value = 0
   totalCount.value = 0
   isDownloading.value = false
+  batchId.value = null
 }
 
 export function useDownloader() {